@@ -8,10 +8,9 @@ var addr: Dictionary = {}
 func _ready() -> void:
 	signals()
 	
-	addr = await Stun.pub_addr().recv
-	print(addr)
-	client.connect("http://127.0.0.1", 3000, addr.ip, addr.port) # connects to a locally hosted server
-	#client.connect("https://p2p-server-s5wb.onrender.com", 443, addr.ip, addr.port) # fails to connect to renders server
+	# the client binds the udp socket holes are punched from, game traffic goes through it with send_packet/take_packets
+	client.connect("http://127.0.0.1", 3000, "0.0.0.0", 0) # connects to a locally hosted server
+	#client.connect("https://p2p-server-s5wb.onrender.com", 443, "0.0.0.0", 0) # fails to connect to renders server
 	print(await client.connection_changed)
 
 func signals():
@@ -19,6 +18,7 @@ func signals():
 	client.connection_changed.connect(on_connection_changed)
 	client.joined_addrs_changed.connect(joined)
 	client.owned_listing_changed.connect(owned)
+	client.join_requests_changed.connect(join_requests)
 	client.listings_changed.connect(on_listings)
 
 func async_error(msg: String) -> void:
@@ -33,6 +33,10 @@ func joined(addrs):
 func owned(id):
 	print(id)
 
+func join_requests(pending):
+	for request in pending: # accept everyone
+		client.respond_join(request.request_id, true, "")
+
 func on_listings(arr):
 	print(arr)
 	
@@ -65,7 +69,7 @@ func _on_refresh_pressed() -> void:
 
 func join(id: String):
 	print("join: " + id)
-	client.join_listing(id)
+	client.join_listing(id, "")
 
 
 func _on_cancel_host_pressed() -> void:
@@ -2,26 +2,27 @@
 extends RefCounted
 class_name Stun
 
-static func pub_addr() -> PubAddrAwaiter:
-	return PubAddrAwaiter.new()
+# defaults to a public server; pass nat_puncher_server's host and stun port (3478) to ask its responder instead
+static func pub_addr(host: String = "stun.l.google.com", port: int = 19302) -> PubAddrAwaiter:
+	return PubAddrAwaiter.new(host, port)
 
 class PubAddrAwaiter:
 	signal recv
 
-	func _init():
-		_run_stun()
+	func _init(host: String, port: int):
+		_run_stun(host, port)
 
-	func _run_stun():
+	func _run_stun(host: String, port: int):
 		var peer := PacketPeerUDP.new()
 		var trans_id := _rand_id()
-		var server = IP.resolve_hostname("stun.l.google.com")
+		var server = IP.resolve_hostname(host)
 
 		if server == "":
 			recv.emit(null)
 			return
 
 		var req := PackedByteArray([0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xA4, 0x42]) + trans_id
-		peer.connect_to_host(server, 19302)
+		peer.connect_to_host(server, port)
 		peer.put_packet(req)
 
 		var timer = Engine.get_main_loop().create_timer(2)
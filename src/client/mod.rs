@@ -1,26 +1,77 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
-use anyhow::{anyhow, Result};
-use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
-use hyper_util::{client::legacy::{self, connect::HttpConnector}, rt::TokioExecutor};
-use tokio::{net::UdpSocket, sync::{broadcast, mpsc, RwLock}, time::{sleep, timeout}};
-use tokio_stream::wrappers::ReceiverStream;
-use tonic::{body::Body, transport::Uri, Request};
-use tonic_web::{GrpcWebCall, GrpcWebClientLayer, GrpcWebClientService};
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::Duration};
+use anyhow::{anyhow, bail, Result};
+use tokio::{net::{lookup_host, UdpSocket}, sync::{broadcast, mpsc, watch, RwLock}, time::{sleep, timeout}};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tonic::{metadata::{Ascii, MetadataValue}, transport::Uri, Request, Status, Streaming};
+use prost::Message;
+use tracing::{debug, warn};
 use uuid::Uuid;
-use crate::{proto::{puncher_service_client::PuncherServiceClient, AddListingRequest, GetListingsRequest, JoinRequest, RemoveListingRequest}, server::listing::{RustListing, RustListingNoId}, ThreadSafe, TIMEOUT};
+use crate::{proto::{puncher_service_client::PuncherServiceClient, server_stream_message::ServerStreamEnum, Capability, AddListingRequest, ClientStreamMessage, ServerStreamMessage, Welcome, BindingRequest, BindingResponse, Fallback, GetListingsRequest, JoinRequest, JoinResponse, PunchStatus, RemoveListingRequest, UpdateListingRequest, WatchListingsRequest}, server::{auth::{AUTHORIZATION, SESSION_TOKEN}, listing::{RustListing, RustListingNoId}, query::{ListingsPage, RustListingQuery}, watch::RustListingEvent}, parse_addr, stun, ThreadSafe, PROTOCOL_VERSION, TIMEOUT};
 
 mod session;
-use session::Session;
+use session::{Resume, Session};
+pub use session::{ConnectionState, PendingJoin};
+mod tls;
+pub use tls::Trust;
+mod transport;
+pub use transport::Transport;
+
+type RpcClient = PuncherServiceClient<transport::Channel>;
+
+/// Allowed over the server's join timeout, for its answer to arrive.
+const JOIN_SLACK: Duration = Duration::from_secs(2);
+
+/// How [`Client::with_options`] reaches the server.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+	pub transport: Transport,
+	pub trust: Trust,
+	/// Proves who the player is to servers with an auth provider, e.g. a JWT from the game's backend.
+	pub auth_token: Option<String>,
+	/// How a dropped session stream is resumed.
+	pub reconnect: Backoff,
+	/// Accepts every join while nothing takes [`Client::join_requests`], on by default. Off, they're rejected.
+	pub auto_accept: bool,
+}
+
+impl Default for ClientOptions {
+	fn default() -> Self {
+		Self {
+			transport: Transport::default(),
+			trust: Trust::default(),
+			auth_token: None,
+			reconnect: Backoff::default(),
+			auto_accept: true,
+		}
+	}
+}
 
-type WebClient = PuncherServiceClient<GrpcWebClientService<legacy::Client<HttpsConnector<HttpConnector>, GrpcWebCall<Body>>>>;
+/// Waits between reconnect attempts, doubling from `initial` up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+	pub initial: Duration,
+	pub max: Duration,
+	/// Given up after, ending the session. 0 never reconnects.
+	pub attempts: u32,
+}
+
+impl Default for Backoff {
+	fn default() -> Self {
+		Self { initial: Duration::from_millis(500), max: Duration::from_secs(10), attempts: 8 }
+	}
+}
 
 pub struct Client {
-	client: ThreadSafe<WebClient>,
+	client: ThreadSafe<RpcClient>,
 	session: Option<Session>,
+	server_url: Uri,
+	auth_token: Option<String>,
+	reconnect: Backoff,
+	auto_accept: bool,
 }
 
 impl Client {
-	pub fn inner(&self) -> &ThreadSafe<WebClient> { &self.client }
+	pub fn inner(&self) -> &ThreadSafe<RpcClient> { &self.client }
 
 	pub fn session(&self) -> &Option<Session> { &self.session }
 
@@ -30,70 +81,83 @@ impl Client {
 		}
 	}
 
-	pub async fn new(server_url: Uri) -> Result<Self> {
-		let https = HttpsConnectorBuilder::new()
-			.with_native_roots()
-			.map_err(|e| anyhow!("With native roots error: {e}"))?
-			.https_or_http()
-			.enable_http1()
-			.build();
-
-		let client = hyper_util::client::legacy::Client::builder(TokioExecutor::new()).build(https);
+	pub async fn new(server_url: Uri, transport: Transport) -> Result<Self> {
+		Self::with_options(server_url, ClientOptions { transport, ..Default::default() }).await
+	}
 
-		let svc = tower::ServiceBuilder::new()
-			.layer(GrpcWebClientLayer::new())
-			.service(client);
+	/// Like [`Client::new`], also choosing which server certificates to trust and how to authenticate.
+	pub async fn with_options(server_url: Uri, options: ClientOptions) -> Result<Self> {
+		let channel = options.transport.channel(&server_url, options.trust.client_config()?)?;
 
-		let client = PuncherServiceClient::with_origin(svc, server_url);
+		let client = PuncherServiceClient::with_origin(channel, server_url.clone());
 		let client = Arc::new(RwLock::new(client));
 
 		Ok(Self {
 			client,
 			session: None,
+			server_url,
+			auth_token: options.auth_token,
+			reconnect: options.reconnect,
+			auto_accept: options.auto_accept,
 		})
 	}
 
+	/// The session's connection, as it drops and resumes; `None` without a session.
+	pub fn connection(&self) -> Option<watch::Receiver<ConnectionState>> {
+		self.session().as_ref().map(Session::connection)
+	}
+
+	/// Starts a session that punches from a fresh `0.0.0.0:0` socket, see [`Session::socket`].
 	pub async fn start_session(&mut self) -> Result<broadcast::Receiver<SocketAddr>> {
-		let (client_tx, client_rx) = mpsc::channel(8);
+		let socket = UdpSocket::bind("0.0.0.0:0")
+			.await
+			.map_err(|e| anyhow!("Unable to bind punching socket: {e}"))?;
 
-		let req = Request::new(ReceiverStream::new(client_rx));
+		self.start_session_on(Arc::new(socket), Claims::exclusive()).await
+	}
 
-		let resp = {
-			let mut client = self.inner().write().await;
-			client.stream_session(req).await
-				.map_err(|e| anyhow!("Stream session status: {e}"))?
-		};
+	/// Starts a session that punches from `socket`, so the opened holes belong to the caller's port.
+	/// The caller keeps reading it, leaving datagrams from [`Session::claims`] queued.
+	pub async fn start_session_with(&mut self, socket: Arc<UdpSocket>) -> Result<broadcast::Receiver<SocketAddr>> {
+		self.start_session_on(socket, Claims::default()).await
+	}
 
-		let mut server_rx = resp.into_inner();
+	async fn start_session_on(&mut self, socket: Arc<UdpSocket>, claims: Claims) -> Result<broadcast::Receiver<SocketAddr>> {
+		let (welcome, server_rx, client_tx) = open_stream(self.inner(), self.auth_token.as_deref(), None).await?;
 
-		let session_id: Uuid = timeout(TIMEOUT, server_rx.message()).await
-			.map_err(|e| anyhow!("Timeout waiting for session_id: {e}"))?
-			.map_err(|e| anyhow!("Received grpc error waiting for session_id: {e}"))?
-			.ok_or(anyhow!("First received message had no contents"))?
-			.session_id_assignment
-			.ok_or(anyhow!("First received message had no session_id"))?
-			.try_into()
-			.map_err(|e| anyhow!("Unable to convert received Vec<u8> to Uuid: {e}"))?;
+		let server_addr = match self.server_addr().await {
+			Ok(addr) => Some(addr),
+			Err(e) => {
+				warn!(error = %e, "Unable to resolve server addr");
+				None
+			},
+		};
 
-		let (session, joined_dst) = Session::start(session_id, server_rx, client_tx)
+		let resume = Resume { client: self.client.clone(), auth_token: self.auth_token.clone(), backoff: self.reconnect };
+		let (session, joined_dst) = Session::start(welcome, socket.clone(), claims, server_addr, (server_rx, client_tx), resume, self.auto_accept)
 			.await
 			.map_err(|e| anyhow!("Session creation error: {e}"))?;
 
+		let reflector = session.has(Capability::UdpReflector);
 		self.session = Some(session);
 
+		// without a reflected mapping the server falls back to our tcp address //
+		if reflector && let Err(e) = self.register_udp(&socket).await {
+			warn!(error = %e, "Unable to register punching socket with reflector");
+		}
+
 		Ok(joined_dst)
 	}
 
 	pub async fn create_listing(&mut self, listing: RustListingNoId) -> Result<Uuid> {
-		let session_id = self
+		let session = self
 			.session()
 			.as_ref()
-			.ok_or(anyhow!("Cannot create listing without a session"))?
-			.id();
+			.ok_or(anyhow!("Cannot create listing without a session"))?;
 		
-		let req = Request::new( AddListingRequest { 
+		let req = session.request( AddListingRequest { 
 			listing: Some(listing.into()), 
-			session_id,
+			session_id: session.id(),
 		});
 
 		let fut = async {
@@ -116,14 +180,13 @@ impl Client {
 	}
 
 	pub async fn remove_listing(&mut self) -> Result<()> {
-		let session_id = self
+		let session = self
 			.session()
 			.as_ref()
-			.ok_or(anyhow!("Cannot remove listing without a session"))?
-			.id();
+			.ok_or(anyhow!("Cannot remove listing without a session"))?;
 		
-		let req = Request::new( RemoveListingRequest { 
-			session_id,
+		let req = session.request( RemoveListingRequest { 
+			session_id: session.id(),
 		});
 
 		let fut = async {
@@ -139,8 +202,43 @@ impl Client {
 		Ok(())
 	}
 
+	/// Replaces the owned listing's contents, keeping its id. A `None` password keeps the current one.
+	pub async fn update_listing(&mut self, listing: RustListingNoId) -> Result<()> {
+		self.update_listing_with(listing, UpdateOptions::default()).await
+	}
+
+	pub async fn update_listing_with(&mut self, listing: RustListingNoId, options: UpdateOptions) -> Result<()> {
+		let session = self
+			.session()
+			.as_ref()
+			.ok_or(anyhow!("Cannot update listing without a session"))?;
+		
+		let req = session.request( UpdateListingRequest { 
+			listing: Some(listing.into()), 
+			session_id: session.id(),
+			clear_password: options.clear_password,
+		});
+
+		let fut = async {
+			let mut client = self.inner().write().await;
+			client.update_listing(req).await
+		};
+
+		timeout(TIMEOUT, fut)
+			.await
+			.map_err(|e| anyhow!("Update listing timeout: {e}"))?
+			.map_err(|e| anyhow!("Update listing error status: {e}"))?;
+
+		Ok(())
+	}
+
+	/// The first page of listings in the server's default order, see [`Client::query_listings`].
 	pub async fn get_listings(&mut self) -> Result<Vec<RustListing>> {
-		let req = Request::new( GetListingsRequest { });
+		Ok(self.query_listings(RustListingQuery::default()).await?.listings)
+	}
+
+	pub async fn query_listings(&mut self, query: RustListingQuery) -> Result<ListingsPage> {
+		let req = Request::new(GetListingsRequest::from(query));
 
 		let fut = async {
 			let mut client = self.inner().write().await;
@@ -150,69 +248,378 @@ impl Client {
 		let resp = timeout(TIMEOUT, fut)
 			.await
 			.map_err(|e| anyhow!("Get listings timeout: {e}"))?
-			.map_err(|e| anyhow!("Get listings error status: {e}"))?;
+			.map_err(|e| anyhow!("Get listings error status: {e}"))?
+			.into_inner();
 
 		let listings: Vec<RustListing> = resp
-			.into_inner()
 			.listings
 			.into_iter()
 			.filter_map(|l| l.try_into().ok())
 			.collect();
 
-		Ok(listings)
+		let next_cursor = (!resp.next_cursor.is_empty()).then_some(resp.next_cursor);
+
+		Ok(ListingsPage { listings, next_cursor })
+	}
+
+	/// A snapshot of every listing, then each change as it happens. Fold them with [`RustListingEvent::apply`].
+	pub async fn watch_listings(&self) -> Result<impl Stream<Item = Result<RustListingEvent>> + use<>> {
+		let req = Request::new(WatchListingsRequest {});
+
+		let fut = async {
+			let mut client = self.inner().write().await;
+			client.watch_listings(req).await
+		};
+
+		let stream = timeout(TIMEOUT, fut)
+			.await
+			.map_err(|e| anyhow!("Watch listings timeout: {e}"))?
+			.map_err(|e| anyhow!("Watch listings error status: {e}"))?
+			.into_inner();
+
+		Ok(stream.map(|event| {
+			event
+				.map_err(|e| anyhow!("Watch listings error status: {e}"))?
+				.try_into()
+		}))
+	}
+
+	/// Registers `socket`'s public udp mapping with the server's reflector, so joins punch toward it. Returns the reflected address.
+	pub async fn register_udp(&self, socket: &UdpSocket) -> Result<SocketAddr> {
+		let (token, claims) = self
+			.session()
+			.as_ref()
+			.map(|s| (s.token(), s.claims().clone()))
+			.ok_or(anyhow!("Cannot register udp socket without a session"))?;
+
+		let reflector = self.server_addr().await?;
+		reflect_among(socket, reflector, &token, &claims).await
+	}
+
+	/// The grpc server's address; the reflector and relays share its host.
+	async fn server_addr(&self) -> Result<SocketAddr> {
+		let host = self
+			.server_url
+			.host()
+			.ok_or(anyhow!("Server url has no host"))?;
+
+		let port = match self.server_url.port_u16() {
+			Some(p) => p,
+			None if self.server_url.scheme_str() == Some("https") => 443,
+			None => 80,
+		};
+
+		lookup_host((host, port))
+			.await
+			.map_err(|e| anyhow!("Unable to resolve server host: {e}"))?
+			.next()
+			.ok_or(anyhow!("Server host resolved to no addresses"))
+	}
+
+	/// See [`Session::join_requests`].
+	pub fn join_requests(&self) -> Result<mpsc::Receiver<PendingJoin>> {
+		let session = self
+			.session()
+			.as_ref()
+			.ok_or(anyhow!("Cannot receive join requests without a session"))?;
+
+		Ok(session.join_requests())
 	}
 
-	pub async fn join(&mut self, listing_id: Uuid) -> Result<()> {
-		let session_id = self
+	pub async fn join(&mut self, listing_id: Uuid) -> Result<JoinOutcome> {
+		self.join_with(listing_id, JoinOptions::default()).await
+	}
+
+	pub async fn join_with(&mut self, listing_id: Uuid, options: JoinOptions) -> Result<JoinOutcome> {
+		let session = self
 			.session()
 			.as_ref()
-			.ok_or(anyhow!("Cannot join listing without a session"))?
-			.id();
+			.ok_or(anyhow!("Cannot join listing without a session"))?;
 		
-		let req = Request::new( JoinRequest { 
-			session_id,
+		let req = session.request( JoinRequest { 
+			session_id: session.id(),
 			target_listing_id: listing_id.into_bytes().to_vec(),
+			payload: options.payload,
+			password: options.password,
 		});
+		let wait = session.join_timeout() + JOIN_SLACK;
 
 		let fut = async {
 			let mut client = self.inner().write().await;
 			client.join(req).await
 		};
 
-		timeout(TIMEOUT, fut)
+		let resp = timeout(wait, fut)
 			.await
 			.map_err(|e| anyhow!("Join listing timeout: {e}"))?
 			.map_err(|e| anyhow!("Join listing error status: {e}"))?;
 
-		Ok(())
+		let server_ip = self.server_addr().await.ok().map(|a| a.ip());
+		JoinOutcome::from_response(resp.into_inner(), server_ip)
+	}
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct JoinOptions {
+	/// Shown to the host in its [`PendingJoin`], e.g. player info.
+	pub payload: Vec<u8>,
+	/// Checked by the server against the listing's password.
+	pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOptions {
+	/// Drops the listing's password, leaving it open. The listing's own password must be `None`.
+	pub clear_password: bool,
+}
+
+/// Opens a session stream and reads its welcome. With a session token, asks to resume that session.
+async fn open_stream(
+	client: &ThreadSafe<RpcClient>,
+	auth_token: Option<&str>,
+	resume: Option<&MetadataValue<Ascii>>,
+) -> Result<(Welcome, Streaming<ServerStreamMessage>, mpsc::Sender<ClientStreamMessage>)> {
+	let (client_tx, client_rx) = mpsc::channel(8);
+
+	let mut req = Request::new(ReceiverStream::new(client_rx));
+	if let Some(token) = auth_token {
+		let bearer = format!("Bearer {token}")
+			.parse()
+			.map_err(|e| anyhow!("Auth token is not valid metadata: {e}"))?;
+		req.metadata_mut().insert(AUTHORIZATION, bearer);
+	}
+	if let Some(token) = resume {
+		req.metadata_mut().insert(SESSION_TOKEN, token.clone());
+	}
+
+	// keeps the status, so a resume can tell a gone session from a failed connection //
+	let resp = client.write().await.stream_session(req).await.map_err(|status: Status| {
+		let message = format!("Stream session status: {status}");
+		anyhow::Error::new(status).context(message)
+	})?;
+
+	let mut server_rx = resp.into_inner();
+
+	// handshake //
+	let welcome = timeout(TIMEOUT, server_rx.message()).await
+		.map_err(|e| anyhow!("Timeout waiting for welcome: {e}"))?
+		.map_err(|e| anyhow!("Received grpc error waiting for welcome: {e}"))?
+		.ok_or(anyhow!("Stream closed before welcome"))?
+		.server_stream_enum;
+
+	let Some(ServerStreamEnum::Welcome(welcome)) = welcome else {
+		bail!("First received message was not a welcome");
+	};
+
+	if welcome.protocol_version != PROTOCOL_VERSION {
+		bail!("Protocol version mismatch: server {}, client {PROTOCOL_VERSION}", welcome.protocol_version);
+	}
+
+	Ok((welcome, server_rx, client_tx))
+}
+
+/// Result of a join, as reported by the server.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct JoinOutcome {
+	pub joiner: PunchStatus,
+	pub host: PunchStatus,
+	/// Where to send game traffic: the host's punched address, or the relay when `fallback` is `Relay`.
+	pub peer: Option<SocketAddr>,
+	pub fallback: Fallback,
+}
+
+impl JoinOutcome {
+	/// Whether the peers can reach each other, directly or through a fallback.
+	pub fn connected(&self) -> bool {
+		(self.joiner.success && self.host.success) || self.fallback != Fallback::None
+	}
+
+	fn from_response(resp: JoinResponse, server_ip: Option<IpAddr>) -> Result<Self> {
+		let ip = match (resp.peer_ip.is_empty(), server_ip) {
+			(false, _) => Some(resp.peer_ip),
+			(true, Some(ip)) => Some(ip.to_string()),
+			(true, None) => None,
+		};
+
+		let peer = match ip {
+			Some(ip) => Some(parse_addr(&ip, resp.peer_port)?),
+			None => None,
+		};
+
+		Ok(Self {
+			joiner: resp.joiner.unwrap_or_default(),
+			host: resp.host.unwrap_or_default(),
+			peer,
+			fallback: Fallback::try_from(resp.fallback).unwrap_or_default(),
+		})
 	}
 }
 
 
+/// Sends binding requests from `socket` to the reflector at `server` until one is answered, returning the reflected address.
+/// `session_token` is the one the server welcomed the session with.
+pub async fn reflect(socket: &UdpSocket, server: SocketAddr, session_token: &str) -> Result<SocketAddr> {
+	reflect_among(socket, server, session_token, &Claims::default()).await
+}
+
+/// Like [`reflect`], claiming the reflector's datagrams in `claims` meanwhile.
+pub(crate) async fn reflect_among(socket: &UdpSocket, server: SocketAddr, session_token: &str, claims: &Claims) -> Result<SocketAddr> {
+	let request = BindingRequest { session_token: session_token.to_string() }.encode_to_vec();
+	let mut recv = [0u8; 64];
+
+	let fut = async {
+		loop {
+			socket.send_to(&request, server).await?;
+
+			// resend on loss //
+			let Ok(result) = timeout(Duration::from_millis(300), recv_claimed(socket, server, &mut recv, claims)).await else {
+				continue;
+			};
+			let len = result?;
+
+			let response = BindingResponse::decode(&recv[..len])
+				.map_err(|e| anyhow!("Malformed binding response: {e}"))?;
+
+			return parse_addr(&response.ip, response.port);
+		}
+	};
+
+	claims.start(server);
+	let result = timeout(TIMEOUT, fut).await;
+	claims.end(&server);
+
+	result.map_err(|e| anyhow!("Reflect timeout: {e}"))?
+}
+
+/// Queries a standard STUN server from `socket`, returning the XOR-MAPPED-ADDRESS it reports.
+pub async fn stun_query(socket: &UdpSocket, server: SocketAddr) -> Result<SocketAddr> {
+	let transaction_id = stun::new_transaction_id();
+	let request = stun::binding_request(&transaction_id);
+	let mut recv = [0u8; 548];
+
+	let fut = async {
+		loop {
+			socket.send_to(&request, server).await?;
+
+			// resend on loss //
+			let Ok(result) = timeout(Duration::from_millis(300), recv_claimed(socket, server, &mut recv, &Claims::default())).await else {
+				continue;
+			};
+			let len = result?;
+
+			// ignore stray or stale responses //
+			if let Ok(addr) = stun::parse_binding_response(&recv[..len], &transaction_id) {
+				return Ok(addr);
+			}
+		}
+	};
+
+	timeout(TIMEOUT, fut)
+		.await
+		.map_err(|e| anyhow!("Stun timeout: {e}"))?
+}
+
+/// Takes the next datagram from `src` off `socket`. Datagrams others in `claims` wait for stay queued for them,
+/// as do the rest unless the socket is exclusive; they're peeked at again once their reader had a moment to.
+async fn recv_claimed(socket: &UdpSocket, src: SocketAddr, buf: &mut [u8], claims: &Claims) -> std::io::Result<usize> {
+	loop {
+		let sender = socket.peek_sender().await?;
+		if sender != src {
+			// nobody else reads an exclusive socket //
+			if claims.exclusive && !claims.contains(&sender) {
+				socket.recv_from(buf).await?;
+			} else {
+				sleep(Duration::from_millis(5)).await;
+			}
+			continue;
+		}
+
+		// another reader may have taken the datagram between peeking and now //
+		let (len, from) = socket.recv_from(buf).await?;
+		if from == src {
+			return Ok(len);
+		}
+	}
+}
+
+/// Punches from a throwaway socket; the hole closes when it is dropped.
+#[deprecated(note = "the hole closes with the throwaway socket, punch from the game's socket with `punch_with`")]
 pub async fn punch(addr: SocketAddr) -> Result<()> {
 	let socket = Arc::new(UdpSocket::bind("0.0.0.0:0".parse::<SocketAddr>().unwrap()).await?);
-	socket.connect(addr).await?;
+	punch_among(socket, addr, &Claims::exclusive()).await
+}
 
+/// Punches toward `addr` from `socket`. The socket is left unconnected so it stays usable for other peers,
+/// and only the peer's datagrams are taken off it; the caller keeps reading the rest.
+pub async fn punch_with(socket: Arc<UdpSocket>, addr: SocketAddr) -> Result<()> {
+	punch_among(socket, addr, &Claims::default()).await
+}
+
+/// Sources that punches and reflects in flight on a shared socket are waiting to hear from.
+/// Whoever else reads the socket leaves their datagrams queued, see [`Session::claims`].
+#[derive(Clone, Default)]
+pub struct Claims {
+	sources: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+	/// Nothing but the claimants reads the socket, so datagrams nobody claims are dropped.
+	exclusive: bool,
+}
+
+impl Claims {
+	pub(crate) fn exclusive() -> Self {
+		Self { exclusive: true, ..Default::default() }
+	}
+
+	pub fn contains(&self, src: &SocketAddr) -> bool {
+		self.sources.lock().unwrap().contains_key(src)
+	}
+
+	fn start(&self, src: SocketAddr) {
+		*self.sources.lock().unwrap().entry(src).or_default() += 1;
+	}
+
+	fn end(&self, src: &SocketAddr) {
+		let mut sources = self.sources.lock().unwrap();
+		if let Some(count) = sources.get_mut(src) {
+			*count -= 1;
+			if *count == 0 {
+				sources.remove(src);
+			}
+		}
+	}
+}
+
+/// Like [`punch_with`], claiming the peer's datagrams in `claims` meanwhile.
+pub(crate) async fn punch_among(socket: Arc<UdpSocket>, addr: SocketAddr, claims: &Claims) -> Result<()> {
+	claims.start(addr);
+	let result = punch_until(socket, addr, claims).await;
+	claims.end(&addr);
+	result
+}
+
+async fn punch_until(socket: Arc<UdpSocket>, addr: SocketAddr, claims: &Claims) -> Result<()> {
 	let packet = b"punch";
 	let mut recv = [0u8; 5];
 	
 	tokio::select! {
 		_ = async { 
 			loop {
-				if let Err(e) = socket.send(packet).await {
-					eprintln!("Unable to send punching packet {e}");
+				if let Err(e) = socket.send_to(packet, addr).await {
+					debug!(%addr, error = %e, "Unable to send punching packet");
 				}
 				
 				sleep(Duration::from_millis(300)).await;
 			}
 		} => {},
 
-		result = timeout(TIMEOUT, socket.recv(&mut recv)) => {
+		result = timeout(TIMEOUT, recv_claimed(&socket, addr, &mut recv, claims)) => {
 			result
 				.map_err(|e| anyhow!("Punch timeout: {e}"))?
 				.map_err(|e| anyhow!("Error receiving punch packets: {e}"))?;
 		},
 	};
 
+	// the peer's packets may have been queued before we sent any, make sure it hears from us //
+	socket.send_to(packet, addr).await?;
+
 	Ok(())
 }
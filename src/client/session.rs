@@ -1,40 +1,271 @@
-use std::{net::{IpAddr, SocketAddr}, time::Duration};
-use tokio::{sync::{broadcast, mpsc::Sender}, time::sleep};
+use std::{net::{IpAddr, SocketAddr}, sync::Arc, time::Duration};
+use tokio::{net::UdpSocket, sync::{broadcast, mpsc::{self, Sender}, oneshot, watch}, time::sleep};
 use tokio_util::sync::CancellationToken;
-use tonic::Streaming;
+use tonic::{metadata::{Ascii, MetadataValue}, Code, Request, Status, Streaming};
+use tracing::{debug, info, info_span, warn, Instrument};
 use uuid::Uuid;
-use anyhow::{anyhow, Result};
-use crate::{proto::{client_stream_message::ClientStreamEnum, server_stream_message::ServerStreamEnum, ClientStreamMessage, PunchStatus, ServerStreamMessage}, TIMEOUT};
+use anyhow::{anyhow, bail, Result};
+use super::{Backoff, Claims, RpcClient};
+use crate::{server::auth::SESSION_TOKEN, proto::{client_stream_message::ClientStreamEnum, server_stream_message::ServerStreamEnum, ClientStreamMessage, Identity, IncomingJoin, JoinDecision, Punch, PunchStatus, Relay, ServerStreamMessage, Capability, Welcome}, parse_addr, ThreadSafe, TIMEOUT};
 
+const RELAY_LATCH_PACKETS: usize = 3;
 
+/// Where incoming join requests go; `None` falls back to [`super::ClientOptions::auto_accept`].
+type JoinHandler = Arc<std::sync::Mutex<Option<mpsc::Sender<PendingJoin>>>>;
 
-pub struct Session {
+/// A session asking to join our listing. Dropping it rejects the join.
+pub struct PendingJoin {
+	pub request_id: Uuid,
+	pub joiner_ip: String,
+	/// Verified by the server; `None` for anonymous joiners.
+	pub joiner: Option<Identity>,
+	pub payload: Vec<u8>,
+	responder: oneshot::Sender<JoinDecision>,
+}
+
+impl PendingJoin {
+	pub fn accept(self) {
+		self.respond(true, None);
+	}
+
+	pub fn reject(self, reason: impl Into<String>) {
+		self.respond(false, Some(reason.into()));
+	}
+
+	fn respond(self, accept: bool, reason: Option<String>) {
+		let decision = JoinDecision {
+			request_id: self.request_id.as_bytes().to_vec(),
+			accept,
+			reason,
+		};
+		// the stream handler only drops its end once the session is over //
+		let _ = self.responder.send(decision);
+	}
+}
+
+
+/// Whether a session's stream to the server is up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnectionState {
+	Connected,
+	/// The stream dropped; the session and its listing are kept while this resumes it.
+	Reconnecting { attempt: u32 },
+	/// Ended, or couldn't be resumed.
+	#[default]
+	Disconnected,
+}
+
+/// Reopens a dropped session stream, see [`super::ClientOptions::reconnect`].
+pub(super) struct Resume {
+	pub client: ThreadSafe<RpcClient>,
+	pub auth_token: Option<String>,
+	pub backoff: Backoff,
+}
+
+/// A session stream opened again by [`Resume::reconnect`].
+enum Reopened {
+	Resumed(Welcome, Streaming<ServerStreamMessage>, Sender<ClientStreamMessage>),
+	/// The server restarted and didn't know the session anymore, or only its persisted listing.
+	Restarted(Welcome, Streaming<ServerStreamMessage>, Sender<ClientStreamMessage>),
+}
+
+impl Resume {
+	/// `shutdown` is the hold-off the server asked for when it shut down, waited before the first attempt.
+	/// Only then is a rejected token taken to mean the server restarted, and a new session started in its place.
+	async fn reconnect(
+		&self,
+		credentials: &Credentials,
+		state: &watch::Sender<ConnectionState>,
+		shutdown: Option<Duration>,
+	) -> Result<Reopened> {
+		let mut delay = self.backoff.initial.max(shutdown.unwrap_or_default());
+		let mut last_error = anyhow!("Reconnecting is off");
+
+		for attempt in 1..=self.backoff.attempts {
+			state.send_replace(ConnectionState::Reconnecting { attempt });
+			sleep(delay).await;
+			delay = (delay * 2).min(self.backoff.max);
+
+			let result = super::open_stream(&self.client, self.auth_token.as_deref(), Some(&credentials.token)).await;
+			let rejected = result
+				.as_ref()
+				.err()
+				.and_then(|e| e.downcast_ref::<Status>())
+				.is_some_and(|s| matches!(s.code(), Code::NotFound | Code::Unauthenticated));
+
+			match result {
+				Ok((welcome, server_rx, client_tx)) => {
+					if welcome.session_id != credentials.session_id.as_bytes() {
+						bail!("Server started a new session instead of resuming");
+					}
+					// a restarted server with a persistent store gives the listing's owner its id back, but nothing else //
+					if !welcome.resumed {
+						return Ok(Reopened::Restarted(welcome, server_rx, client_tx));
+					}
+					return Ok(Reopened::Resumed(welcome, server_rx, client_tx));
+				},
+				// a restarted server signs with a new key, and has none of our state anyway //
+				Err(e) if rejected && shutdown.is_some() => {
+					info!(error = %e, "Server no longer knows the session, starting a new one");
+					match super::open_stream(&self.client, self.auth_token.as_deref(), None).await {
+						Ok((welcome, server_rx, client_tx)) => return Ok(Reopened::Restarted(welcome, server_rx, client_tx)),
+						Err(e) => {
+							warn!(attempt, error = %e, "Unable to start a new session");
+							last_error = e;
+						},
+					}
+				},
+				// past the grace period, retrying won't bring it back //
+				Err(e) if rejected => return Err(e),
+				Err(e) => {
+					warn!(attempt, error = %e, "Reconnect attempt failed");
+					last_error = e;
+				},
+			}
+		}
+
+		Err(last_error)
+	}
+}
+
+/// Who the session is to the server. Replaced when a restarted server starts a new session, see [`Resume::reconnect`].
+#[derive(Clone)]
+struct Credentials {
 	session_id: Uuid,
+	token: MetadataValue<Ascii>,
+}
+
+impl Credentials {
+	fn from_welcome(welcome: &Welcome) -> Result<Self> {
+		let session_id = Uuid::from_slice(&welcome.session_id)
+			.map_err(|e| anyhow!("Unable to convert received Vec<u8> to Uuid: {e}"))?;
+		let token = welcome.session_token
+			.parse()
+			.map_err(|e| anyhow!("Received bad session token: {e}"))?;
+
+		Ok(Self { session_id, token })
+	}
+}
+
+/// What the stream handler works with, kept across reconnects.
+#[derive(Clone)]
+struct StreamContext {
+	socket: Arc<UdpSocket>,
+	/// The reflector's too; relays on the server host share its ip.
+	server_addr: Option<SocketAddr>,
+	join_handler: JoinHandler,
+	/// Accepts joins while nothing takes [`Session::join_requests`].
+	auto_accept: bool,
+	joined_broadcast: broadcast::Sender<SocketAddr>,
+	claims: Claims,
+}
+
+
+pub struct Session {
+	credentials: watch::Receiver<Credentials>,
+	capabilities: Vec<Capability>,
+	join_timeout: Duration,
+	socket: Arc<UdpSocket>,
+	claims: Claims,
+	join_handler: JoinHandler,
+	connection: watch::Receiver<ConnectionState>,
 	cancellation_token: CancellationToken,
 }
 
 impl Session {
-	pub fn uuid(&self) -> &Uuid { &self.session_id }
+	/// Changes if the server restarts; listings of the old session are gone with it.
+	pub fn uuid(&self) -> Uuid { self.credentials.borrow().session_id }
+
+	/// Capabilities the server advertised in its welcome.
+	pub fn capabilities(&self) -> &[Capability] { &self.capabilities }
+
+	pub fn has(&self, capability: Capability) -> bool { self.capabilities.contains(&capability) }
 
-	pub fn id(&self) -> Vec<u8> { self.session_id.as_bytes().to_vec() }
+	/// The longest the server said it takes to answer a join.
+	pub fn join_timeout(&self) -> Duration { self.join_timeout }
+
+	/// The socket punches are made from.
+	pub fn socket(&self) -> &Arc<UdpSocket> { &self.socket }
+
+	/// Whoever else reads [`Self::socket`] leaves datagrams from these sources queued for the session.
+	pub fn claims(&self) -> &Claims { &self.claims }
+
+	pub fn id(&self) -> Vec<u8> { self.uuid().as_bytes().to_vec() }
+
+	/// What the server signed this session's id into, see [`super::reflect`].
+	pub fn token(&self) -> String { self.credentials.borrow().token.to_str().unwrap_or_default().to_string() }
+
+	/// Wraps `message` with the session token the server issued, which session rpcs require.
+	pub fn request<T>(&self, message: T) -> Request<T> {
+		let mut request = Request::new(message);
+		request.metadata_mut().insert(SESSION_TOKEN, self.credentials.borrow().token.clone());
+		request
+	}
+
+	/// Routes join requests for our listing to the returned receiver. Until then they're rejected,
+	/// or accepted with [`super::ClientOptions::auto_accept`]. Replaces any previous receiver; dropping it rejects further requests.
+	pub fn join_requests(&self) -> mpsc::Receiver<PendingJoin> {
+		let (tx, rx) = mpsc::channel(8);
+		*self.join_handler.lock().unwrap() = Some(tx);
+		rx
+	}
+
+	/// Follows the stream to the server as it drops and resumes.
+	pub fn connection(&self) -> watch::Receiver<ConnectionState> { self.connection.clone() }
 
 	pub fn end(self) { self.cancellation_token.cancel() }
 
-	pub async fn start(
-		session_id: Uuid,
-		server_rx: Streaming<ServerStreamMessage>,
-		client_tx: Sender<ClientStreamMessage>,
+	pub(super) async fn start(
+		welcome: Welcome,
+		socket: Arc<UdpSocket>,
+		claims: Claims,
+		server_addr: Option<SocketAddr>,
+		stream: (Streaming<ServerStreamMessage>, Sender<ClientStreamMessage>),
+		resume: Resume,
+		auto_accept: bool,
 	) -> Result<(Self, broadcast::Receiver<SocketAddr>)> {
+		let session_credentials = Credentials::from_welcome(&welcome)?;
+		let session_id = session_credentials.session_id;
+		let (credentials_tx, credentials) = watch::channel(session_credentials);
 		let cancellation_token = CancellationToken::new();
 
+		// unknown capabilities are from newer servers, ignore them //
+		let capabilities = welcome
+			.capabilities
+			.into_iter()
+			.filter_map(|c| Capability::try_from(c).ok())
+			.collect();
+
+		// older servers don't say, they wait up to the default timeout at each of six steps //
+		let join_timeout = match welcome.join_timeout_secs {
+			0 => TIMEOUT * 6,
+			secs => Duration::from_secs(secs),
+		};
+
 		let (joined_tx, joined_rx) = broadcast::channel(8);
+		let join_handler = JoinHandler::default();
+		let (state_tx, state_rx) = watch::channel(ConnectionState::Connected);
 
-		tokio::spawn(handle_stream(server_rx, client_tx.clone(), cancellation_token.clone(), joined_tx));
-		tokio::spawn(keepalive(client_tx, cancellation_token.clone()));
+		let ctx = StreamContext {
+			socket: socket.clone(),
+			server_addr,
+			join_handler: join_handler.clone(),
+			auto_accept,
+			joined_broadcast: joined_tx,
+			claims: claims.clone(),
+		};
+		let span = info_span!("session", %session_id);
+		tokio::spawn(run(credentials_tx, stream, ctx, resume, state_tx, cancellation_token.clone()).instrument(span));
 
 		Ok((
 			Self {
-				session_id,
+				credentials,
+				capabilities,
+				join_timeout,
+				socket,
+				claims,
+				join_handler,
+				connection: state_rx,
 				cancellation_token,
 			},
 			joined_rx,
@@ -42,84 +273,233 @@ impl Session {
 	}
 }
 
+/// Handles the stream until the session ends, resuming it whenever it drops.
+async fn run(
+	credentials: watch::Sender<Credentials>,
+	(mut server_rx, mut client_tx): (Streaming<ServerStreamMessage>, Sender<ClientStreamMessage>),
+	ctx: StreamContext,
+	resume: Resume,
+	state: watch::Sender<ConnectionState>,
+	cancellation_token: CancellationToken,
+) {
+	loop {
+		let connection = cancellation_token.child_token();
+		tokio::spawn(keepalive(client_tx.clone(), connection.clone()).in_current_span());
+
+		let shutdown = tokio::select! {
+			// takes the sender, so the request stream ends with the response //
+			retry_after = handle_stream(server_rx, client_tx, &ctx) => retry_after,
+			_ = cancellation_token.cancelled() => break,
+		};
+		connection.cancel();
+
+		let current = credentials.borrow().clone();
+		let reopened = tokio::select! {
+			reopened = resume.reconnect(&current, &state, shutdown) => reopened,
+			_ = cancellation_token.cancelled() => break,
+		};
+
+		let welcome = match reopened {
+			Ok(Reopened::Resumed(welcome, rx, tx)) => {
+				info!("Resumed session");
+				(server_rx, client_tx) = (rx, tx);
+				welcome
+			},
+			Ok(Reopened::Restarted(welcome, rx, tx)) => {
+				let restarted = match Credentials::from_welcome(&welcome) {
+					Ok(c) => c,
+					Err(e) => {
+						warn!(error = %e, "Unable to start a new session");
+						break;
+					},
+				};
+				info!(session_id = %restarted.session_id, "Started a new session on the restarted server");
+				credentials.send_replace(restarted);
+				(server_rx, client_tx) = (rx, tx);
+				welcome
+			},
+			Err(e) => {
+				warn!(error = %e, "Unable to resume session");
+				break;
+			},
+		};
+
+		// a restarted server doesn't know our punch address, and the nat may have moved it //
+		if welcome.capabilities.contains(&(Capability::UdpReflector as i32)) && let Some(reflector) = ctx.server_addr {
+			let token = credentials.borrow().token.to_str().unwrap_or_default().to_string();
+			if let Err(e) = super::reflect_among(&ctx.socket, reflector, &token, &ctx.claims).await {
+				warn!(error = %e, "Unable to register punching socket with reflector");
+			}
+		}
+		state.send_replace(ConnectionState::Connected);
+	}
+
+	state.send_replace(ConnectionState::Disconnected);
+}
+
+/// Returns once the stream ends, with how long to wait before reconnecting if the server shut down.
 async fn handle_stream(
 	mut server_rx: Streaming<ServerStreamMessage>, 
 	client_tx: Sender<ClientStreamMessage>, 
-	cancellation_token: CancellationToken,
-	joined_broadcast: broadcast::Sender<SocketAddr>,
-) {
-	tokio::select! {
-		_ = async {
-			loop {
-				match server_rx.message().await {
-					Ok(opt) => {
-						match opt {
-							Some(msg_struct) => {
-								if let Some(msg) = msg_struct.server_stream_enum {
-									
-									// Actual message handling
-									match msg {
-										ServerStreamEnum::Punch(punch) => { // PUNCH
-											// parse the addr
-											let addr = match parse_addr(&punch.ip, punch.port) {
-												Ok(a) => a,
-												Err(e) => {
-													eprintln!("Received bad punch addr: {e}");
-													let msg = ClientStreamMessage { client_stream_enum: Some(ClientStreamEnum::PunchStatus(PunchStatus { 
-														message: Some(format!("Bad punch addr: {e}")),
-														success: false,
-													}))};
-													if let Err(e) = client_tx.send_timeout(msg, TIMEOUT).await {
-														eprintln!("Unable to send punch status (bad addr) to server: {e}");
-													};
-													continue;
-												},
-											};
-											
-											// attempt punching
-											match super::punch(addr).await {
-												Ok(_) => {
-													// broadcast joined addr
-													if let Err(e) = joined_broadcast.send(addr) {
-														eprintln!("Unable to broadcast joined addr: {e}");
-													};
-
-													// send ok message to server
-													let msg = ClientStreamMessage { client_stream_enum: Some(ClientStreamEnum::PunchStatus(PunchStatus { 
-														message: None,
-														success: true,
-													}))};
-													if let Err(e) = client_tx.send_timeout(msg, TIMEOUT).await {
-														eprintln!("Unable to send punch status (bad addr) to server: {e}");
-													};
-												},
-												Err(e) => {
-													// send not-ok message to server
-													println!("Unable to punch: {e}"); // not neccecarily an error to fail punching.
-													let msg = ClientStreamMessage { client_stream_enum: Some(ClientStreamEnum::PunchStatus(PunchStatus { 
-														message: Some(format!("Unable to punch: {e}")),
-														success: false,
-													}))};
-													if let Err(e) = client_tx.send_timeout(msg, TIMEOUT).await {
-														eprintln!("Unable to send punch status (bad addr) to server: {e}");
-													};
-												},
-											}
+	ctx: &StreamContext,
+) -> Option<Duration> {
+	let StreamContext { socket, server_addr, join_handler, auto_accept, joined_broadcast, claims } = ctx;
+	let mut retry_after = None;
+
+	loop {
+		match server_rx.message().await {
+			Ok(opt) => {
+				match opt {
+					Some(msg_struct) => {
+						if let Some(msg) = msg_struct.server_stream_enum {
+							
+							// Actual message handling
+							match msg {
+								ServerStreamEnum::Punch(punch) => { // PUNCH
+									// a host may be punching several joiners at once //
+									tokio::spawn(handle_punch(punch, socket.clone(), claims.clone(), client_tx.clone(), joined_broadcast.clone()).in_current_span());
+								}
+								ServerStreamEnum::Relay(relay) => { // RELAY
+									let addr = match relay_addr(&relay, server_addr.map(|a| a.ip())) {
+										Ok(a) => a,
+										Err(e) => {
+											warn!(error = %e, "Received bad relay addr");
+											continue;
+										},
+									};
+
+									// latch our mapping on the relay before the game starts sending //
+									for _ in 0..RELAY_LATCH_PACKETS {
+										if let Err(e) = socket.send_to(b"relay", addr).await {
+											warn!(error = %e, "Unable to send relay latch packet");
 										}
 									}
 
-								};
-							},
-							None => break,
-						}
-					},
-					Err(status) => {
-						eprintln!("Received status message from server: {status}. Continuing.");
+									if let Err(e) = joined_broadcast.send(addr) {
+										debug!(error = %e, "Unable to broadcast relay addr");
+									};
+								}
+								ServerStreamEnum::JoinRequest(request) => { // JOIN REQUEST
+									let handler = join_handler.lock().unwrap().clone();
+									tokio::spawn(handle_join_request(request, handler, *auto_accept, client_tx.clone()).in_current_span());
+								}
+								ServerStreamEnum::Ping(_) => { // PING
+									// any message keeps the session alive //
+									let pong = ClientStreamMessage { client_stream_enum: None };
+									if let Err(e) = client_tx.send_timeout(pong, TIMEOUT).await {
+										warn!(error = %e, "Unable to answer ping");
+									};
+								}
+								ServerStreamEnum::Welcome(_) => { // WELCOME
+									warn!("Received a second welcome; ignoring");
+								}
+								ServerStreamEnum::Shutdown(shutdown) => { // SHUTDOWN
+									// punches in flight still arrive until the stream ends //
+									info!(reason = %shutdown.reason, retry_after_secs = shutdown.retry_after_secs, "Server is shutting down, reconnecting once the stream ends");
+									retry_after = Some(Duration::from_secs(shutdown.retry_after_secs));
+								}
+							}
+
+						};
 					},
+					None => break,
 				}
+			},
+			Err(status) => {
+				warn!(%status, "Received status message from server; continuing");
+			},
+		}
+	}
+
+	retry_after
+}
+
+async fn handle_punch(
+	punch: Punch,
+	socket: Arc<UdpSocket>,
+	claims: Claims,
+	client_tx: Sender<ClientStreamMessage>,
+	joined_broadcast: broadcast::Sender<SocketAddr>,
+) {
+	let result = match parse_addr(&punch.ip, punch.port) {
+		Ok(addr) => super::punch_among(socket, addr, &claims)
+			.await
+			.map(|_| addr)
+			.map_err(|e| format!("Unable to punch: {e}")),
+		Err(e) => {
+			warn!(error = %e, "Received bad punch addr");
+			Err(format!("Bad punch addr: {e}"))
+		},
+	};
+
+	let message = match result {
+		Ok(addr) => {
+			info!(peer = %addr, "Punched");
+			if let Err(e) = joined_broadcast.send(addr) {
+				debug!(error = %e, "Unable to broadcast joined addr");
+			};
+			None
+		},
+		Err(e) => {
+			info!(reason = %e, "Punch failed"); // not neccecarily an error to fail punching.
+			Some(e)
+		},
+	};
+
+	let status = PunchStatus {
+		success: message.is_none(),
+		message,
+		request_id: punch.request_id,
+	};
+	let msg = ClientStreamMessage { client_stream_enum: Some(ClientStreamEnum::PunchStatus(status)) };
+	if let Err(e) = client_tx.send_timeout(msg, TIMEOUT).await {
+		warn!(error = %e, "Unable to send punch status to server");
+	}
+}
+
+async fn handle_join_request(
+	request: IncomingJoin,
+	handler: Option<mpsc::Sender<PendingJoin>>,
+	auto_accept: bool,
+	client_tx: Sender<ClientStreamMessage>,
+) {
+	let reject = |reason: &str| JoinDecision {
+		request_id: request.request_id.clone(),
+		accept: false,
+		reason: Some(reason.to_string()),
+	};
+
+	let decision = match (Uuid::from_slice(&request.request_id), handler) {
+		(Err(_), _) => reject("Malformed join request"),
+
+		// nobody is listening //
+		(_, None) if auto_accept => JoinDecision {
+			request_id: request.request_id.clone(),
+			accept: true,
+			reason: None,
+		},
+		(_, None) => reject("Host is not accepting joins"),
+
+		(Ok(request_id), Some(handler)) => {
+			let (responder, decision) = oneshot::channel();
+			let pending = PendingJoin {
+				request_id,
+				joiner_ip: request.joiner_ip.clone(),
+				joiner: request.joiner.clone(),
+				payload: request.payload.clone(),
+				responder,
+			};
+
+			match handler.send(pending).await {
+				Ok(_) => decision.await.unwrap_or_else(|_| reject("Join request dropped")),
+				Err(_) => reject("Host is not accepting joins"),
 			}
-		} => {}
-		_ = cancellation_token.cancelled() => {}
+		},
+	};
+
+	let msg = ClientStreamMessage { client_stream_enum: Some(ClientStreamEnum::JoinDecision(decision)) };
+	if let Err(e) = client_tx.send_timeout(msg, TIMEOUT).await {
+		warn!(error = %e, "Unable to send join decision to server");
 	}
 }
 
@@ -133,7 +513,7 @@ async fn keepalive(
 				let keepalive = ClientStreamMessage { client_stream_enum: None };
 
 				if let Err(e) = client_tx.send_timeout(keepalive, TIMEOUT).await {
-					eprintln!("Error sending keepalive ping: {e}");
+					warn!(error = %e, "Error sending keepalive ping");
 				};
 
 				sleep(Duration::from_secs(60)).await;
@@ -143,19 +523,12 @@ async fn keepalive(
 	}
 }
 
+/// An empty relay ip means the relay lives on the grpc server's host.
+fn relay_addr(relay: &Relay, server_ip: Option<IpAddr>) -> Result<SocketAddr> {
+	if !relay.ip.is_empty() {
+		return parse_addr(&relay.ip, relay.port);
+	}
 
-fn parse_addr<P>(ip: &str, port: P) -> Result<SocketAddr>
-where
-	P: TryInto<u16>,
-	P::Error: std::fmt::Display,
-{
-	let ip: IpAddr = ip
-		.parse()
-		.map_err(|e| anyhow!("Unable to parse ip: {e}"))?;
-	
-	let port: u16 = port
-		.try_into()
-		.map_err(|e| anyhow!("Unable to coerce port: {e}"))?;
-	
-	Ok(SocketAddr::new(ip, port))
-}
\ No newline at end of file
+	let ip = server_ip.ok_or(anyhow!("Relay is on the server host but its ip is unknown"))?;
+	parse_addr(&ip.to_string(), relay.port)
+}
@@ -0,0 +1,103 @@
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use hyper_rustls::ConfigBuilderExt;
+use rustls::{client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier}, crypto::{aws_lc_rs, verify_tls12_signature, verify_tls13_signature, CryptoProvider}, pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime}, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+
+/// Which server certificates the client accepts over https.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Trust {
+	/// The OS's root store.
+	#[default]
+	NativeRoots,
+	/// Only certificates issued by these PEM CAs, e.g. a self-hosted server's own CA.
+	Ca(Vec<u8>),
+	/// Only this exact PEM certificate, whoever issued it. Its name and expiry are not checked.
+	Pinned(Vec<u8>),
+}
+
+impl Trust {
+	/// Without alpn protocols, each transport sets its own.
+	pub(super) fn client_config(&self) -> Result<ClientConfig> {
+		let provider = Arc::new(aws_lc_rs::default_provider());
+		let builder = ClientConfig::builder_with_provider(provider.clone())
+			.with_safe_default_protocol_versions()
+			.map_err(|e| anyhow!("Tls protocol versions error: {e}"))?;
+
+		let config = match self {
+			Self::NativeRoots => builder
+				.with_native_roots()
+				.map_err(|e| anyhow!("With native roots error: {e}"))?
+				.with_no_client_auth(),
+			Self::Ca(pem) => {
+				let mut roots = RootCertStore::empty();
+				for cert in CertificateDer::pem_slice_iter(pem) {
+					let cert = cert.map_err(|e| anyhow!("Malformed CA certificate: {e}"))?;
+					roots.add(cert).map_err(|e| anyhow!("Unusable CA certificate: {e}"))?;
+				}
+				if roots.is_empty() {
+					return Err(anyhow!("No CA certificate in pem"));
+				}
+
+				builder
+					.with_root_certificates(roots)
+					.with_no_client_auth()
+			},
+			Self::Pinned(pem) => {
+				let pinned = CertificateDer::from_pem_slice(pem)
+					.map_err(|e| anyhow!("Malformed pinned certificate: {e}"))?;
+
+				builder
+					.dangerous()
+					.with_custom_certificate_verifier(Arc::new(PinnedVerifier { pinned, provider }))
+					.with_no_client_auth()
+			},
+		};
+
+		Ok(config)
+	}
+}
+
+/// Accepts exactly one certificate, but still checks the handshake is signed by its key.
+#[derive(Debug)]
+struct PinnedVerifier {
+	pinned: CertificateDer<'static>,
+	provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+	fn verify_server_cert(
+		&self,
+		end_entity: &CertificateDer<'_>,
+		_intermediates: &[CertificateDer<'_>],
+		_server_name: &ServerName<'_>,
+		_ocsp_response: &[u8],
+		_now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		if end_entity.as_ref() != self.pinned.as_ref() {
+			return Err(rustls::Error::General("Server certificate does not match the pinned one".to_string()));
+		}
+		Ok(ServerCertVerified::assertion())
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.provider.signature_verification_algorithms.supported_schemes()
+	}
+}
@@ -0,0 +1,148 @@
+use std::{io, sync::Arc, task::{Context, Poll}};
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::{client::legacy::{self, connect::HttpConnector}, rt::{TokioExecutor, TokioIo}};
+use rustls::{pki_types::ServerName, ClientConfig};
+use tokio::net::{lookup_host, TcpStream};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tokio_util::either::Either;
+use tonic::{body::Body, codegen::{http, Service}, transport::{Endpoint, Uri}};
+use tonic_web::{GrpcWebCall, GrpcWebClientLayer, GrpcWebClientService};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+type WebService = GrpcWebClientService<legacy::Client<HttpsConnector<HttpConnector>, GrpcWebCall<Body>>>;
+
+/// How [`super::Client`] carries its rpcs to the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Transport {
+	/// grpc-web over http/1.1, for browser-like environments and proxies that only pass http/1.
+	#[default]
+	GrpcWeb,
+	/// Native grpc over http/2, with truly bidirectional streams and less overhead.
+	Grpc,
+}
+
+impl Transport {
+	pub(super) fn channel(self, server_url: &Uri, tls: ClientConfig) -> Result<Channel> {
+		match self {
+			Self::GrpcWeb => Ok(Channel::Web(Box::new(web(tls)))),
+			Self::Grpc => grpc(server_url, tls).map(Channel::Grpc),
+		}
+	}
+}
+
+/// Either transport, behind one `PuncherServiceClient`.
+#[derive(Clone)]
+pub enum Channel {
+	Grpc(tonic::transport::Channel),
+	Web(Box<WebService>),
+}
+
+impl Service<http::Request<Body>> for Channel {
+	type Response = http::Response<Body>;
+	type Error = BoxError;
+	type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		match self {
+			Self::Grpc(channel) => channel.poll_ready(cx).map_err(Into::into),
+			Self::Web(web) => web.poll_ready(cx).map_err(Into::into),
+		}
+	}
+
+	fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+		match self {
+			Self::Grpc(channel) => {
+				let fut = channel.call(request);
+				Box::pin(async move { Ok(fut.await?) })
+			},
+			Self::Web(web) => {
+				let fut = web.call(request);
+				Box::pin(async move { Ok(fut.await?.map(Body::new)) })
+			},
+		}
+	}
+}
+
+fn web(tls: ClientConfig) -> WebService {
+	let https = HttpsConnectorBuilder::new()
+		.with_tls_config(tls)
+		.https_or_http()
+		.enable_http1()
+		.build();
+
+	let client = legacy::Client::builder(TokioExecutor::new()).build(https);
+
+	tower::ServiceBuilder::new()
+		.layer(GrpcWebClientLayer::new())
+		.service(client)
+}
+
+/// Connects lazily, like the grpc-web client.
+fn grpc(server_url: &Uri, mut tls: ClientConfig) -> Result<tonic::transport::Channel> {
+	let host = server_url.host().ok_or(anyhow!("Server url has no host"))?;
+	let https = server_url.scheme_str() == Some("https");
+	let port = server_url.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+	let tls = if https {
+		tls.alpn_protocols = vec![b"h2".to_vec()];
+		let name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']').to_string())
+			.map_err(|e| anyhow!("Invalid server name {host}: {e}"))?;
+		Some((TlsConnector::from(Arc::new(tls)), name))
+	} else {
+		None
+	};
+
+	// tonic only does tls itself when configured with `ClientTlsConfig`, which can't pin a certificate,
+	// so the connector handshakes and the endpoint is told it's plain http //
+	let uri = Uri::builder()
+		.scheme("http")
+		.authority(format!("{host}:{port}"))
+		.path_and_query("/")
+		.build()
+		.map_err(|e| anyhow!("Invalid server url: {e}"))?;
+
+	let connector = Connector { addr: format!("{host}:{port}"), tls };
+	Ok(Endpoint::from(uri).connect_with_connector_lazy(connector))
+}
+
+type Io = TokioIo<Either<TcpStream, TlsStream<TcpStream>>>;
+
+#[derive(Clone)]
+struct Connector {
+	addr: String,
+	tls: Option<(TlsConnector, ServerName<'static>)>,
+}
+
+impl Service<Uri> for Connector {
+	type Response = Io;
+	type Error = io::Error;
+	type Future = BoxFuture<'static, io::Result<Io>>;
+
+	fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn call(&mut self, _uri: Uri) -> Self::Future {
+		let addr = self.addr.clone();
+		let tls = self.tls.clone();
+
+		Box::pin(async move {
+			let addr = lookup_host(addr)
+				.await?
+				.next()
+				.ok_or(io::Error::new(io::ErrorKind::NotFound, "Server host resolved to no addresses"))?;
+
+			let tcp = TcpStream::connect(addr).await?;
+			tcp.set_nodelay(true)?;
+
+			let io = match tls {
+				Some((connector, name)) => Either::Right(connector.connect(name, tcp).await?),
+				None => Either::Left(tcp),
+			};
+
+			Ok(TokioIo::new(io))
+		})
+	}
+}
@@ -1,5 +1,5 @@
 use godot::prelude::*;
-use crate::listing::{RustListing, RustListingNoId};
+use crate::{proto::Identity, server::listing::{RustListing, RustListingNoId}};
 
 #[derive(GodotClass)]
 #[class(base=RefCounted)]
@@ -8,6 +8,11 @@ pub struct GodotListing {
 	id: GString,
 	#[var]
 	listing_no_id: Gd<GodotListingNoId>,
+	#[var]
+	has_password: bool,
+	/// `account_id`, `display_name` and `platform`; empty for anonymous hosts.
+	#[var]
+	host: Dictionary,
 }
 
 #[godot_api]
@@ -16,6 +21,8 @@ impl IRefCounted for GodotListing {
 		Self {
 			id: GString::new(),
 			listing_no_id: Gd::from_init_fn(|b|GodotListingNoId::init(b)),
+			has_password: false,
+			host: Dictionary::new(),
 		}
 	}
 }
@@ -24,18 +31,51 @@ impl From<RustListing> for GodotListing {
 	fn from(listing: RustListing) -> Self {
 		Self {
 			id: listing.id().to_string().into(),
+			has_password: listing.has_password(),
+			host: identity_dict(listing.host()),
 			listing_no_id: Gd::from_object(listing.into_inner().into()),
 		}
 	}
 }
 
 
+/// Empty for anonymous players.
+pub fn identity_dict(identity: Option<&Identity>) -> Dictionary {
+	let mut dict = Dictionary::new();
+	if let Some(identity) = identity {
+		dict.set("account_id", identity.account_id.clone());
+		dict.set("display_name", identity.display_name.clone());
+		dict.set("platform", identity.platform.clone());
+	}
+	dict
+}
+
+
 // LISTINGNOID //
 #[derive(GodotClass)]
 #[class(base=RefCounted)]
 pub struct GodotListingNoId {
 	#[var]
 	pub name: GString,
+	/// Empty for no password.
+	#[var]
+	pub password: GString,
+	#[var]
+	pub game_mode: GString,
+	#[var]
+	pub map: GString,
+	#[var]
+	pub version: GString,
+	#[var]
+	pub current_players: u32,
+	/// 0 for unlimited.
+	#[var]
+	pub max_players: u32,
+	#[var]
+	pub region: GString,
+	/// String keys, PackedByteArray values.
+	#[var]
+	pub attributes: Dictionary,
 }
 
 #[godot_api]
@@ -43,6 +83,14 @@ impl IRefCounted for GodotListingNoId   {
 	fn init(_: Base<RefCounted>) -> Self { 
 		Self {
 			name: GString::new(),
+			password: GString::new(),
+			game_mode: GString::new(),
+			map: GString::new(),
+			version: GString::new(),
+			current_players: 0,
+			max_players: 0,
+			region: GString::new(),
+			attributes: Dictionary::new(),
 		}
 	}
 }
@@ -51,14 +99,44 @@ impl From<RustListingNoId> for GodotListingNoId {
 	fn from(listing_no_id: RustListingNoId) -> Self {
 		Self {
 			name: listing_no_id.name.into(),
+			password: listing_no_id.password.unwrap_or_default().into(),
+			game_mode: listing_no_id.game_mode.into(),
+			map: listing_no_id.map.into(),
+			version: listing_no_id.version.into(),
+			current_players: listing_no_id.current_players,
+			max_players: listing_no_id.max_players,
+			region: listing_no_id.region.into(),
+			attributes: listing_no_id
+				.attributes
+				.into_iter()
+				.map(|(k, v)| (k.to_variant(), PackedByteArray::from(v.as_slice()).to_variant()))
+				.collect(),
 		}
 	}
 }
 
 impl From<GodotListingNoId> for RustListingNoId {
 	fn from(gd_listing_no_id: GodotListingNoId) -> Self {
+		let password: String = gd_listing_no_id.password.into();
 		Self {
 			name: gd_listing_no_id.name.into(),
+			password: (!password.is_empty()).then_some(password),
+			game_mode: gd_listing_no_id.game_mode.into(),
+			map: gd_listing_no_id.map.into(),
+			version: gd_listing_no_id.version.into(),
+			current_players: gd_listing_no_id.current_players,
+			max_players: gd_listing_no_id.max_players,
+			region: gd_listing_no_id.region.into(),
+			// non string keys or non byte values are dropped //
+			attributes: gd_listing_no_id
+				.attributes
+				.iter_shared()
+				.filter_map(|(k, v)| {
+					let k = k.try_to::<GString>().ok()?.to_string();
+					let v = v.try_to::<PackedByteArray>().ok()?.to_vec();
+					Some((k, v))
+				})
+				.collect(),
 		}
 	}
 }
\ No newline at end of file
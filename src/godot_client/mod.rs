@@ -1,14 +1,21 @@
-use std::{net::{SocketAddr, SocketAddrV4}, sync::{Arc, OnceLock}};
+use std::{collections::HashMap, net::{SocketAddr, SocketAddrV4}, sync::{Arc, OnceLock}, time::Duration};
 use godot::{obj::WithBaseField, prelude::*};
-use tokio::{runtime::{self, Handle, Runtime}, sync::RwLock};
-use crate::{client::Client, listing::{GodotListing, GodotListingNoId, RustListing, RustListingNoId}, ThreadSafe};
+use tokio::{net::UdpSocket, runtime::{self, Handle, Runtime}, sync::{broadcast::error::RecvError, RwLock}, time::sleep};
+use tokio_stream::StreamExt;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use uuid::Uuid;
+use crate::{client::{Claims, Client, ClientOptions, ConnectionState, JoinOptions, JoinOutcome, PendingJoin, UpdateOptions}, proto::{Identity, ListingSort}, server::{listing::{RustListing, RustListingNoId}, query::RustListingQuery}, ThreadSafe};
 
 mod asyncvalue;
 use asyncvalue::AsyncValue;
 mod listing;
+use listing::{identity_dict, GodotListing, GodotListingNoId};
 
 static RUNTIME: OnceLock<Runtime> = OnceLock::new();
 
+/// Datagrams kept for `take_packets`; more are dropped until the game takes some.
+const MAX_QUEUED_PACKETS: usize = 1024;
+
 fn handle() -> Handle {
 	RUNTIME.get_or_init(|| { runtime::Builder::new_multi_thread().enable_all().build().unwrap() }).handle().clone()
 }
@@ -18,12 +25,37 @@ fn handle() -> Handle {
 struct PunchingClient {
 	base: Base<Node>,
 	client: ThreadSafe<Option<Client>>,
+	/// Bearer token for servers that verify identities, e.g. a JWT from the game's backend. Read by `connect`.
+	#[var]
+	auth_token: GString,
 	
 	errors: AsyncValue<String>,
-	connected: AsyncValue<bool>,
+	connected: AsyncValue<ConnectionState>,
 	listings: AsyncValue<Option<Vec<RustListing>>>,
+	listings_cursor: AsyncValue<Option<Vec<u8>>>,
 	owned_listing: AsyncValue<Option<String>>,
 	joined_dst: AsyncValue<Vec<SocketAddr>>,
+	join_outcome: AsyncValue<Option<JoinOutcome>>,
+	pending_joins: AsyncValue<Vec<JoinRequestInfo>>,
+	join_responders: ThreadSafe<HashMap<Uuid, PendingJoin>>,
+	socket: ThreadSafe<Option<GameSocket>>,
+	packets: ThreadSafe<Vec<(SocketAddr, Vec<u8>)>>,
+}
+
+/// The socket holes are punched from, which the game's traffic goes through as well.
+/// Dropping it stops handing its datagrams to `take_packets`.
+struct GameSocket {
+	socket: Arc<UdpSocket>,
+	_reader: DropGuard,
+}
+
+/// The cloneable part of a [`PendingJoin`], shown to gdscript.
+#[derive(Clone, PartialEq)]
+struct JoinRequestInfo {
+	request_id: Uuid,
+	joiner_ip: String,
+	joiner: Option<Identity>,
+	payload: Vec<u8>,
 }
 
 #[godot_api]
@@ -36,11 +68,18 @@ impl INode for PunchingClient {
 		Self {
 			base,
 			client: Arc::new(RwLock::new(None)),
+			auth_token: GString::new(),
 			
 			connected: AsyncValue::from_default("connection_changed"),
 			listings: AsyncValue::from_default("listings_changed"),
+			listings_cursor: AsyncValue::from_default("listings_cursor_changed"),
 			owned_listing: AsyncValue::from_default("owned_listing_changed"),
 			joined_dst: AsyncValue::from_default("joined_addrs_changed"),
+			join_outcome: AsyncValue::from_default("join_finished"),
+			pending_joins: AsyncValue::from_default("join_requests_changed"),
+			join_responders: Arc::new(RwLock::new(HashMap::new())),
+			socket: Arc::new(RwLock::new(None)),
+			packets: Arc::new(RwLock::new(Vec::new())),
 			errors: AsyncValue::from_default("async_error"),
 		}
 	}
@@ -54,6 +93,11 @@ impl INode for PunchingClient {
 		}
 
 		if let Some((sig, val)) = self.connected.poll() {
+			let val = match val {
+				ConnectionState::Connected => "connected",
+				ConnectionState::Reconnecting { .. } => "reconnecting",
+				ConnectionState::Disconnected => "disconnected",
+			};
 			base.emit_signal(sig, &[val.to_variant()]);
 		};
 
@@ -71,6 +115,11 @@ impl INode for PunchingClient {
 			base.emit_signal(sig, &[gd_listings]);
 		};
 
+		if let Some((sig, val)) = self.listings_cursor.poll() {
+			let val = val.map_or(Variant::nil(), |c| PackedByteArray::from(c.as_slice()).to_variant());
+			base.emit_signal(sig, &[val]);
+		};
+
 		if let Some((sig, val)) = self.owned_listing.poll() {
 			let val = val.clone().map_or(Variant::nil(), |v| v.to_variant());
 			base.emit_signal(sig, &[val]);
@@ -92,22 +141,66 @@ impl INode for PunchingClient {
 
 			base.emit_signal(sig, &[val.to_variant()]);
 		};
+
+		if let Some((sig, val)) = self.pending_joins.poll() {
+			let val: Array<Dictionary> = val
+				.iter()
+				.map(|info| {
+					let mut dict = Dictionary::new();
+					dict.set("request_id", info.request_id.to_string());
+					dict.set("joiner_ip", info.joiner_ip.clone());
+					dict.set("joiner", identity_dict(info.joiner.as_ref()));
+					dict.set("payload", PackedByteArray::from(info.payload.as_slice()));
+					dict
+				})
+				.collect();
+
+			base.emit_signal(sig, &[val.to_variant()]);
+		};
+
+		if let Some((sig, val)) = self.join_outcome.poll() {
+			let val = val.as_ref().map_or(Variant::nil(), |o| {
+				let mut dict = Dictionary::new();
+				dict.set("connected", o.connected());
+				dict.set("joiner_success", o.joiner.success);
+				dict.set("joiner_message", o.joiner.message.clone().unwrap_or_default());
+				dict.set("host_success", o.host.success);
+				dict.set("host_message", o.host.message.clone().unwrap_or_default());
+				dict.set("peer_ip", o.peer.map(|a| a.ip().to_string()).unwrap_or_default());
+				dict.set("peer_port", o.peer.map_or(0, |a| a.port()));
+				dict.set("fallback", o.fallback.as_str_name());
+				dict.to_variant()
+			});
+			base.emit_signal(sig, &[val]);
+		};
 	}
 }
 
 #[godot_api]
 impl PunchingClient {
+	/// `"connected"`, `"reconnecting"` while a dropped session is resumed, or `"disconnected"`.
 	#[signal]
 	pub fn connection_changed(new_connection: Variant);
+	/// Fires on its own while connected as listings come and go; `get_listings`/`query_listings` also set it.
 	#[signal]
 	pub fn listings_changed(new_listings: Variant);
+	/// Pass to `query_listings` as `cursor` for the next page; nil on the last page.
+	#[signal]
+	pub fn listings_cursor_changed(next_cursor: Variant);
 	#[signal]
 	pub fn owned_listing_changed(new_owned_listing: Variant);
 	#[signal]
 	pub fn joined_addrs_changed(new_joined_addr: Variant);
 	#[signal]
+	pub fn join_finished(result: Variant);
+	/// Pending requests must be answered with `respond_join`.
+	#[signal]
+	pub fn join_requests_changed(pending: Variant);
+	#[signal]
 	pub fn async_error(msg: GString);
 
+	/// Binds the udp socket holes are punched from to `ip`/`port`, 0 picking any port. The game doesn't bind its own:
+	/// its traffic goes through this one, with `send_packet` and `take_packets`, so it reaches punched peers.
 	#[func]
 	pub fn connect(&self, server_url: String, server_port: u16, ip: String, port: u16) {
 		let ip = match ip.parse() {
@@ -123,11 +216,17 @@ impl PunchingClient {
 		let client = self.client.clone();
 		let connected_flag = self.connected.inner().clone();
 		let joined_dst = self.joined_dst.inner().clone();
+		let client_listings = self.listings.inner().clone();
+		let pending_joins = self.pending_joins.inner().clone();
+		let join_responders = self.join_responders.clone();
 		let error = self.errors.inner().clone();
-		
+		let game_socket = self.socket.clone();
+		let packets = self.packets.clone();
+		let auth_token = (!self.auth_token.is_empty()).then(|| self.auth_token.to_string());
 		
 		let fut = async move {
-			let new_client = match Client::new(server_url.parse().unwrap()).await {
+			let options = ClientOptions { auth_token, ..Default::default() };
+			let mut new_client = match Client::with_options(server_url.parse().unwrap(), options).await {
 				Ok(c) => c,
 				Err(e) => {
 					let mut err = error.write().await;
@@ -136,16 +235,175 @@ impl PunchingClient {
 				},
 			};
 
+			let socket = match UdpSocket::bind(addr).await {
+				Ok(s) => s,
+				Err(e) => {
+					let mut err = error.write().await;
+					*err = format!("Unable to bind {addr}: {e}");
+					return;
+				},
+			};
+
+			let socket = Arc::new(socket);
+			let mut joined_rx = match new_client.start_session_with(socket.clone()).await {
+				Ok(rx) => rx,
+				Err(e) => {
+					let mut err = error.write().await;
+					*err = e.to_string();
+					return;
+				},
+			};
+
+			// hand the game every datagram the session doesn't claim //
+			let claims = new_client.session().as_ref().map(|s| s.claims().clone()).unwrap_or_default();
+			let reader = CancellationToken::new();
+			tokio::spawn(read_packets(socket.clone(), claims, packets, error.clone(), reader.clone()));
+			*game_socket.write().await = Some(GameSocket { socket, _reader: reader.drop_guard() });
+
+			// forward joined addrs //
+			tokio::spawn(async move {
+				loop {
+					match joined_rx.recv().await {
+						Ok(addr) => joined_dst.write().await.push(addr),
+						Err(RecvError::Lagged(_)) => continue,
+						Err(RecvError::Closed) => break,
+					}
+				}
+			});
+
+			// forward join requests //
+			let mut join_requests = match new_client.join_requests() {
+				Ok(rx) => rx,
+				Err(e) => {
+					let mut err = error.write().await;
+					*err = e.to_string();
+					return;
+				},
+			};
+			tokio::spawn(async move {
+				while let Some(pending) = join_requests.recv().await {
+					let info = JoinRequestInfo {
+						request_id: pending.request_id,
+						joiner_ip: pending.joiner_ip.clone(),
+						joiner: pending.joiner.clone(),
+						payload: pending.payload.clone(),
+					};
+					join_responders.write().await.insert(pending.request_id, pending);
+					pending_joins.write().await.push(info);
+				}
+			});
+
+			// keep listings live //
+			let mut listing_events = match new_client.watch_listings().await {
+				Ok(s) => s,
+				Err(e) => {
+					let mut err = error.write().await;
+					*err = e.to_string();
+					return;
+				},
+			};
+
+			// follow the session's connection //
+			let mut connection = match new_client.connection() {
+				Some(c) => c,
+				None => {
+					let mut err = error.write().await;
+					*err = String::from("Session ended while connecting");
+					return;
+				},
+			};
+
+			let mut watch_connection = connection.clone();
+			let watch_client = client.clone();
+			let watch_error = error.clone();
+			tokio::spawn(async move {
+				let mut listings = Vec::new();
+				loop {
+					while let Some(event) = listing_events.next().await {
+						match event {
+							Ok(event) => event.apply(&mut listings),
+							Err(e) => {
+								let mut err = watch_error.write().await;
+								*err = e.to_string();
+								break;
+							},
+						}
+						*client_listings.write().await = Some(listings.clone());
+					}
+
+					// the watch ends with the stream it was on, open another once the session is connected again //
+					listing_events = loop {
+						if watch_connection.wait_for(|s| *s == ConnectionState::Connected).await.is_err() {
+							return;
+						}
+
+						let reopened = match watch_client.read().await.as_ref() {
+							Some(c) => c.watch_listings().await,
+							None => return,
+						};
+						match reopened {
+							Ok(events) => break events,
+							Err(e) => {
+								*watch_error.write().await = e.to_string();
+
+								// retried once the connection changes //
+								if watch_connection.changed().await.is_err() {
+									return;
+								}
+							},
+						}
+					};
+				}
+			});
+			tokio::spawn(async move {
+				loop {
+					let state = *connection.borrow_and_update();
+					*connected_flag.write().await = state;
+					if connection.changed().await.is_err() {
+						break;
+					}
+				}
+				*connected_flag.write().await = ConnectionState::Disconnected;
+			});
+
 			let mut client = client.write().await;
 			*client = Some(new_client);
-
-			let mut flag = connected_flag.write().await;
-			*flag = true;
 		};
 
 		handle().spawn(fut);
 	}
 
+	#[func]
+	pub fn respond_join(&self, request_id: String, accept: bool, reason: String) {
+		let request_id: Uuid = match request_id.parse() {
+			Ok(id) => id,
+			Err(e) => {
+				godot_error!("Couldnt respond to join: {e}");
+				return
+			}
+		};
+
+		let pending_joins = self.pending_joins.inner().clone();
+		let join_responders = self.join_responders.clone();
+		let error = self.errors.inner().clone();
+
+		handle().spawn(async move {
+			pending_joins.write().await.retain(|info| info.request_id != request_id);
+
+			let Some(pending) = join_responders.write().await.remove(&request_id) else {
+				let mut err = error.write().await;
+				*err = String::from("Join request expired or already answered");
+				return;
+			};
+
+			if accept {
+				pending.accept();
+			} else {
+				pending.reject(reason);
+			}
+		});
+	}
+
 	#[func]
 	pub fn remove_joined(&self, index: u32) {
 		let index = match index.try_into() {
@@ -175,23 +433,73 @@ impl PunchingClient {
 		
 		let client = self.client.clone();
 		let connected_flag = self.connected.inner().clone();
+		let game_socket = self.socket.clone();
 		let error = self.errors.inner().clone();
 
 		handle().spawn( async move {
+			*game_socket.write().await = None;
+
 			let mut client = client.write().await;
 			let c = client.take();
-			if let Some(c) = c {
-				c.end().await;
+			if let Some(mut c) = c {
+				c.end_session();
 			} else {
 				let mut err = error.write().await;
 				*err = String::from("Not connected");
 			}
 
 			let mut flag = connected_flag.write().await;
-			*flag = false
+			*flag = ConnectionState::Disconnected;
 		});
 	}
 	
+	/// Sends `data` from the socket holes are punched from, see `connect`.
+	#[func]
+	pub fn send_packet(&self, ip: String, port: u16, data: PackedByteArray) {
+		let ip = match ip.parse() {
+			Ok(ip) => ip,
+			Err(e) => {
+				godot_error!("Could not parse ip: {e}");
+				return;
+			}
+		};
+		let addr = SocketAddr::new(ip, port);
+		let game_socket = self.socket.clone();
+		let error = self.errors.inner().clone();
+		let data = data.to_vec();
+
+		handle().spawn(async move {
+			let Some(socket) = game_socket.read().await.as_ref().map(|s| s.socket.clone()) else {
+				let mut err = error.write().await;
+				*err = String::from("Not connected");
+				return;
+			};
+
+			if let Err(e) = socket.send_to(&data, addr).await {
+				let mut err = error.write().await;
+				*err = format!("Unable to send to {addr}: {e}");
+			}
+		});
+	}
+
+	/// Datagrams that arrived since the last call, as `{ip, port, data}`. Peers' late `punch` packets show up too; skip them.
+	#[func]
+	pub fn take_packets(&self) -> Array<Dictionary> {
+		// don't stall the frame on the reader, its datagrams wait for the next call //
+		let Ok(mut packets) = self.packets.try_write() else { return Array::new() };
+
+		packets
+			.drain(..)
+			.map(|(src, data)| {
+				let mut dict = Dictionary::new();
+				dict.set("ip", src.ip().to_string());
+				dict.set("port", src.port());
+				dict.set("data", PackedByteArray::from(data.as_slice()));
+				dict
+			})
+			.collect()
+	}
+
 	#[func]
 	pub fn create_listing(&self, listing: Gd<GodotListingNoId>) {
 		let client = self.client.clone();
@@ -221,6 +529,28 @@ impl PunchingClient {
 		});
 	}
 
+	/// Keeps the listing id; an empty password keeps the current one, unless `clear_password`.
+	#[func]
+	pub fn update_listing(&self, listing: Gd<GodotListingNoId>, clear_password: bool) {
+		let client = self.client.clone();
+		let listing = RustListingNoId::from(listing.bind().clone());
+		let error = self.errors.inner().clone();
+
+		handle().spawn(async move {
+			let mut client = client.write().await;
+			let Some(c) = client.as_mut() else {
+				let mut err = error.write().await;
+				*err = String::from("Not connected");
+				return;
+			};
+
+			if let Err(e) = c.update_listing_with(listing, UpdateOptions { clear_password }).await {
+				let mut err = error.write().await;
+				*err = e.to_string();
+			};
+		});
+	}
+
 	#[func]
 	pub fn remove_listing(&self) {
 		let client = self.client.clone();
@@ -274,9 +604,51 @@ impl PunchingClient {
 		});
 	}
 
+	/// Keys (all optional): `name_contains`, `attributes` (String -> PackedByteArray), `min_players`, `max_players`,
+	/// `has_password`, `version`, `sort` ("id", "name" or "players"), `descending`, `page_size` and `cursor`.
+	#[func]
+	pub fn query_listings(&self, query: Dictionary) {
+		let query = match parse_query(&query) {
+			Ok(q) => q,
+			Err(e) => {
+				godot_error!("Bad listings query: {e}");
+				return;
+			}
+		};
+
+		let client = self.client.clone();
+		let client_listings = self.listings.inner().clone();
+		let listings_cursor = self.listings_cursor.inner().clone();
+		let error = self.errors.inner().clone();
+
+		handle().spawn(async move {
+			let mut client = client.write().await;
+			let Some(c) = client.as_mut() else {
+				let mut err = error.write().await;
+				*err = String::from("Not connected");
+				return;
+			};
+
+			let page = match c.query_listings(query).await {
+				Ok(p) => p,
+				Err(e) => {
+					let mut err = error.write().await;
+					*err = e.to_string();
+					return
+				}
+			};
+
+			*listings_cursor.write().await = page.next_cursor;
+
+			let mut flag = client_listings.write().await;
+			*flag = Some(page.listings);
+		});
+	}
+
 	
+	/// `password` is ignored by listings without one.
 	#[func]
-	pub fn join_listing(&self, listing_id: String) {
+	pub fn join_listing(&self, listing_id: String, password: String) {
 		let listing_id = match listing_id.parse() {
 			Ok(id) => id,
 			Err(e) => {
@@ -286,6 +658,7 @@ impl PunchingClient {
 		};
 
 		let client = self.client.clone();
+		let join_outcome = self.join_outcome.inner().clone();
 		let error = self.errors.inner().clone();
 
 		handle().spawn(async move {
@@ -296,11 +669,101 @@ impl PunchingClient {
 				return;
 			};
 
-			if let Err(e) = c.join(listing_id).await {
-				let mut err = error.write().await;
-				*err = e.to_string();
-				return;
+			let options = JoinOptions {
+				password: (!password.is_empty()).then_some(password),
+				..Default::default()
 			};
+
+			let outcome = match c.join_with(listing_id, options).await {
+				Ok(o) => o,
+				Err(e) => {
+					let mut err = error.write().await;
+					*err = e.to_string();
+					return;
+				}
+			};
+
+			let mut flag = join_outcome.write().await;
+			*flag = Some(outcome);
 		});
 	}
-}
\ No newline at end of file
+}
+
+/// Queues datagrams on `socket` for `take_packets` until `stop`, leaving those the session's punches wait for.
+async fn read_packets(
+	socket: Arc<UdpSocket>,
+	claims: Claims,
+	packets: ThreadSafe<Vec<(SocketAddr, Vec<u8>)>>,
+	error: ThreadSafe<String>,
+	stop: CancellationToken,
+) {
+	let mut buf = vec![0u8; u16::MAX as usize];
+
+	loop {
+		let sender = tokio::select! {
+			_ = stop.cancelled() => break,
+			sender = socket.peek_sender() => sender,
+		};
+
+		match sender {
+			Ok(src) if claims.contains(&src) => {
+				sleep(Duration::from_millis(5)).await;
+				continue;
+			},
+			Ok(_) => {},
+			// e.g. an icmp unreachable for an earlier send, the next datagram is fine //
+			Err(e) => {
+				*error.write().await = format!("Udp socket error: {e}");
+				sleep(Duration::from_millis(5)).await;
+				continue;
+			},
+		}
+
+		let (len, src) = match socket.recv_from(&mut buf).await {
+			Ok(r) => r,
+			Err(_) => continue,
+		};
+
+		let mut packets = packets.write().await;
+		if packets.len() < MAX_QUEUED_PACKETS {
+			packets.push((src, buf[..len].to_vec()));
+		}
+	}
+}
+
+fn parse_query(dict: &Dictionary) -> Result<RustListingQuery, String> {
+	fn get<T: FromGodot>(dict: &Dictionary, key: &str) -> Result<Option<T>, String> {
+		dict.get(key)
+			.map(|v| v.try_to::<T>().map_err(|e| format!("{key}: {e}")))
+			.transpose()
+	}
+
+	let sort = match get::<GString>(dict, "sort")?.map(String::from).as_deref() {
+		None | Some("id") => ListingSort::Id,
+		Some("name") => ListingSort::Name,
+		Some("players") => ListingSort::Players,
+		Some(other) => return Err(format!("Unknown sort: {other}")),
+	};
+
+	let attributes = get::<Dictionary>(dict, "attributes")?
+		.unwrap_or_default()
+		.iter_shared()
+		.map(|(k, v)| Ok((
+			k.try_to::<GString>().map_err(|e| format!("attribute key: {e}"))?.to_string(),
+			v.try_to::<PackedByteArray>().map_err(|e| format!("attribute value: {e}"))?.to_vec(),
+		)))
+		.collect::<Result<_, String>>()?;
+
+	Ok(RustListingQuery {
+		name_contains: get::<GString>(dict, "name_contains")?.map(String::from),
+		attributes,
+		min_players: get(dict, "min_players")?,
+		max_players: get(dict, "max_players")?,
+		has_password: get(dict, "has_password")?,
+		version: get::<GString>(dict, "version")?.map(String::from),
+		sort,
+		descending: get(dict, "descending")?.unwrap_or_default(),
+		page_size: get(dict, "page_size")?.unwrap_or_default(),
+		cursor: get::<PackedByteArray>(dict, "cursor")?.map(|c| c.to_vec()),
+	})
+}
@@ -1,12 +1,34 @@
-use std::{sync::Arc, time::Duration};
+use std::{net::{IpAddr, SocketAddr}, sync::Arc, time::Duration};
+use anyhow::{anyhow, Result};
 use tokio::sync::RwLock;
 
 type ThreadSafe<T> = Arc<RwLock<T>>;
 
 const TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Addresses travel as an ip string and a wider port in messages, in both directions.
+fn parse_addr<P>(ip: &str, port: P) -> Result<SocketAddr>
+where
+	P: TryInto<u16>,
+	P::Error: std::fmt::Display,
+{
+	let ip: IpAddr = ip
+		.parse()
+		.map_err(|e| anyhow!("Unable to parse ip: {e}"))?;
+	
+	let port: u16 = port
+		.try_into()
+		.map_err(|e| anyhow!("Unable to coerce port: {e}"))?;
+	
+	Ok(SocketAddr::new(ip, port))
+}
+
+/// Bumped on any breaking change to `proto/puncher.proto`; sent in `Welcome` and checked by the client.
+pub const PROTOCOL_VERSION: u32 = 4;
+
 pub mod server;
 pub mod client;
+pub mod stun;
 
 pub mod proto {
 	tonic::include_proto!("puncher");
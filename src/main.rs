@@ -1,9 +1,67 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
-
-use nat_puncher::server;
+use std::future::pending;
+use clap::Parser;
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
+use nat_puncher::server::{self, config::{Cli, ServerConfig}};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-	let addr = std::net::SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3000));
-    server::run(addr).await
+	let config = ServerConfig::load(Cli::parse())?;
+	init_tracing(&config);
+
+	info!(addr = %config.addr(), "Listening");
+
+	let stun = async {
+		match config.stun_addr() {
+			Some(addr) => server::stun::run(addr).await,
+			None => pending().await,
+		}
+	};
+
+	// stun has nothing to drain, and stops with the process //
+	tokio::select! {
+		result = server::run_with_shutdown(config.clone(), shutdown_signal()) => result,
+		result = stun => result,
+	}
+}
+
+/// Prints events at `config.log_level` and up, as `config.log_format`. Other crates only get to warn.
+fn init_tracing(config: &ServerConfig) {
+	let level = &config.log_level;
+	let filter = EnvFilter::new(format!("warn,nat_puncher={level},nat_puncher_server={level}"));
+	let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+	match config.log_format.as_str() {
+		"json" => subscriber.json().with_current_span(true).with_span_list(true).init(),
+		_ => subscriber.pretty().init(),
+	}
+}
+
+/// Ctrl-c, or the SIGTERM hosts send before stopping the process.
+async fn shutdown_signal() {
+	#[cfg(unix)]
+	let terminate = async {
+		use tokio::signal::unix::{signal, SignalKind};
+		match signal(SignalKind::terminate()) {
+			Ok(mut terminate) => { terminate.recv().await; },
+			Err(e) => {
+				error!(error = %e, "Unable to listen for SIGTERM");
+				pending::<()>().await;
+			},
+		}
+	};
+	#[cfg(not(unix))]
+	let terminate = pending::<()>();
+
+	let interrupt = async {
+		if let Err(e) = tokio::signal::ctrl_c().await {
+			error!(error = %e, "Unable to listen for ctrl-c");
+			pending::<()>().await;
+		}
+	};
+
+	tokio::select! {
+		_ = interrupt => {},
+		_ = terminate => {},
+	}
 }
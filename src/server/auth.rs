@@ -0,0 +1,194 @@
+use std::{sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tonic::{service::Interceptor, Request, Status};
+use uuid::Uuid;
+use crate::proto::Identity;
+
+/// Metadata key the session token is sent under on session rpcs.
+pub const SESSION_TOKEN: &str = "x-session-token";
+/// Metadata key of the `Bearer` token `StreamSession` is opened with, see [`AuthProvider`].
+pub const AUTHORIZATION: &str = "authorization";
+
+const TAG_LEN: usize = 32;
+
+/// Signs session ids into tokens, so only the client a session was issued to can act as it.
+///
+/// The key is random per process; sessions don't outlive it anyway.
+#[derive(Clone)]
+pub struct SessionKey {
+	key: Arc<[u8; 32]>,
+}
+
+impl Default for SessionKey {
+	fn default() -> Self {
+		Self { key: Arc::new(rand::random()) }
+	}
+}
+
+impl SessionKey {
+	pub fn from_bytes(key: [u8; 32]) -> Self {
+		Self { key: Arc::new(key) }
+	}
+
+	pub fn to_bytes(&self) -> [u8; 32] { *self.key }
+
+	/// The same key from the same `secret` every time, so tokens outlive a restart without the key being stored.
+	pub fn derive(secret: &[u8]) -> Self {
+		let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac takes any key length");
+		mac.update(b"nat_puncher session key");
+		Self::from_bytes(mac.finalize().into_bytes().into())
+	}
+
+	fn mac(&self, session_id: &Uuid) -> Hmac<Sha256> {
+		let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_slice()).expect("hmac takes any key length");
+		mac.update(session_id.as_bytes());
+		mac
+	}
+
+	/// The id followed by its signature, url-safe base64.
+	pub fn issue(&self, session_id: &Uuid) -> String {
+		let mut token = session_id.as_bytes().to_vec();
+		token.extend(self.mac(session_id).finalize().into_bytes());
+		URL_SAFE_NO_PAD.encode(token)
+	}
+
+	/// The session id `token` was issued for, if this key signed it.
+	pub fn verify(&self, token: &str) -> Option<Uuid> {
+		let token = URL_SAFE_NO_PAD.decode(token).ok()?;
+		if token.len() != 16 + TAG_LEN {
+			return None;
+		}
+
+		let (session_id, tag) = token.split_at(16);
+		let session_id = Uuid::from_slice(session_id).ok()?;
+		// constant time //
+		self.mac(&session_id).verify_slice(tag).ok()?;
+
+		Some(session_id)
+	}
+}
+
+/// The session a request's token was verified for, set by [`AuthInterceptor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthedSession(pub Uuid);
+
+
+// -- PROVIDERS -- //
+
+/// Verifies who a player is from the bearer token their client opens `StreamSession` with,
+/// e.g. one issued by the game's account backend.
+///
+/// Called from an interceptor, so it can't await; providers that ask a remote service should cache.
+pub trait AuthProvider: Send + Sync + 'static {
+	/// The identity `token` proves. Errors are sent to the client as `unauthenticated`.
+	fn authenticate(&self, token: &str) -> Result<Identity>;
+}
+
+/// Accepts HS256 JWTs signed with a secret shared with the game's backend.
+///
+/// Claims: `sub` is the account id, `name` the display name, `platform` the platform, `exp` the unix expiry.
+pub struct JwtAuth {
+	encoding: EncodingKey,
+	decoding: DecodingKey,
+	validation: Validation,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+	sub: String,
+	#[serde(default)]
+	name: String,
+	#[serde(default)]
+	platform: String,
+	exp: u64,
+}
+
+impl JwtAuth {
+	pub fn new(secret: impl AsRef<[u8]>) -> Self {
+		// only HS256, `exp` and `sub` required, no grace past `exp` //
+		let mut validation = Validation::new(Algorithm::HS256);
+		validation.set_required_spec_claims(&["exp", "sub"]);
+		validation.leeway = 0;
+
+		Self {
+			encoding: EncodingKey::from_secret(secret.as_ref()),
+			decoding: DecodingKey::from_secret(secret.as_ref()),
+			validation,
+		}
+	}
+
+	/// A token for `identity` that expires after `ttl`, as the game's backend would issue it.
+	pub fn sign(&self, identity: &Identity, ttl: Duration) -> String {
+		let claims = Claims {
+			sub: identity.account_id.clone(),
+			name: identity.display_name.clone(),
+			platform: identity.platform.clone(),
+			exp: (SystemTime::now() + ttl).duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+		};
+
+		jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding).expect("hs256 signs any claims")
+	}
+}
+
+impl AuthProvider for JwtAuth {
+	fn authenticate(&self, token: &str) -> Result<Identity> {
+		let claims = jsonwebtoken::decode::<Claims>(token, &self.decoding, &self.validation)
+			.map_err(|e| anyhow!("{e}"))?
+			.claims;
+
+		Ok(Identity {
+			account_id: claims.sub,
+			display_name: claims.name,
+			platform: claims.platform,
+		})
+	}
+}
+
+
+// -- INTERCEPTOR -- //
+
+/// Checks the tokens on every request that carries them, recording what they prove in the extensions:
+/// an [`AuthedSession`] for a session token, an [`Identity`] for a bearer token.
+///
+/// Requests without tokens pass through, for the rpcs that need neither. Handlers that need a session check
+/// for [`AuthedSession`] and that the session is still live; `StreamSession` requires an [`Identity`] when
+/// a provider is set.
+#[derive(Clone)]
+pub struct AuthInterceptor {
+	pub sessions: SessionKey,
+	pub provider: Option<Arc<dyn AuthProvider>>,
+}
+
+impl Interceptor for AuthInterceptor {
+	fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+		if let Some(token) = request.metadata().get(SESSION_TOKEN) {
+			let session_id = token
+				.to_str()
+				.ok()
+				.and_then(|token| self.sessions.verify(token))
+				.ok_or(Status::unauthenticated("Invalid session token"))?;
+
+			request.extensions_mut().insert(AuthedSession(session_id));
+		}
+
+		if let (Some(provider), Some(bearer)) = (self.provider.as_ref(), request.metadata().get(AUTHORIZATION)) {
+			let token = bearer
+				.to_str()
+				.ok()
+				.and_then(|b| b.strip_prefix("Bearer "))
+				.ok_or(Status::unauthenticated("Authorization is not a bearer token"))?;
+
+			let identity = provider
+				.authenticate(token)
+				.map_err(|e| Status::unauthenticated(format!("Invalid bearer token: {e}")))?;
+			request.extensions_mut().insert(identity);
+		}
+
+		Ok(request)
+	}
+}
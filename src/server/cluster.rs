@@ -0,0 +1,351 @@
+use std::{net::{IpAddr, SocketAddr}, sync::Arc, time::Duration};
+use anyhow::{anyhow, Result};
+use futures::{future::join_all, stream::{FuturesUnordered, StreamExt}};
+use tokio::time::timeout;
+use sha2::{Digest, Sha256};
+use tonic::{metadata::{Ascii, MetadataValue}, service::{interceptor::InterceptedService, Interceptor}, transport::{Channel, Uri}, Code, Request, Response, Status};
+use uuid::Uuid;
+use tracing::warn;
+use crate::{parse_addr, proto::{node_service_client::NodeServiceClient, node_service_server::NodeService, JoinDecision, NodeAskHostRequest, NodeAskHostResponse, NodeListingsRequest, NodeListingsResponse, NodePunchRequest, NodeRelayRequest, NodeRelayResponse, PunchStatus}, server::{listing::RustListing, session::SessionRef, store::RendezvousStore}};
+
+type NodeClient = NodeServiceClient<InterceptedService<Channel, NodeSecret>>;
+
+/// Metadata key nodes present the shared node secret under.
+pub const NODE_SECRET: &str = "x-node-secret";
+
+// ---- SECRET ---- //
+
+/// The secret every node in a cluster shares. Attached to outgoing node rpcs as an interceptor,
+/// and required on incoming ones by [`NodeAuth`], since node rpcs act on any session without its token.
+#[derive(Clone)]
+pub struct NodeSecret {
+	value: MetadataValue<Ascii>,
+}
+
+impl NodeSecret {
+	pub fn new(secret: &str) -> Result<Self> {
+		if secret.is_empty() {
+			return Err(anyhow!("Node secret must not be empty"));
+		}
+		let value = secret.parse().map_err(|e| anyhow!("Node secret must be printable ascii: {e}"))?;
+		Ok(Self { value })
+	}
+
+	fn digest(value: &[u8]) -> [u8; 32] { Sha256::digest(value).into() }
+}
+
+impl Interceptor for NodeSecret {
+	fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+		request.metadata_mut().insert(NODE_SECRET, self.value.clone());
+		Ok(request)
+	}
+}
+
+/// Turns away node rpcs that don't present the [`NodeSecret`].
+#[derive(Clone)]
+pub struct NodeAuth {
+	digest: [u8; 32],
+}
+
+impl NodeAuth {
+	pub fn new(secret: &NodeSecret) -> Self {
+		Self { digest: NodeSecret::digest(secret.value.as_bytes()) }
+	}
+}
+
+impl Interceptor for NodeAuth {
+	fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+		// comparing digests doesn't leak how much of the secret matched //
+		let presented = request
+			.metadata()
+			.get(NODE_SECRET)
+			.map(|v| NodeSecret::digest(v.as_bytes()));
+
+		if presented != Some(self.digest) {
+			return Err(Status::unauthenticated("Missing or wrong node secret"));
+		}
+		Ok(request)
+	}
+}
+
+
+// ---- PEERS ---- //
+
+/// The other nodes behind the same load balancer. Listings and joins fan out to them;
+/// `WatchListings` still only sees this node's listings.
+#[derive(Default, Clone)]
+pub struct Peers {
+	nodes: Vec<NodeClient>,
+}
+
+impl Peers {
+	/// Connects lazily to each peer's node listener, so peers may start after this node.
+	pub fn new(uris: impl IntoIterator<Item = Uri>, secret: NodeSecret) -> Self {
+		let nodes = uris
+			.into_iter()
+			.map(|uri| NodeServiceClient::with_interceptor(Channel::builder(uri).connect_lazy(), secret.clone()))
+			.collect();
+
+		Self { nodes }
+	}
+
+	/// Every peer's own listings, asked all at once. Unreachable peers are skipped.
+	pub async fn listings(&self, wait: Duration) -> Vec<RustListing> {
+		let responses = join_all(self.nodes.iter().map(|node| {
+			let mut node = node.clone();
+			async move { timeout(wait, node.listings(Request::new(NodeListingsRequest {}))).await }
+		})).await;
+
+		let mut listings = Vec::new();
+		for resp in responses {
+			let resp = match resp {
+				Ok(Ok(resp)) => resp.into_inner(),
+				Ok(Err(status)) => {
+					warn!(%status, "Peer node listings status");
+					continue;
+				},
+				Err(e) => {
+					warn!(error = %e, "Peer node listings timeout");
+					continue;
+				},
+			};
+
+			listings.extend(resp.listings.into_iter().filter_map(|l| l.try_into().ok()));
+		}
+
+		listings
+	}
+
+	/// Asks every peer at once; the one that owns `request.listing_id` asks the host.
+	pub async fn ask_host(&self, request: NodeAskHostRequest, wait: Duration) -> Result<(Host, JoinDecision, SocketAddr), Status> {
+		let mut asks: FuturesUnordered<_> = self.nodes.iter().map(|node| {
+			let mut node = node.clone();
+			let request = Request::new(request.clone());
+
+			// the owner waits up to `wait` for the host's decision //
+			async move {
+				let resp = timeout(wait * 2, node.ask_host(request)).await;
+				(node, resp)
+			}
+		}).collect();
+
+		// a peer that doesn't answer in time is taken not to own the listing //
+		let mut timed_out = false;
+
+		while let Some((node, resp)) = asks.next().await {
+			let resp = match resp {
+				Ok(Ok(resp)) => resp.into_inner(),
+				Ok(Err(status)) if status.code() == Code::NotFound => continue,
+				Ok(Err(status)) => return Err(status),
+				Err(e) => {
+					warn!(error = %e, "Peer node ask host timeout");
+					timed_out = true;
+					continue;
+				},
+			};
+
+			let session_id = resp.host_session_id
+				.try_into()
+				.map_err(|e| Status::internal(format!("Peer node sent a bad session id: {e}")))?;
+
+			let punch_addr = parse_addr(&resp.punch_ip, resp.punch_port)
+				.map_err(|e| Status::internal(format!("Peer node sent a bad punch addr: {e}")))?;
+
+			let decision = resp.decision.ok_or(Status::internal("Peer node sent no join decision"))?;
+
+			return Ok((Host::Remote { node: Box::new(node), session_id }, decision, punch_addr));
+		}
+
+		if timed_out {
+			return Err(Status::unavailable("Listing not found on the peer nodes that answered in time."));
+		}
+		Err(Status::invalid_argument("Listing ID has no associated session."))
+	}
+}
+
+
+// ---- HOST ---- //
+
+/// The host side of a join, connected to this node or to a peer.
+pub enum Host {
+	Local { session: SessionRef, session_id: Uuid },
+	Remote { node: Box<NodeClient>, session_id: Uuid },
+}
+
+impl Host {
+	pub fn is_remote(&self) -> bool { matches!(self, Self::Remote { .. }) }
+
+	/// The host's session, on whichever node it is connected to.
+	pub fn session_id(&self) -> Uuid {
+		match self {
+			Self::Local { session_id, .. } | Self::Remote { session_id, .. } => *session_id,
+		}
+	}
+
+	pub async fn order_punch(&self, addr: SocketAddr, wait: Duration) -> Result<PunchStatus> {
+		let (node, session_id) = match self {
+			Self::Local { session, .. } => return super::order_punch(session.clone(), addr, wait).await,
+			Self::Remote { node, session_id } => (node, session_id),
+		};
+
+		let request = NodePunchRequest {
+			session_id: session_id.as_bytes().to_vec(),
+			ip: addr.ip().to_string(),
+			port: addr.port().into(),
+		};
+
+		// the owner waits up to `wait` to send the order and again for the status //
+		let status = timeout(wait * 2, node.clone().order_punch(Request::new(request)))
+			.await
+			.map_err(|e| anyhow!("Timeout forwarding punch order: {e}"))?
+			.map_err(|e| anyhow!("Forwarded punch order status: {e}"))?
+			.into_inner();
+
+		Ok(status)
+	}
+
+	/// A remote host can't resolve an empty relay ip to this node, so `ip` must be known.
+	pub async fn order_relay(&self, ip: Option<IpAddr>, port: u16, wait: Duration) -> Result<()> {
+		let (node, session_id) = match self {
+			Self::Local { session, .. } => return super::order_relay(session.clone(), ip, port, wait).await,
+			Self::Remote { node, session_id } => (node, session_id),
+		};
+
+		let ip = ip.ok_or(anyhow!("Relay ip is unknown, peer nodes can't reach it"))?;
+		let request = NodeRelayRequest {
+			session_id: session_id.as_bytes().to_vec(),
+			ip: ip.to_string(),
+			port: port.into(),
+		};
+
+		timeout(wait * 2, node.clone().order_relay(Request::new(request)))
+			.await
+			.map_err(|e| anyhow!("Timeout forwarding relay order: {e}"))?
+			.map_err(|e| anyhow!("Forwarded relay order status: {e}"))?;
+
+		Ok(())
+	}
+}
+
+
+// ---- NODE ---- //
+
+/// Serves [`NodeService`] to peers, acting on this node's sessions.
+pub struct Node {
+	store: Arc<dyn RendezvousStore>,
+	timeout: Duration,
+}
+
+impl Node {
+	pub fn new(store: Arc<dyn RendezvousStore>, timeout: Duration) -> Self {
+		Self { store, timeout }
+	}
+
+	async fn session(&self, session_id: Vec<u8>) -> Result<SessionRef, Status> {
+		let session_id: Uuid = session_id
+			.try_into()
+			.map_err(|e| Status::invalid_argument(format!("Invalid session Uuid: {e}")))?;
+
+		self.store
+			.session(&session_id)
+			.await
+			.ok_or(Status::not_found("Session is not on this node"))
+	}
+}
+
+#[tonic::async_trait]
+impl NodeService for Node {
+	async fn listings(
+		&self,
+		_request: Request<NodeListingsRequest>,
+	) -> Result<Response<NodeListingsResponse>, Status> {
+		let listings = self.store
+			.listings()
+			.await
+			.into_iter()
+			.map(Into::into)
+			.collect();
+
+		Ok(Response::new(NodeListingsResponse { listings }))
+	}
+
+	async fn ask_host(
+		&self,
+		request: Request<NodeAskHostRequest>,
+	) -> Result<Response<NodeAskHostResponse>, Status> {
+		let request = request.into_inner();
+
+		let listing_id: Uuid = request.listing_id
+			.try_into()
+			.map_err(|e| Status::invalid_argument(format!("Invalid listing Uuid: {e}")))?;
+		let joiner_id: Uuid = request.joiner_session_id
+			.try_into()
+			.map_err(|e| Status::invalid_argument(format!("Invalid session Uuid: {e}")))?;
+		let joiner_ip: IpAddr = request.joiner_ip
+			.parse()
+			.map_err(|e| Status::invalid_argument(format!("Invalid joiner ip: {e}")))?;
+
+		// not ours, the asking node tries the next peer //
+		if self.store.listing(&listing_id).await.is_none() {
+			return Err(Status::not_found("Listing is not on this node"));
+		}
+
+		let joiner = super::Joiner {
+			session_id: joiner_id,
+			ip: joiner_ip,
+			identity: request.joiner,
+			payload: request.payload,
+		};
+
+		let (host_session_id, _, punch_addr, decision) = super::decide_join(
+			&*self.store,
+			&listing_id,
+			joiner,
+			request.password.as_deref(),
+			self.timeout,
+		).await?;
+
+		Ok(Response::new(NodeAskHostResponse {
+			host_session_id: host_session_id.as_bytes().to_vec(),
+			decision: Some(decision),
+			punch_ip: punch_addr.ip().to_string(),
+			punch_port: punch_addr.port().into(),
+		}))
+	}
+
+	async fn order_punch(
+		&self,
+		request: Request<NodePunchRequest>,
+	) -> Result<Response<PunchStatus>, Status> {
+		let request = request.into_inner();
+		let addr = parse_addr(&request.ip, request.port)
+			.map_err(|e| Status::invalid_argument(format!("Invalid punch addr: {e}")))?;
+
+		let session = self.session(request.session_id).await?;
+		let status = super::order_punch(session, addr, self.timeout)
+			.await
+			.unwrap_or_else(|e| PunchStatus { message: Some(e.to_string()), success: false, ..Default::default() });
+
+		Ok(Response::new(status))
+	}
+
+	async fn order_relay(
+		&self,
+		request: Request<NodeRelayRequest>,
+	) -> Result<Response<NodeRelayResponse>, Status> {
+		let request = request.into_inner();
+		let ip: IpAddr = request.ip
+			.parse()
+			.map_err(|e| Status::invalid_argument(format!("Invalid relay ip: {e}")))?;
+		let port: u16 = request.port
+			.try_into()
+			.map_err(|e| Status::invalid_argument(format!("Invalid relay port: {e}")))?;
+
+		let session = self.session(request.session_id).await?;
+		super::order_relay(session, Some(ip), port, self.timeout)
+			.await
+			.map_err(|e| Status::unavailable(format!("Unable to send relay order: {e}")))?;
+
+		Ok(Response::new(NodeRelayResponse {}))
+	}
+}
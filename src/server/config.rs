@@ -0,0 +1,407 @@
+use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, path::{Path, PathBuf}, time::Duration};
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use serde::Deserialize;
+use tonic::transport::Uri;
+use super::{cluster::NodeSecret, ratelimit::Rate};
+
+/// Everything [`super::run`] needs. Loaded from defaults, then a TOML file, then env vars and CLI flags, see [`ServerConfig::load`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+	/// Grpc (tcp) and reflector (udp) bind address. Loopback by default, but every interface when the port comes from `PORT`.
+	pub host: IpAddr,
+	pub port: u16,
+	/// The STUN responder's port on `host`, 0 to disable.
+	pub stun_port: u16,
+	/// Serves Prometheus metrics over http on this port on `host`, 0 to disable, see [`super::metrics`].
+	pub metrics_port: u16,
+
+	/// How long to wait on a client, e.g. for a punch status or join decision.
+	pub timeout_secs: u64,
+	/// How often empty messages are sent down each session stream, so proxies keep it open.
+	pub keepalive_secs: u64,
+	/// Sessions not heard from for this long are dropped with their listing, 0 to keep them forever.
+	/// Checked every keepalive, pinging the client from halfway, so must be at least two keepalives.
+	pub idle_timeout_secs: u64,
+	/// How long a dropped session and its listing are kept for the client to resume, 0 to drop them at once.
+	/// Sessions expired for going idle are dropped at once.
+	pub resume_grace_secs: u64,
+	/// How long a shutdown waits for in-flight joins to finish, see [`super::run_with_shutdown`].
+	pub drain_secs: u64,
+	/// How long clients are told to wait before reconnecting after a shutdown.
+	pub shutdown_retry_secs: u64,
+
+	/// Unlimited when unset.
+	pub max_sessions: Option<usize>,
+	pub max_listings: Option<usize>,
+
+	/// Sustained requests per second from one ip, 0 for unlimited, see [`super::ratelimit`].
+	pub ip_rate_limit: f64,
+	/// Requests one ip may send at once.
+	pub ip_burst: u32,
+	/// Sustained requests per second from one session, 0 for unlimited.
+	pub session_rate_limit: f64,
+	pub session_burst: u32,
+	/// Reverse proxies whose `X-Forwarded-For` is believed, so clients behind them don't share one ip's limit.
+	pub trusted_proxies: Vec<IpAddr>,
+
+	/// Origins browsers may call the grpc-web endpoint from; `*` allows any, empty allows none.
+	pub cors_origins: Vec<String>,
+	/// `error`, `warn`, `info`, `debug` or `trace`.
+	pub log_level: String,
+	/// `pretty` for people, `json` for log collectors.
+	pub log_format: String,
+
+	/// PEM files; serves plaintext unless both are set.
+	pub tls_cert: Option<PathBuf>,
+	pub tls_key: Option<PathBuf>,
+	/// How often the tls files are checked for changes.
+	pub tls_reload_secs: u64,
+	/// Connections that haven't finished the tls handshake by then are dropped.
+	pub tls_handshake_secs: u64,
+
+	/// Relay datagrams between peers whose punch failed.
+	pub relay: bool,
+	/// The relay's bind address, `host` when unset.
+	pub relay_host: Option<IpAddr>,
+	/// Told to peers as the relay's address, e.g. the public ip in front of a NAT.
+	/// When unset, the relay host unless it is loopback or unspecified, else the grpc server's host.
+	pub relay_public_ip: Option<IpAddr>,
+	/// Persist listings to this file, see [`super::store::FileStore`].
+	pub store_path: Option<PathBuf>,
+	/// Session tokens are signed with a key derived from this, so owners can reclaim listings after a restart.
+	/// Required with `store_path`, and kept out of the store file.
+	pub store_secret: Option<String>,
+	/// Other nodes sharing the listing registry, at their node listeners, see [`super::cluster`].
+	pub peers: Vec<String>,
+	/// Where peers reach this node. Kept off the public port, on loopback unless the cluster spans hosts.
+	pub node_host: IpAddr,
+	pub node_port: u16,
+	/// Shared by every node in the cluster and required on node rpcs. The node listener is off when unset.
+	pub node_secret: Option<String>,
+
+	/// Shared secret for HS256 bearer tokens, see [`super::auth::JwtAuth`]. Sessions are anonymous when unset.
+	pub auth_secret: Option<String>,
+}
+
+impl Default for ServerConfig {
+	fn default() -> Self {
+		Self {
+			host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+			port: 3000,
+			stun_port: 3478,
+			metrics_port: 0,
+			timeout_secs: 10,
+			keepalive_secs: 30,
+			idle_timeout_secs: 150,
+			resume_grace_secs: 30,
+			drain_secs: 10,
+			shutdown_retry_secs: 5,
+			max_sessions: None,
+			max_listings: None,
+			ip_rate_limit: 20.0,
+			ip_burst: 50,
+			session_rate_limit: 5.0,
+			session_burst: 20,
+			trusted_proxies: Vec::new(),
+			cors_origins: Vec::new(),
+			log_level: "info".to_string(),
+			log_format: "pretty".to_string(),
+			tls_cert: None,
+			tls_key: None,
+			tls_reload_secs: 60,
+			tls_handshake_secs: 10,
+			relay: true,
+			relay_host: None,
+			relay_public_ip: None,
+			store_path: None,
+			store_secret: None,
+			peers: Vec::new(),
+			node_host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+			node_port: 3001,
+			node_secret: None,
+			auth_secret: None,
+		}
+	}
+}
+
+impl ServerConfig {
+	/// Layers `cli` and its env vars over the config file it names, if any.
+	pub fn load(cli: Cli) -> Result<Self> {
+		let (mut config, file_host) = match cli.config.as_ref() {
+			Some(path) => {
+				let text = std::fs::read_to_string(path)
+					.map_err(|e| anyhow!("Unable to read config {}: {e}", path.display()))?;
+				let config = Self::from_toml(&text)
+					.map_err(|e| anyhow!("Invalid config {}: {e}", path.display()))?;
+				let file_host = text.parse::<toml::Table>().is_ok_and(|table| table.contains_key("host"));
+				(config, file_host)
+			},
+			None => (Self::default(), false),
+		};
+
+		config.apply(cli, file_host);
+		config.validate()?;
+		Ok(config)
+	}
+
+	pub fn from_toml(text: &str) -> Result<Self> {
+		toml::from_str(text).map_err(anyhow::Error::from)
+	}
+
+	pub fn addr(&self) -> SocketAddr { SocketAddr::new(self.host, self.port) }
+
+	/// `None` when disabled.
+	pub fn stun_addr(&self) -> Option<SocketAddr> {
+		(self.stun_port != 0).then(|| SocketAddr::new(self.host, self.stun_port))
+	}
+
+	pub fn metrics_addr(&self) -> Option<SocketAddr> {
+		(self.metrics_port != 0).then(|| SocketAddr::new(self.host, self.metrics_port))
+	}
+
+	pub fn timeout(&self) -> Duration { Duration::from_secs(self.timeout_secs) }
+
+	pub fn keepalive(&self) -> Duration { Duration::from_secs(self.keepalive_secs) }
+
+	/// `None` when sessions never expire.
+	pub fn idle_timeout(&self) -> Option<Duration> {
+		(self.idle_timeout_secs != 0).then(|| Duration::from_secs(self.idle_timeout_secs))
+	}
+
+	pub fn resume_grace(&self) -> Duration { Duration::from_secs(self.resume_grace_secs) }
+
+	pub fn drain(&self) -> Duration { Duration::from_secs(self.drain_secs) }
+
+	pub fn shutdown_retry(&self) -> Duration { Duration::from_secs(self.shutdown_retry_secs) }
+
+	/// The cert and key paths, when serving tls.
+	pub fn tls(&self) -> Option<(&Path, &Path)> {
+		Some((self.tls_cert.as_deref()?, self.tls_key.as_deref()?))
+	}
+
+	/// `None` when unlimited.
+	pub fn ip_rate(&self) -> Option<Rate> {
+		(self.ip_rate_limit > 0.0).then_some(Rate { per_sec: self.ip_rate_limit, burst: self.ip_burst })
+	}
+
+	pub fn session_rate(&self) -> Option<Rate> {
+		(self.session_rate_limit > 0.0).then_some(Rate { per_sec: self.session_rate_limit, burst: self.session_burst })
+	}
+
+	pub fn tls_reload(&self) -> Duration { Duration::from_secs(self.tls_reload_secs) }
+
+	pub fn tls_handshake(&self) -> Duration { Duration::from_secs(self.tls_handshake_secs) }
+
+	pub fn relay_host(&self) -> IpAddr { self.relay_host.unwrap_or(self.host) }
+
+	/// Only listened on in a cluster, see [`super::PuncherServer::with_cluster`].
+	pub fn node_addr(&self) -> SocketAddr { SocketAddr::new(self.node_host, self.node_port) }
+
+	pub fn peer_uris(&self) -> Result<Vec<Uri>> {
+		self.peers
+			.iter()
+			.map(|p| p.parse().map_err(|e| anyhow!("Invalid peer uri {p}: {e}")))
+			.collect()
+	}
+
+	pub fn validate(&self) -> Result<()> {
+		if self.timeout_secs == 0 {
+			return Err(anyhow!("timeout_secs must be at least 1"));
+		}
+		if self.keepalive_secs == 0 {
+			return Err(anyhow!("keepalive_secs must be at least 1"));
+		}
+		if self.idle_timeout_secs != 0 && self.idle_timeout_secs < 2 * self.keepalive_secs {
+			return Err(anyhow!("idle_timeout_secs must be 0 or at least twice keepalive_secs"));
+		}
+		if !(self.ip_rate_limit >= 0.0 && self.session_rate_limit >= 0.0) {
+			return Err(anyhow!("Rate limits must be 0 or positive"));
+		}
+		if (self.ip_rate_limit > 0.0 && self.ip_burst == 0) || (self.session_rate_limit > 0.0 && self.session_burst == 0) {
+			return Err(anyhow!("Rate limit bursts must be at least 1"));
+		}
+		if self.tls_cert.is_some() != self.tls_key.is_some() {
+			return Err(anyhow!("tls_cert and tls_key must be set together"));
+		}
+		if self.tls_reload_secs == 0 {
+			return Err(anyhow!("tls_reload_secs must be at least 1"));
+		}
+		if self.tls_handshake_secs == 0 {
+			return Err(anyhow!("tls_handshake_secs must be at least 1"));
+		}
+		if self.store_path.is_some() && self.store_secret.as_ref().is_none_or(String::is_empty) {
+			return Err(anyhow!("store_path needs a non-empty store_secret"));
+		}
+		if self.auth_secret.as_ref().is_some_and(String::is_empty) {
+			return Err(anyhow!("auth_secret must not be empty"));
+		}
+		if !["error", "warn", "info", "debug", "trace"].contains(&self.log_level.as_str()) {
+			return Err(anyhow!("Unknown log_level {}", self.log_level));
+		}
+		if !["pretty", "json"].contains(&self.log_format.as_str()) {
+			return Err(anyhow!("Unknown log_format {}", self.log_format));
+		}
+		if let Some(secret) = self.node_secret.as_ref() {
+			NodeSecret::new(secret)?;
+		}
+		if !self.peers.is_empty() && self.node_secret.is_none() {
+			return Err(anyhow!("peers need a node_secret"));
+		}
+		self.peer_uris()?;
+		Ok(())
+	}
+
+	/// `file_host` is whether the config file set `host`, even to the default.
+	fn apply(&mut self, cli: Cli, file_host: bool) {
+		let Cli { config: _, host, port, platform_port, stun_port, metrics_port, timeout_secs, keepalive_secs, idle_timeout_secs, resume_grace_secs, drain_secs, shutdown_retry_secs, max_sessions, max_listings, ip_rate_limit, ip_burst, session_rate_limit, session_burst, trusted_proxies, cors_origins, log_level, log_format, tls_cert, tls_key, tls_reload_secs, tls_handshake_secs, no_relay, relay_host, relay_public_ip, store_path, store_secret, peers, node_host, node_port, node_secret, auth_secret } = cli;
+
+		// platforms like Render route their `PORT` from outside, so it has to be bound on every interface //
+		if let Some(platform_port) = platform_port && port.is_none() {
+			if host.is_none() && !file_host {
+				self.host = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+			}
+			self.port = platform_port;
+		}
+
+		if let Some(host) = host { self.host = host }
+		if let Some(port) = port { self.port = port }
+		if let Some(stun_port) = stun_port { self.stun_port = stun_port }
+		if let Some(metrics_port) = metrics_port { self.metrics_port = metrics_port }
+		if let Some(timeout_secs) = timeout_secs { self.timeout_secs = timeout_secs }
+		if let Some(keepalive_secs) = keepalive_secs { self.keepalive_secs = keepalive_secs }
+		if let Some(idle_timeout_secs) = idle_timeout_secs { self.idle_timeout_secs = idle_timeout_secs }
+		if let Some(resume_grace_secs) = resume_grace_secs { self.resume_grace_secs = resume_grace_secs }
+		if let Some(drain_secs) = drain_secs { self.drain_secs = drain_secs }
+		if let Some(shutdown_retry_secs) = shutdown_retry_secs { self.shutdown_retry_secs = shutdown_retry_secs }
+		if max_sessions.is_some() { self.max_sessions = max_sessions }
+		if max_listings.is_some() { self.max_listings = max_listings }
+		if let Some(ip_rate_limit) = ip_rate_limit { self.ip_rate_limit = ip_rate_limit }
+		if let Some(ip_burst) = ip_burst { self.ip_burst = ip_burst }
+		if let Some(session_rate_limit) = session_rate_limit { self.session_rate_limit = session_rate_limit }
+		if let Some(session_burst) = session_burst { self.session_burst = session_burst }
+		if !trusted_proxies.is_empty() { self.trusted_proxies = trusted_proxies }
+		if !cors_origins.is_empty() { self.cors_origins = cors_origins }
+		if let Some(log_level) = log_level { self.log_level = log_level }
+		if let Some(log_format) = log_format { self.log_format = log_format }
+		if tls_cert.is_some() { self.tls_cert = tls_cert }
+		if tls_key.is_some() { self.tls_key = tls_key }
+		if let Some(tls_reload_secs) = tls_reload_secs { self.tls_reload_secs = tls_reload_secs }
+		if let Some(tls_handshake_secs) = tls_handshake_secs { self.tls_handshake_secs = tls_handshake_secs }
+		if no_relay { self.relay = false }
+		if relay_host.is_some() { self.relay_host = relay_host }
+		if relay_public_ip.is_some() { self.relay_public_ip = relay_public_ip }
+		if store_path.is_some() { self.store_path = store_path }
+		if store_secret.is_some() { self.store_secret = store_secret }
+		if !peers.is_empty() { self.peers = peers }
+		if let Some(node_host) = node_host { self.node_host = node_host }
+		if let Some(node_port) = node_port { self.node_port = node_port }
+		if node_secret.is_some() { self.node_secret = node_secret }
+		if auth_secret.is_some() { self.auth_secret = auth_secret }
+	}
+}
+
+
+// -- CLI -- //
+
+/// Flags for `nat_puncher_server`. Each overrides the config file.
+#[derive(Debug, Default, Parser)]
+#[command(version, about = "NAT hole punching rendezvous server")]
+pub struct Cli {
+	/// TOML config file.
+	#[arg(short, long, env = "NAT_PUNCHER_CONFIG")]
+	pub config: Option<PathBuf>,
+
+	#[arg(long, env = "NAT_PUNCHER_HOST")]
+	pub host: Option<IpAddr>,
+	#[arg(short, long)]
+	pub port: Option<u16>,
+	/// Hosts like Render pass the port to bind through `PORT`. Loses to `--port`, and binds `0.0.0.0` unless a host is set.
+	#[arg(long, env = "PORT", hide = true)]
+	pub platform_port: Option<u16>,
+	/// 0 disables the STUN responder.
+	#[arg(long)]
+	pub stun_port: Option<u16>,
+	/// 0 disables the metrics endpoint.
+	#[arg(long)]
+	pub metrics_port: Option<u16>,
+
+	#[arg(long)]
+	pub timeout_secs: Option<u64>,
+	#[arg(long)]
+	pub keepalive_secs: Option<u64>,
+	/// 0 keeps idle sessions forever.
+	#[arg(long)]
+	pub idle_timeout_secs: Option<u64>,
+	/// 0 drops sessions as soon as their stream does.
+	#[arg(long)]
+	pub resume_grace_secs: Option<u64>,
+	#[arg(long)]
+	pub drain_secs: Option<u64>,
+	#[arg(long)]
+	pub shutdown_retry_secs: Option<u64>,
+
+	#[arg(long)]
+	pub max_sessions: Option<usize>,
+	#[arg(long)]
+	pub max_listings: Option<usize>,
+
+	/// Requests per second, 0 for unlimited.
+	#[arg(long)]
+	pub ip_rate_limit: Option<f64>,
+	#[arg(long)]
+	pub ip_burst: Option<u32>,
+	#[arg(long)]
+	pub session_rate_limit: Option<f64>,
+	#[arg(long)]
+	pub session_burst: Option<u32>,
+	/// Repeatable, or comma separated.
+	#[arg(long = "trusted-proxy", value_delimiter = ',')]
+	pub trusted_proxies: Vec<IpAddr>,
+
+	/// Repeatable, or comma separated.
+	#[arg(long = "cors-origin", value_delimiter = ',')]
+	pub cors_origins: Vec<String>,
+	#[arg(long, env = "NAT_PUNCHER_LOG")]
+	pub log_level: Option<String>,
+	#[arg(long, env = "NAT_PUNCHER_LOG_FORMAT")]
+	pub log_format: Option<String>,
+
+	/// PEM certificate chain; needs `--tls-key`.
+	#[arg(long, env = "NAT_PUNCHER_TLS_CERT")]
+	pub tls_cert: Option<PathBuf>,
+	#[arg(long, env = "NAT_PUNCHER_TLS_KEY")]
+	pub tls_key: Option<PathBuf>,
+	#[arg(long)]
+	pub tls_reload_secs: Option<u64>,
+	#[arg(long)]
+	pub tls_handshake_secs: Option<u64>,
+
+	#[arg(long)]
+	pub no_relay: bool,
+	/// Defaults to `--host`.
+	#[arg(long)]
+	pub relay_host: Option<IpAddr>,
+	#[arg(long)]
+	pub relay_public_ip: Option<IpAddr>,
+	#[arg(long)]
+	pub store_path: Option<PathBuf>,
+	/// Prefer the env var, flags show up in process listings.
+	#[arg(long, env = "NAT_PUNCHER_STORE_SECRET", hide_env_values = true)]
+	pub store_secret: Option<String>,
+	/// Repeatable, or comma separated.
+	#[arg(long = "peer", value_delimiter = ',')]
+	pub peers: Vec<String>,
+	#[arg(long)]
+	pub node_host: Option<IpAddr>,
+	#[arg(long)]
+	pub node_port: Option<u16>,
+	/// Prefer the env var, flags show up in process listings.
+	#[arg(long, env = "NAT_PUNCHER_NODE_SECRET", hide_env_values = true)]
+	pub node_secret: Option<String>,
+
+	/// Prefer the env var, flags show up in process listings.
+	#[arg(long, env = "NAT_PUNCHER_AUTH_SECRET", hide_env_values = true)]
+	pub auth_secret: Option<String>,
+}
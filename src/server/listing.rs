@@ -1,6 +1,17 @@
-use anyhow::{anyhow, Error, Result};
+use std::collections::HashMap;
+use anyhow::{anyhow, bail, Error, Result};
+use argon2::{password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString}, Argon2};
+use tokio::task::spawn_blocking;
 use uuid::Uuid;
-use crate::proto::{Listing as TonicListing, ListingNoId as TonicListingNoId};
+use crate::proto::{Identity, Listing as TonicListing, ListingNoId as TonicListingNoId};
+
+// -- LIMITS -- //
+pub const MAX_TEXT_LEN: usize = 64;
+pub const MAX_ATTRIBUTES: usize = 32;
+pub const MAX_ATTRIBUTE_KEY_LEN: usize = 64;
+pub const MAX_ATTRIBUTE_VALUE_LEN: usize = 1024;
+/// Argon2 takes time in the length too, so long ones are refused before hashing.
+pub const MAX_PASSWORD_LEN: usize = 128;
 
 // ---- RUST ---- //
 
@@ -9,14 +20,52 @@ use crate::proto::{Listing as TonicListing, ListingNoId as TonicListingNoId};
 pub struct RustListing {
 	listing_no_id: RustListingNoId,
 	id: Uuid,
+	has_password: bool,
+	password_hash: Option<String>, // server side only
+	host: Option<Identity>,
+}
+
+/// A listing with its password hashed, ready to create or update a [`RustListing`] with.
+/// Hashing happens here so callers can do it before taking any locks.
+pub struct PreparedListing {
+	listing_no_id: RustListingNoId,
+	password_hash: Option<String>,
+}
+
+impl PreparedListing {
+	/// Hashes and strips the password, if any. Callers [`RustListingNoId::validate`] first,
+	/// so a bad listing is told apart from a failed hash.
+	pub async fn new(listing_no_id: impl Into<RustListingNoId>) -> Result<Self> {
+		let mut listing_no_id = listing_no_id.into();
+
+		let password_hash = match listing_no_id.password.take() {
+			Some(password) => Some(hash_password(password).await?),
+			None => None,
+		};
+
+		Ok(Self { listing_no_id, password_hash })
+	}
 }
 
 impl RustListing {
-	pub fn new(listing_no_id: impl Into<RustListingNoId>) -> Self {
+	pub fn new(prepared: PreparedListing) -> Self {
 		Self {
-			listing_no_id: listing_no_id.into(),
+			listing_no_id: prepared.listing_no_id,
 			id: Uuid::new_v4(),
+			has_password: prepared.password_hash.is_some(),
+			password_hash: prepared.password_hash,
+			host: None,
+		}
+	}
+
+	/// Replaces the contents but keeps the id. No password keeps the current one, unless `clear_password`.
+	pub fn update(&mut self, prepared: PreparedListing, clear_password: bool) {
+		if prepared.password_hash.is_some() || clear_password {
+			self.has_password = prepared.password_hash.is_some();
+			self.password_hash = prepared.password_hash;
 		}
+
+		self.listing_no_id = prepared.listing_no_id;
 	}
 
 	pub fn id(&self) -> &Uuid {&self.id}
@@ -24,6 +73,59 @@ impl RustListing {
 	pub fn inner(&self) -> &RustListingNoId {&self.listing_no_id}
 
 	pub fn into_inner(self) -> RustListingNoId {self.listing_no_id}
+
+	pub fn has_password(&self) -> bool {self.has_password}
+
+	/// The argon2 hash, for persisting server side.
+	pub fn password_hash(&self) -> Option<&str> {self.password_hash.as_deref()}
+
+	/// Inverse of [`RustListing::password_hash`], for restoring a persisted listing.
+	pub fn with_password_hash(mut self, password_hash: Option<String>) -> Self {
+		self.has_password = password_hash.is_some();
+		self.password_hash = password_hash;
+		self
+	}
+
+	/// Who is hosting, when the server verified it.
+	pub fn host(&self) -> Option<&Identity> {self.host.as_ref()}
+
+	/// Set by the server from the owning session, never taken from the client.
+	pub fn with_host(mut self, host: Option<Identity>) -> Self {
+		self.host = host;
+		self
+	}
+
+	/// Always true for listings without a password. Only meaningful server side, where the hash is known.
+	/// Argon2 is slow on purpose, so this runs on the blocking pool.
+	pub async fn verify_password(&self, attempt: Option<&str>) -> bool {
+		let Some(hash) = self.password_hash.clone() else { return true };
+		let Some(attempt) = attempt else { return false };
+		if attempt.len() > MAX_PASSWORD_LEN {
+			return false;
+		}
+
+		let attempt = attempt.to_string();
+		spawn_blocking(move || {
+			PasswordHash::new(&hash)
+				.is_ok_and(|hash| Argon2::default().verify_password(attempt.as_bytes(), &hash).is_ok())
+		})
+		.await
+		.unwrap_or(false)
+	}
+}
+
+/// On the blocking pool, like [`RustListing::verify_password`].
+async fn hash_password(password: String) -> Result<String> {
+	spawn_blocking(move || {
+		let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+			.map_err(|e| anyhow!("Unable to encode salt: {e}"))?;
+		let hash = Argon2::default()
+			.hash_password(password.as_bytes(), &salt)
+			.map_err(|e| anyhow!("Unable to hash password: {e}"))?;
+		Ok(hash.to_string())
+	})
+	.await
+	.map_err(|e| anyhow!("Password hashing task failed: {e}"))?
 }
 
 impl TryFrom<TonicListing> for RustListing {
@@ -36,21 +138,81 @@ impl TryFrom<TonicListing> for RustListing {
 				.ok_or(anyhow!("Empty inner listing."))?
 				.into(),
 			id: listing_packet.id.try_into()?,
+			has_password: listing_packet.has_password,
+			password_hash: None,
+			host: listing_packet.host,
 		})
 	}
 }
 
 
 // RUST ListingNoId //
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Default)]
 pub struct RustListingNoId {
 	pub name: String, 
+	pub password: Option<String>,
+	pub game_mode: String,
+	pub map: String,
+	pub version: String,
+	pub current_players: u32,
+	/// 0 for unlimited.
+	pub max_players: u32,
+	pub region: String,
+	pub attributes: HashMap<String, Vec<u8>>,
+}
+
+impl RustListingNoId {
+	/// Checks the size limits the server enforces.
+	pub fn validate(&self) -> Result<()> {
+		let texts = [
+			("name", &self.name),
+			("game_mode", &self.game_mode),
+			("map", &self.map),
+			("version", &self.version),
+			("region", &self.region),
+		];
+		for (field, text) in texts {
+			if text.len() > MAX_TEXT_LEN {
+				bail!("{field} is longer than {MAX_TEXT_LEN} bytes");
+			}
+		}
+
+		if self.password.as_ref().is_some_and(|p| p.len() > MAX_PASSWORD_LEN) {
+			bail!("password is longer than {MAX_PASSWORD_LEN} bytes");
+		}
+
+		if self.max_players != 0 && self.current_players > self.max_players {
+			bail!("current_players exceeds max_players");
+		}
+
+		if self.attributes.len() > MAX_ATTRIBUTES {
+			bail!("More than {MAX_ATTRIBUTES} attributes");
+		}
+		for (key, value) in self.attributes.iter() {
+			if key.len() > MAX_ATTRIBUTE_KEY_LEN {
+				bail!("Attribute key longer than {MAX_ATTRIBUTE_KEY_LEN} bytes");
+			}
+			if value.len() > MAX_ATTRIBUTE_VALUE_LEN {
+				bail!("Attribute {key} longer than {MAX_ATTRIBUTE_VALUE_LEN} bytes");
+			}
+		}
+
+		Ok(())
+	}
 }
 
 impl From<TonicListingNoId> for RustListingNoId {
 	fn from(listing_no_id_packet: TonicListingNoId) -> Self {
 		Self {
 			name: listing_no_id_packet.name,
+			password: listing_no_id_packet.password,
+			game_mode: listing_no_id_packet.game_mode,
+			map: listing_no_id_packet.map,
+			version: listing_no_id_packet.version,
+			current_players: listing_no_id_packet.current_players,
+			max_players: listing_no_id_packet.max_players,
+			region: listing_no_id_packet.region,
+			attributes: listing_no_id_packet.attributes,
 		}
 	}
 }
@@ -65,6 +227,8 @@ impl From<RustListing> for TonicListing {
 		Self {
 			listing_no_id: Some(listing.listing_no_id.into()),
 			id: listing.id.into(),
+			has_password: listing.has_password,
+			host: listing.host,
 		}
 	}
 }
@@ -75,6 +239,14 @@ impl From<RustListingNoId> for TonicListingNoId {
 	fn from(listing_no_id: RustListingNoId) -> Self {
 		Self {
 			name: listing_no_id.name,
+			password: listing_no_id.password,
+			game_mode: listing_no_id.game_mode,
+			map: listing_no_id.map,
+			version: listing_no_id.version,
+			current_players: listing_no_id.current_players,
+			max_players: listing_no_id.max_players,
+			region: listing_no_id.region,
+			attributes: listing_no_id.attributes,
 		}
 	}
-}
\ No newline at end of file
+}
@@ -0,0 +1,188 @@
+use std::{convert::Infallible, fmt::Write, future::Future, net::SocketAddr, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
+use anyhow::Result;
+use hyper::{body::Incoming, header::{HeaderValue, CONTENT_TYPE}, server::conn::http1, service::service_fn, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tracing::{debug, warn};
+use crate::{proto::PunchStatus, server::{relay::RelayUsage, store::RendezvousStore}};
+
+/// Upper bounds of the punch latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Which end of a join a punch order went to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+	Joiner,
+	Host,
+}
+
+impl Side {
+	const ALL: [Self; 2] = [Self::Joiner, Self::Host];
+
+	fn label(self) -> &'static str {
+		match self {
+			Self::Joiner => "joiner",
+			Self::Host => "host",
+		}
+	}
+}
+
+/// What the server counts for `/metrics`, see [`run`]. Sessions and listings are read from the store when scraped.
+#[derive(Debug, Default)]
+pub struct Metrics {
+	join_attempts: AtomicU64,
+	/// Successes then failures, by [`Side`].
+	punches: [[AtomicU64; 2]; 2],
+	punch_latency: [Histogram; 2],
+	stream_errors: AtomicU64,
+}
+
+impl Metrics {
+	pub fn join_attempt(&self) { self.join_attempts.fetch_add(1, Ordering::Relaxed); }
+
+	pub fn stream_error(&self) { self.stream_errors.fetch_add(1, Ordering::Relaxed); }
+
+	/// Runs the punch order to `side`, counting whether it worked and how long it took.
+	pub async fn punch(&self, side: Side, order: impl Future<Output = Result<PunchStatus>>) -> Result<PunchStatus> {
+		let start = Instant::now();
+		let status = order.await;
+
+		let success = status.as_ref().is_ok_and(|s| s.success);
+		self.punches[side as usize][usize::from(!success)].fetch_add(1, Ordering::Relaxed);
+		self.punch_latency[side as usize].observe(start.elapsed());
+
+		status
+	}
+
+	/// The Prometheus text format. Relay metrics are left out without a relay.
+	pub async fn render(&self, store: &dyn RendezvousStore, relay: Option<&RelayUsage>) -> String {
+		let mut out = String::new();
+
+		header(&mut out, "nat_puncher_sessions", "gauge", "Sessions connected to this node.");
+		let _ = writeln!(out, "nat_puncher_sessions {}", store.session_count().await);
+
+		header(&mut out, "nat_puncher_listings", "gauge", "Listings registered on this node.");
+		let _ = writeln!(out, "nat_puncher_listings {}", store.listing_count().await);
+
+		header(&mut out, "nat_puncher_join_attempts_total", "counter", "Join requests received.");
+		let _ = writeln!(out, "nat_puncher_join_attempts_total {}", load(&self.join_attempts));
+
+		header(&mut out, "nat_puncher_punches_total", "counter", "Punch orders by side and result.");
+		for side in Side::ALL {
+			let [success, failure] = &self.punches[side as usize];
+			let _ = writeln!(out, "nat_puncher_punches_total{{side=\"{}\",result=\"success\"}} {}", side.label(), load(success));
+			let _ = writeln!(out, "nat_puncher_punches_total{{side=\"{}\",result=\"failure\"}} {}", side.label(), load(failure));
+		}
+
+		header(&mut out, "nat_puncher_punch_duration_seconds", "histogram", "Time from sending a punch order to its status.");
+		for side in Side::ALL {
+			self.punch_latency[side as usize].render(&mut out, "nat_puncher_punch_duration_seconds", side.label());
+		}
+
+		header(&mut out, "nat_puncher_stream_errors_total", "counter", "Errors reading session streams.");
+		let _ = writeln!(out, "nat_puncher_stream_errors_total {}", load(&self.stream_errors));
+
+		if let Some(relay) = relay {
+			header(&mut out, "nat_puncher_relay_allocations_total", "counter", "Relay port pairs allocated.");
+			let _ = writeln!(out, "nat_puncher_relay_allocations_total {}", load(&relay.allocations));
+
+			header(&mut out, "nat_puncher_relay_active_allocations", "gauge", "Relay port pairs still forwarding.");
+			let _ = writeln!(out, "nat_puncher_relay_active_allocations {}", load(&relay.active));
+
+			header(&mut out, "nat_puncher_relay_bytes_total", "counter", "Bytes forwarded by the relay.");
+			let _ = writeln!(out, "nat_puncher_relay_bytes_total {}", load(&relay.bytes));
+		}
+
+		out
+	}
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+	let _ = writeln!(out, "# HELP {name} {help}");
+	let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn load(counter: &AtomicU64) -> u64 { counter.load(Ordering::Relaxed) }
+
+
+// -- HISTOGRAM -- //
+
+#[derive(Debug, Default)]
+struct Histogram {
+	/// Per bucket, made cumulative when rendered. The last one is `+Inf`.
+	buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+	sum_micros: AtomicU64,
+}
+
+impl Histogram {
+	fn observe(&self, elapsed: Duration) {
+		let secs = elapsed.as_secs_f64();
+		let bucket = LATENCY_BUCKETS.iter().position(|le| secs <= *le).unwrap_or(LATENCY_BUCKETS.len());
+		self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+		self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+	}
+
+	fn render(&self, out: &mut String, name: &str, side: &str) {
+		let mut count = 0;
+		for (i, bucket) in self.buckets.iter().enumerate() {
+			count += load(bucket);
+			let le = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), f64::to_string);
+			let _ = writeln!(out, "{name}_bucket{{side=\"{side}\",le=\"{le}\"}} {count}");
+		}
+
+		let sum = load(&self.sum_micros) as f64 / 1_000_000.0;
+		let _ = writeln!(out, "{name}_sum{{side=\"{side}\"}} {sum}");
+		let _ = writeln!(out, "{name}_count{{side=\"{side}\"}} {count}");
+	}
+}
+
+
+// -- ENDPOINT -- //
+
+/// Everything a scrape reads.
+struct Scrape {
+	metrics: Arc<Metrics>,
+	store: Arc<dyn RendezvousStore>,
+	relay: Option<Arc<RelayUsage>>,
+}
+
+/// Serves `GET /metrics` over plain http on `addr`, for Prometheus to scrape.
+pub async fn run(addr: SocketAddr, metrics: Arc<Metrics>, store: Arc<dyn RendezvousStore>, relay: Option<Arc<RelayUsage>>) -> Result<()> {
+	let listener = TcpListener::bind(addr).await?;
+	let scrape = Arc::new(Scrape { metrics, store, relay });
+
+	loop {
+		let stream = match listener.accept().await {
+			Ok((stream, _)) => stream,
+			Err(e) => {
+				warn!(error = %e, "Metrics accept error; continuing");
+				continue;
+			},
+		};
+
+		let scrape = scrape.clone();
+		tokio::spawn(async move {
+			let service = service_fn(|request| {
+				let scrape = scrape.clone();
+				async move { Ok::<_, Infallible>(respond(request, &scrape).await) }
+			});
+
+			if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+				debug!(error = %e, "Metrics connection error");
+			}
+		});
+	}
+}
+
+async fn respond(request: Request<Incoming>, scrape: &Scrape) -> Response<String> {
+	if request.method() != Method::GET || request.uri().path() != "/metrics" {
+		let mut response = Response::new(String::new());
+		*response.status_mut() = StatusCode::NOT_FOUND;
+		return response;
+	}
+
+	let body = scrape.metrics.render(&*scrape.store, scrape.relay.as_deref()).await;
+	let mut response = Response::new(body);
+	response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"));
+	response
+}
@@ -1,64 +1,379 @@
-use std::{collections::HashMap, net::SocketAddr, pin, sync::Arc};
+use std::{future::{pending, Future}, net::{IpAddr, SocketAddr}, pin, sync::Arc, time::Duration};
 use anyhow::{anyhow, Result};
-use tokio::{join, sync::{mpsc::{self, Sender}, RwLock}, time::timeout};
+use tokio::{join, try_join, sync::{broadcast, mpsc}, time::{sleep, timeout}};
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Request, Response, Status, Streaming, transport::Server};
+use tonic::{codegen::http::{header::HeaderName, Extensions, HeaderValue}, Request, Response, Status, Streaming, transport::{Server, Uri}};
 use tonic_web::GrpcWebLayer;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 use uuid::Uuid;
-use futures::Stream;
-use crate::{proto::{client_stream_message::ClientStreamEnum, puncher_service_server::{PuncherService, PuncherServiceServer}, server_stream_message::ServerStreamEnum, AddListingRequest, AddListingResponse, ClientStreamMessage, GetListingsRequest, GetListingsResponse, JoinRequest, JoinResponse, Punch, PunchStatus, RemoveListingRequest, RemoveListingResponse, ServerStreamMessage}, TIMEOUT};
+use futures::{future::BoxFuture, Stream};
+use tracing::{debug, error, field, info, info_span, instrument, warn, Instrument, Span};
+use crate::{proto::{client_stream_message::ClientStreamEnum, node_service_server::NodeServiceServer, puncher_service_server::{PuncherService, PuncherServiceServer}, NodeAskHostRequest, server_stream_message::ServerStreamEnum, AddListingRequest, AddListingResponse, ClientStreamMessage, GetListingsRequest, GetListingsResponse, ListingEvent, JoinRequest, JoinResponse, Fallback, IncomingJoin, JoinDecision, Ping, Punch, PunchStatus, Relay as RelayOrder, RemoveListingRequest, Shutdown as ShutdownNotice, RemoveListingResponse, ServerStreamMessage, UpdateListingRequest, UpdateListingResponse, WatchListingsRequest, Welcome, Capability, Identity}, PROTOCOL_VERSION};
 
 pub mod session;
-use session::{Session, SessionRef};
+use session::{Dispatcher, Liveness, Session, SessionRef};
 pub mod listing;
-use listing::RustListing;
+pub mod query;
+use listing::{PreparedListing, RustListing, RustListingNoId};
+pub mod store;
+use store::{FileStore, MemoryStore, RendezvousStore};
+pub mod reflector;
+pub mod stun;
+pub mod relay;
+use relay::{Relay, RelayLimits};
+pub mod watch;
+pub mod cluster;
+use cluster::{Host, Node, NodeAuth, NodeSecret, Peers};
+pub mod config;
+use config::ServerConfig;
+pub mod tls;
+use tls::ReloadingCert;
+pub mod ratelimit;
+use ratelimit::{RateLimitLayer, RateLimiter};
+pub mod auth;
+use auth::{AuthInterceptor, AuthProvider, AuthedSession, JwtAuth, SessionKey};
+pub mod shutdown;
+use shutdown::Shutdown;
+pub mod metrics;
+use metrics::{Metrics, Side};
 
-pub async fn run(addr: SocketAddr) -> anyhow::Result<()> {
-	let server = PuncherServer::default();
+/// Serves the grpc service on `config.addr()` (tcp) and the udp reflector on the same address (udp).
+/// The relay, listing store, cluster and metrics endpoint are set up as configured.
+pub async fn run(config: ServerConfig) -> anyhow::Result<()> {
+	run_with_shutdown(config, pending()).await
+}
+
+/// Like [`run`], until `signal` resolves. Then new sessions and joins are refused, every session is sent a `Shutdown`,
+/// joins already running get `config.drain()` to finish, and the streams are closed before returning.
+pub async fn run_with_shutdown(config: ServerConfig, signal: impl Future<Output = ()> + Send) -> anyhow::Result<()> {
+	let mut server = PuncherServer::default();
+
+	if let Some(secret) = config.node_secret.as_ref() {
+		server = server.with_cluster(NodeSecret::new(secret)?, config.peer_uris()?);
+	}
+
+	if config.relay {
+		let mut relay = Relay::new(config.relay_host(), RelayLimits::default());
+		if let Some(public_ip) = config.relay_public_ip {
+			relay = relay.with_public_ip(public_ip);
+		}
+		if config.relay_host().is_loopback() && relay.public_ip().is_none() {
+			warn!("Relay is bound to loopback, so peers on other machines can't reach it; set relay_host");
+		}
+		server = server.with_relay(relay);
+	}
+
+	if let Some(path) = config.store_path.as_ref() {
+		let secret = config.store_secret.as_ref().ok_or(anyhow!("store_path needs a store_secret"))?;
+		server = server.with_store(FileStore::open(path, SessionKey::derive(secret.as_bytes())).await?);
+	}
+
+	if let Some(secret) = config.auth_secret.as_ref() {
+		server = server.with_auth(JwtAuth::new(secret.as_bytes()));
+	}
+
+	serve_with_shutdown(config, server, signal).await
+}
+
+/// Like [`run`], with a preconfigured server, e.g. one using a custom [`RendezvousStore`].
+pub async fn serve(config: ServerConfig, server: PuncherServer) -> anyhow::Result<()> {
+	serve_with_shutdown(config, server, pending()).await
+}
 
-	let svc = PuncherServiceServer::new(server);
-	Server::builder()
+/// Like [`serve`], shutting down as [`run_with_shutdown`] does.
+pub async fn serve_with_shutdown(config: ServerConfig, mut server: PuncherServer, signal: impl Future<Output = ()> + Send) -> anyhow::Result<()> {
+	let addr = config.addr();
+	let store = server.store.clone();
+	let shutdown = server.shutdown.clone();
+	let drain = config.drain();
+	let linger = config.timeout();
+	let metrics_addr = config.metrics_addr();
+	let counters = server.metrics.clone();
+	let relay_usage = server.relay.as_ref().map(Relay::usage);
+	let session_key = server.session_key.clone();
+
+	let node_addr = config.node_addr();
+	let node = server.node_secret.as_ref().map(|secret| {
+		NodeServiceServer::with_interceptor(Node::new(store.clone(), config.timeout()), NodeAuth::new(secret))
+	});
+	let cors = cors_layer(&config.cors_origins)?;
+	let rate_limit = RateLimitLayer::new(RateLimiter::new(config.ip_rate(), config.session_rate()), config.trusted_proxies.clone());
+	let keepalive = config.keepalive();
+	let tls_handshake = config.tls_handshake();
+	let tls = match config.tls() {
+		Some((cert, key)) => Some(ReloadingCert::new(cert, key, config.tls_reload())?),
+		None => None,
+	};
+
+	server.config = config;
+	let unclaimed = server.expire_unclaimed();
+	let auth = AuthInterceptor { sessions: server.session_key.clone(), provider: server.auth.clone() };
+	let svc = PuncherServiceServer::with_interceptor(server, auth);
+	let router = Server::builder()
 		.accept_http1(true)
+		.http2_keepalive_interval(Some(keepalive))
+		.layer(cors)
 		.layer(GrpcWebLayer::new())
-		.add_service(svc)
-		.serve(addr)
-		.await?;
+		// inside grpc-web, so browsers can read the status too //
+		.layer(rate_limit)
+		.add_service(svc);
+
+	// tonic stops accepting once this resolves, and returns when the closed streams are done //
+	let stopped = async {
+		signal.await;
+		info!("Shutting down, draining in-flight joins");
+		shutdown.drain(drain).await;
+	};
+
+	let grpc = async {
+		let serving = async {
+			match tls {
+				Some(cert) => router.serve_with_incoming_shutdown(tls::incoming(addr, cert, tls_handshake).await?, stopped).await?,
+				None => router.serve_with_shutdown(addr, stopped).await?,
+			}
+			Ok(())
+		};
+
+		// a client that never hangs up doesn't keep the server around //
+		tokio::select! {
+			result = serving => result,
+			_ = async { shutdown.closed().await; sleep(linger).await } => {
+				warn!(linger_secs = linger.as_secs(), "Connections still open after closing, exiting anyway");
+				Ok(())
+			},
+		}
+	};
+
+	let reflector = async {
+		tokio::select! {
+			result = reflector::run(addr, store.clone(), session_key) => result,
+			_ = shutdown.closed() => Ok(()),
+		}
+	};
+
+	// peers get their own listener, off the public port and its rate limits //
+	let cluster = async {
+		let Some(node) = node else { return Ok(()) };
+		Server::builder()
+			.add_service(node)
+			.serve_with_shutdown(node_addr, shutdown.closed())
+			.await?;
+		Ok(())
+	};
+
+	let exporter = async {
+		let Some(metrics_addr) = metrics_addr else { return Ok(()) };
+		tokio::select! {
+			result = metrics::run(metrics_addr, counters, store.clone(), relay_usage) => result,
+			_ = shutdown.closed() => Ok(()),
+		}
+	};
+
+	let unclaimed = async {
+		tokio::select! {
+			_ = unclaimed => {},
+			_ = shutdown.closed() => {},
+		}
+		Ok(())
+	};
+
+	try_join!(grpc, reflector, cluster, exporter, unclaimed)?;
 	
 	Ok(())
 }
 
-#[derive(Default)]
+/// Lets browsers on `origins` call the grpc-web endpoint.
+fn cors_layer(origins: &[String]) -> Result<CorsLayer> {
+	let allow_origin = if origins.iter().any(|o| o == "*") {
+		AllowOrigin::any()
+	} else {
+		let origins = origins
+			.iter()
+			.map(|o| HeaderValue::from_str(o).map_err(|e| anyhow!("Invalid cors origin {o}: {e}")))
+			.collect::<Result<Vec<_>>>()?;
+		AllowOrigin::list(origins)
+	};
+
+	let expose = ["grpc-status", "grpc-message", "grpc-status-details-bin"].map(HeaderName::from_static);
+
+	Ok(CorsLayer::new()
+		.allow_origin(allow_origin)
+		.allow_headers(Any)
+		.expose_headers(expose))
+}
+
 pub struct PuncherServer {
-	sessions: Arc<RwLock<HashMap<Uuid, SessionRef>>>,
-	id_map: Arc<RwLock<HashMap<Uuid, Uuid>>>,
+	config: ServerConfig,
+	store: Arc<dyn RendezvousStore>,
+	relay: Option<Relay>,
+	peers: Peers,
+	node_secret: Option<NodeSecret>,
+	listing_events: broadcast::Sender<ListingEvent>,
+	session_key: SessionKey,
+	auth: Option<Arc<dyn AuthProvider>>,
+	shutdown: Shutdown,
+	metrics: Arc<Metrics>,
+}
+
+impl Default for PuncherServer {
+	fn default() -> Self {
+		Self {
+			config: ServerConfig::default(),
+			store: Arc::new(MemoryStore::default()),
+			relay: None,
+			peers: Peers::default(),
+			node_secret: None,
+			listing_events: broadcast::channel(watch::EVENT_BUFFER).0,
+			session_key: SessionKey::default(),
+			auth: None,
+			shutdown: Shutdown::default(),
+			metrics: Arc::default(),
+		}
+	}
 }
 
 impl PuncherServer {
+	/// Replaces the default in-memory registry.
+	pub fn with_store(mut self, store: impl RendezvousStore) -> Self {
+		if let Some(key) = store.session_key() {
+			self.session_key = key;
+		}
+		self.store = Arc::new(store);
+		self
+	}
+
+	/// Joins a cluster sharing its listing registry, see [`cluster`]. `peers` are the other nodes' node listeners,
+	/// and this node serves its own on `config.node_addr()`. Every node must use the same `secret`.
+	pub fn with_cluster(mut self, secret: NodeSecret, peers: impl IntoIterator<Item = Uri>) -> Self {
+		self.peers = Peers::new(peers, secret.clone());
+		self.node_secret = Some(secret);
+		self
+	}
+
+	/// Requires every session to open with a bearer token `auth` accepts, see [`AuthProvider`].
+	/// Sessions are anonymous otherwise.
+	pub fn with_auth(mut self, auth: impl AuthProvider) -> Self {
+		self.auth = Some(Arc::new(auth));
+		self
+	}
+
+	/// Enables relaying between peers whose punch failed.
+	pub fn with_relay(mut self, relay: Relay) -> Self {
+		self.relay = Some(relay);
+		self
+	}
+
+	/// Advertised to every client in `Welcome`.
+	fn capabilities(&self) -> Vec<Capability> {
+		let mut capabilities = vec![Capability::UdpReflector, Capability::JoinApproval];
+		if self.relay.is_some() {
+			capabilities.push(Capability::Relay);
+		}
+		capabilities
+	}
+
+	fn timeout(&self) -> Duration { self.config.timeout() }
+
+	/// Advertised in `Welcome`. Asking the host, then the punch orders, then the relay orders,
+	/// each sent and answered within `timeout` (the sides in parallel, a peer node in its own `timeout * 2`).
+	fn join_timeout(&self) -> Duration { self.timeout() * 6 }
+
 	async fn get(&self, session_id: &Uuid) -> Option<SessionRef> {
-		let sessions = self.sessions.read().await;
-		sessions.get(session_id).map(|s| s.clone())
+		self.store.session(session_id).await
 	}
 
-	fn cleanup_fut(&self, session_id: &Uuid)  -> impl Future<Output = ()> + Send + 'static {
-		let sessions = self.sessions.clone();
-		let id_map = self.id_map.clone();
-		let session_id = session_id.clone();
+	/// The live session a request's token was issued to, see [`AuthInterceptor`]. `claimed` is the session id in the message.
+	async fn authed(&self, extensions: &Extensions, claimed: &[u8]) -> Result<(Uuid, SessionRef), Status> {
+		let AuthedSession(session_id) = *extensions
+			.get()
+			.ok_or(Status::unauthenticated("Missing session token"))?;
+
+		if claimed != session_id.as_bytes() {
+			return Err(Status::permission_denied("Session token was issued to another session"));
+		}
+
+		let session = self
+			.get(&session_id)
+			.await
+			.ok_or(Status::unauthenticated("Session is not connected"))?;
+
+		Ok((session_id, session))
+	}
+
+	/// Tells watchers; having none is fine.
+	fn publish(&self, event: ListingEvent) {
+		let _ = self.listing_events.send(event);
+	}
+
+	/// Removes the listings loaded from a persistent store whose owners don't reclaim them within the resume grace period.
+	fn expire_unclaimed(&self) -> impl Future<Output = ()> + Send + 'static {
+		let store = self.store.clone();
+		let listing_events = self.listing_events.clone();
+		let grace = self.config.resume_grace();
 
 		async move {
-			let mut sessions = sessions.write().await;
+			let loaded: Vec<Uuid> = store.listings().await.iter().map(|l| *l.id()).collect();
+			if loaded.is_empty() {
+				return;
+			}
+			sleep(grace).await;
+
+			for listing_id in loaded {
+				let Some((owner, _)) = store.listing(&listing_id).await else { continue };
+				if store.session(&owner).await.is_some() {
+					continue;
+				}
 
-			let Some(session) = sessions.remove(&session_id) else {
-				eprintln!("When trying to remove session it didnt exist.");
+				match store.remove_listing_of(&owner).await {
+					Ok(Some(listing)) => {
+						info!(%listing_id, "Removed listing nobody reclaimed");
+						let _ = listing_events.send(watch::removed(listing.id()));
+					},
+					Ok(None) => {},
+					Err(e) => error!(error = %e, "Unable to remove unclaimed listing"),
+				}
+			}
+		}
+	}
+
+	/// Ends the session once its `attachment` stream does. Called with whether the stream dropped,
+	/// in which case the client gets the grace period to resume on a new one first.
+	fn cleanup(&self, session_id: &Uuid, attachment: u64) -> impl FnOnce(bool) -> BoxFuture<'static, ()> + Send + 'static {
+		let store = self.store.clone();
+		let relay = self.relay.clone();
+		let listing_events = self.listing_events.clone();
+		let grace = self.config.resume_grace();
+		let session_id = *session_id;
+
+		move |dropped| Box::pin(async move {
+			if dropped {
+				sleep(grace).await;
+			}
+
+			let Some(session) = store.session(&session_id).await else {
+				warn!("When trying to remove session it didnt exist.");
 				return;
 			};
-			
-			let session = session.lock().await;
 
-			if let Some(listing) = session.listing.as_ref() {
-				let mut id_map = id_map.write().await;
-				id_map.remove(listing.id());
+			// waits out any call still using the session, and any resume //
+			let mut session = session.lock().await;
+			if session.attachment() != attachment {
+				return;
 			}
-		}
+			session.close();
+			store.remove_session(&session_id).await;
+			info!(dropped, "Session ended");
+
+			if let Some(relay) = relay.as_ref() {
+				relay.release(&session_id);
+			}
+
+			match store.remove_listing_of(&session_id).await {
+				Ok(Some(listing)) => { let _ = listing_events.send(watch::removed(listing.id())); },
+				Ok(None) => {},
+				Err(e) => error!(error = %e, "Unable to remove listing of ended session"),
+			}
+		})
 	}
 }
 
@@ -66,157 +381,303 @@ impl PuncherServer {
 #[tonic::async_trait]
 impl PuncherService for PuncherServer {
 	type StreamSessionStream = pin::Pin<Box<dyn Stream<Item = Result<ServerStreamMessage, Status>> + Send + Sync + 'static>>;
+	type WatchListingsStream = pin::Pin<Box<dyn Stream<Item = Result<ListingEvent, Status>> + Send + Sync + 'static>>;
 
+	#[instrument(skip_all, fields(session_id = %raw_id(&request.get_ref().session_id)))]
 	async fn add_listing( // ADD LISTING //
         &self,
         request: Request<AddListingRequest>,
     ) -> Result<Response<AddListingResponse>, Status> {
-		println!("Add listing req: {}", Uuid::from_slice(&request.get_ref().session_id).unwrap_or(Uuid::max()));
+		debug!("Add listing req");
 
+		// validate session //
+		let (session_id, session) = self.authed(request.extensions(), &request.get_ref().session_id).await?;
 		let request = request.into_inner();
 		
-		// validate session //
-		let session_id = request
-			.session_id
-			.try_into()
-			.map_err(|e| Status::invalid_argument(format!("Invalid Uuid: {e}")))?;
 
-		let session = self
-			.get(&session_id)
+		// generate listing, hashing before the session is locked //
+		let listing_no_id: RustListingNoId = request
+			.listing
+			.ok_or(Status::invalid_argument("No supplied listing."))?
+			.into();
+		listing_no_id
+			.validate()
+			.map_err(|e| Status::invalid_argument(format!("Invalid listing: {e}")))?;
+
+		let prepared = PreparedListing::new(listing_no_id)
 			.await
-			.ok_or(Status::invalid_argument(format!("Invalid session_id")))?;
-		
+			.map_err(|e| Status::internal(format!("Unable to create listing: {e}")))?;
+
 
 		// validate assignment //
-		{
-			let session = session.lock().await;
-			if session.listing.is_some() {
-				return Err(Status::already_exists("This session already has an associated listing."))
-			}
+		let session = session.lock().await;
+		if self.store.listing_of(&session_id).await.is_some() {
+			return Err(Status::already_exists("This session already has an associated listing."))
 		}
 
-
-		// generate listing //
-		let listing_no_id_packet = request
-			.listing
-			.ok_or(Status::invalid_argument("No supplied listing."))?;
-		let listing = RustListing::new(listing_no_id_packet);
+		let listing = RustListing::new(prepared).with_host(session.identity().cloned());
 		
 
 		// assign listing //
-		let mut id_map = self.id_map.write().await;
-		id_map.insert(*listing.id(), session_id);
-
 		let listing_id = listing.id().as_bytes().to_vec();
 
-		let mut session = session.lock().await;
-		session.listing = Some(listing);
+		let added = self.store
+			.put_listing(session_id, listing.clone(), self.config.max_listings)
+			.await
+			.map_err(|e| Status::internal(format!("Unable to store listing: {e}")))?;
+		if !added {
+			return Err(Status::resource_exhausted("Too many listings on this server."));
+		}
+		self.publish(watch::added(&listing));
+		info!(listing_id = %listing.id(), "Listing added");
 
 
 		Ok(Response::new(AddListingResponse { listing_id }))
     }
 
+	#[instrument(skip_all, fields(session_id = %raw_id(&request.get_ref().session_id)))]
     async fn remove_listing( // REMOVE LISTING //
         &self,
         request: Request<RemoveListingRequest>,
     ) -> Result<Response<RemoveListingResponse>, Status> {
-		println!("Remove listing req: {}", Uuid::from_slice(&request.get_ref().session_id).unwrap_or(Uuid::max()));
+		debug!("Remove listing req");
 		
 		// validate session //
-		let session_id = request.into_inner().session_id.try_into()
-			.map_err(|e| Status::invalid_argument(format!("Invalid Uuid: {e}")))?;
-
-		let session = self
-			.get(&session_id)
-			.await
-			.ok_or(Status::invalid_argument(format!("Invalid session_id")))?;
+		let (session_id, session) = self.authed(request.extensions(), &request.get_ref().session_id).await?;
 
 		
 		// assignment //
-		let mut session = session.lock().await;
+		let _session = session.lock().await;
+
+		let removed = self.store
+			.remove_listing_of(&session_id)
+			.await
+			.map_err(|e| Status::internal(format!("Unable to remove listing: {e}")))?;
 
-		if let Some(listing) = session.listing.as_ref() {
-			let mut id_map = self.id_map.write().await;
-			id_map.remove(listing.id());
-			
-			session.listing = None;
+		if let Some(listing) = removed {
+			self.publish(watch::removed(listing.id()));
+			info!(listing_id = %listing.id(), "Listing removed");
 		}
 
 		Ok(Response::new(RemoveListingResponse {}))
     }
 
+	#[instrument(skip_all, fields(session_id = %raw_id(&request.get_ref().session_id)))]
+    async fn update_listing( // UPDATE LISTING //
+        &self,
+        request: Request<UpdateListingRequest>,
+    ) -> Result<Response<UpdateListingResponse>, Status> {
+		debug!("Update listing req");
+
+		// validate session //
+		let (session_id, session) = self.authed(request.extensions(), &request.get_ref().session_id).await?;
+		let request = request.into_inner();
+
+		// validate listing //
+		let listing_no_id: RustListingNoId = request
+			.listing
+			.ok_or(Status::invalid_argument("No supplied listing."))?
+			.into();
+		listing_no_id
+			.validate()
+			.map_err(|e| Status::invalid_argument(format!("Invalid listing: {e}")))?;
+
+		if request.clear_password && listing_no_id.password.is_some() {
+			return Err(Status::invalid_argument("Cannot both clear and set the password."));
+		}
+
+		let prepared = PreparedListing::new(listing_no_id)
+			.await
+			.map_err(|e| Status::internal(format!("Unable to update listing: {e}")))?;
+
+		// update in place //
+		let _session = session.lock().await;
+		let mut listing = self.store
+			.listing_of(&session_id)
+			.await
+			.ok_or(Status::not_found("This session has no listing to update."))?;
+
+		listing.update(prepared, request.clear_password);
+
+		self.store
+			.put_listing(session_id, listing.clone(), None)
+			.await
+			.map_err(|e| Status::internal(format!("Unable to store listing: {e}")))?;
+		self.publish(watch::updated(&listing));
+		debug!(listing_id = %listing.id(), "Listing updated");
+
+		Ok(Response::new(UpdateListingResponse {}))
+    }
+
+	#[instrument(skip_all)]
     async fn get_listings( // GET LISTINGS //
         &self,
-        _: Request<GetListingsRequest>,
+        request: Request<GetListingsRequest>,
     ) -> Result<Response<GetListingsResponse>, Status> {
-		println!("Get listing req");
+		debug!("Get listing req");
 
-        let sessions = self.sessions.read().await;
-		let mut listings = Vec::new();
-		for (_, session) in sessions.iter() {
-			let session = session.lock().await;
-			if let Some(listing) = session.listing.as_ref() {
-				listings.push(listing.clone().into());
-			}
-		}
+		let request = request.into_inner();
+
+		let mut listings = self.store.listings().await;
+		listings.extend(self.peers.listings(self.timeout()).await);
+
+		let (listings, next_cursor) = query::page(listings, &request)
+			.map_err(|e| Status::invalid_argument(format!("Invalid listings query: {e}")))?;
 
-		Ok(Response::new(GetListingsResponse { listings }))
+		let listings = listings.into_iter().map(Into::into).collect();
+
+		Ok(Response::new(GetListingsResponse { listings, next_cursor }))
     }
 
+	#[instrument(skip_all)]
+	async fn watch_listings( // WATCH LISTINGS //
+		&self,
+		_request: Request<WatchListingsRequest>,
+	) -> Result<Response<Self::WatchListingsStream>, Status> {
+		debug!("Watch listings req");
+
+		// subscribe before the snapshot so no change slips between them //
+		let events = self.listing_events.subscribe();
+		let (tx, rx) = mpsc::channel(32);
+
+		let store = self.store.clone();
+		let shutdown = self.shutdown.clone();
+		tokio::spawn(async move {
+			tokio::select! {
+				_ = watch::forward(store, events, tx.clone()) => {},
+				_ = shutdown.closed() => { let _ = tx.send(Err(Status::unavailable("Server is shutting down."))).await; },
+			}
+		}.in_current_span());
+
+		let out_stream = Box::pin(ReceiverStream::new(rx)) as Self::WatchListingsStream;
+		Ok(Response::new(out_stream))
+	}
+
+	#[instrument(skip_all, fields(session_id = field::Empty, addr = request.remote_addr().map(field::display)))]
 	async fn stream_session( // STREAM //
 		&self,
 		request: Request<Streaming<ClientStreamMessage>>,
 	) -> Result<Response<Self::StreamSessionStream>, Status> {
-		let session_id = Uuid::new_v4();
+		// a session token resumes that session, if it's still within its grace period //
+		let (session_id, resuming) = match request.extensions().get::<AuthedSession>() {
+			Some(AuthedSession(session_id)) => match self.get(session_id).await {
+				Some(session) => (*session_id, Some(session)),
+				// the owner of a listing persisted before a restart, reclaiming it //
+				None if self.store.listing_of(session_id).await.is_some() => (*session_id, None),
+				None => return Err(Status::not_found("Session has ended, start a new one.")),
+			},
+			None => (Uuid::new_v4(), None),
+		};
+		// a reclaimed listing gets a fresh session under its old id, with none of the old state //
+		let resumed = resuming.is_some();
+
+		Span::current().record("session_id", field::display(session_id));
+		info!(resumed, "Stream session req");
 
-		println!("Stream session req: {session_id}");
+		if self.shutdown.is_draining() {
+			return Err(Status::unavailable("Server is shutting down."));
+		}
+
+		// set by the interceptor from a valid bearer token //
+		let identity = request.extensions().get::<Identity>().cloned();
+		if self.auth.is_some() && identity.is_none() {
+			return Err(Status::unauthenticated("This server needs a bearer token to start a session."));
+		}
 
 		let addr = match request.remote_addr()  {
 			Some(a) => Ok(a),
 			None => {
-				eprintln!("Unable to establish connection with client because remote_addr() returned none.");
+				error!("Unable to establish connection with client because remote_addr() returned none.");
 				Err(Status::internal("Couldnt get client remote_addr"))
 			}
 		}?;
 
 		let streaming_rx = request.into_inner();
-		let (client_tx, client_rx) = mpsc::channel(32);
+		let replies = Dispatcher::default();
+		let liveness = Liveness::default();
 
 		let (server_tx, server_rx) = mpsc::channel(32);
 
-		let session = Session::new_ref(addr, server_tx, client_rx);
+		let welcome = Ok(ServerStreamMessage {
+			server_stream_enum: Some(ServerStreamEnum::Welcome(Welcome {
+				session_id: session_id.as_bytes().to_vec(),
+				session_token: self.session_key.issue(&session_id),
+				protocol_version: PROTOCOL_VERSION,
+				capabilities: self.capabilities().into_iter().map(|c| c as i32).collect(),
+				resumed,
+				join_timeout_secs: self.join_timeout().as_secs(),
+			})),
+		});
+		server_tx
+			.send(welcome)
+			.await
+			.map_err(|e| Status::internal(format!("Unable to send welcome: {e}")))?;
+
+		let attachment = match resuming {
+			Some(session) => {
+				let mut session = session.lock().await;
+				if session.is_closed() {
+					return Err(Status::not_found("Session has ended, start a new one."));
+				}
+				session.attach(addr, server_tx.clone(), replies.clone(), liveness.clone())
+			},
+			None => {
+				let session = Session::new_ref(session_id, addr, identity, server_tx.clone(), replies.clone(), liveness.clone());
+				if !self.store.insert_session(session_id, session, self.config.max_sessions).await {
+					return Err(Status::resource_exhausted("Too many sessions on this server."));
+				}
+				0
+			},
+		};
+
+		// outlives this rpc, through to the cleanup //
+		let span = info_span!(parent: None, "session", %session_id, %addr, attachment);
 
-		let cleanup = self.cleanup_fut(&session_id);
+		tokio::spawn(keepalive(
+			server_tx,
+			liveness.clone(),
+			self.config.keepalive(),
+			self.config.idle_timeout(),
+			self.shutdown.clone(),
+			self.config.shutdown_retry(),
+		).instrument(span.clone()));
+
+		let cleanup = self.cleanup(&session_id, attachment);
 
 		tokio::spawn(handle_stream(
 			streaming_rx, 
-			client_tx, 
+			replies, 
+			liveness,
+			self.metrics.clone(),
 			cleanup,
-		));
-
-		let mut sessions = self.sessions.write().await;
-		sessions.insert(session_id, session);
+		).instrument(span));
 
 		let out_stream = Box::pin(ReceiverStream::new(server_rx)) as Self::StreamSessionStream;
 		Ok(Response::new(out_stream))
 	}
 
+	#[instrument(skip_all, fields(
+		session_id = %raw_id(&request.get_ref().session_id),
+		listing_id = %raw_id(&request.get_ref().target_listing_id),
+		addr = request.remote_addr().map(field::display),
+		outcome = field::Empty,
+	))]
 	async fn join( // JOIN //
 		&self,
 		request: Request<JoinRequest>
 	) -> Result<Response<JoinResponse>, Status> {
-		println!("Join session req: {}, {}", Uuid::from_slice(&request.get_ref().session_id).unwrap_or(Uuid::max()), Uuid::from_slice(&request.get_ref().target_listing_id).unwrap_or(Uuid::max()));
+		debug!("Join session req");
+		self.metrics.join_attempt();
 
-		let request = request.into_inner();
+		// a shutdown waits for this to finish //
+		let _running = self
+			.shutdown
+			.join()
+			.ok_or(Status::unavailable("Server is shutting down."))?;
 
 		// validate session //
-		let session_id: Uuid = request.session_id.try_into()
-			.map_err(|e| Status::invalid_argument(format!("Invalid session Uuid: {e}")))?;
-
-		let session = self
-			.get(&session_id)
-			.await
-			.ok_or(Status::invalid_argument(format!("Invalid session id")))?;
+		let (session_id, session) = self.authed(request.extensions(), &request.get_ref().session_id).await?;
+		let request = request.into_inner();
 
 		// validate target session //
 		let target_listing_id: Uuid = request
@@ -224,116 +685,356 @@ impl PuncherService for PuncherServer {
 			.try_into()
 			.map_err(|e| Status::invalid_argument(format!("Invalid listing Uuid: {e}")))?;
 		
-		let id_map = self.id_map.read().await;
-		let target_session_id = id_map
-			.get(&target_listing_id)
-			.ok_or(Status::invalid_argument("Listing ID has no associated session."))?;
+		// ask the host, here or through the node that owns the listing //
+		let joiner = {
+			let session = session.lock().await;
+			Joiner {
+				session_id,
+				ip: session.addr().ip(),
+				identity: session.identity().cloned(),
+				payload: request.payload,
+			}
+		};
+		let (target, decision, target_addr) = match self.store.listing(&target_listing_id).await {
+			Some(_) => {
+				let (host_id, target_session, target_addr, decision) = decide_join(
+					&*self.store,
+					&target_listing_id,
+					joiner,
+					request.password.as_deref(),
+					self.timeout(),
+				).await?;
+				(Host::Local { session: target_session, session_id: host_id }, decision, target_addr)
+			},
+			None => {
+				self.peers.ask_host(NodeAskHostRequest {
+					listing_id: target_listing_id.as_bytes().to_vec(),
+					joiner_session_id: session_id.as_bytes().to_vec(),
+					joiner_ip: joiner.ip.to_string(),
+					payload: joiner.payload,
+					password: request.password,
+					joiner: joiner.identity,
+				}, self.timeout()).await?
+			},
+		};
 
-		let target_session = self
-			.get(&target_session_id)
-			.await
-			.ok_or(Status::invalid_argument(format!("Invalid session id")))?;
+		if !decision.accept {
+			let reason = decision.reason.unwrap_or_default();
+			return Err(Status::permission_denied(format!("Host rejected join: {reason}")));
+		}
 
 		// send both clients punch orders //
-		let target_addr = {
-			let target_session = target_session.lock().await;
-			target_session.addr().clone()
-		};
-
 		let addr = {
 			let session = session.lock().await;
-			session.addr().clone()
+			*session.punch_addr()
 		};
 
 
-		let (resp, target_resp) = join!(order_punch(session, target_addr), order_punch(target_session, addr));
+		let (resp, target_resp) = join!(
+			self.metrics.punch(Side::Joiner, order_punch(session.clone(), target_addr, self.timeout())),
+			self.metrics.punch(Side::Host, target.order_punch(addr, self.timeout())),
+		);
 
 
-		let resp = match resp {
-			Ok(r) => r,
-			Err(e) => {
-				eprintln!("Error while  trying to punch: {e}");
-				PunchStatus{ message: None, success: false}
-			},
+		let resp = resp.unwrap_or_else(|e| {
+			warn!(side = "joiner", error = %e, "Error while trying to punch");
+			PunchStatus { message: Some(e.to_string()), success: false, ..Default::default() }
+		});
+		let target_resp = target_resp.unwrap_or_else(|e| {
+			warn!(side = "host", error = %e, "Error while trying to punch");
+			PunchStatus { message: Some(e.to_string()), success: false, ..Default::default() }
+		});
+
+		let mut response = JoinResponse {
+			joiner: Some(resp.clone()),
+			host: Some(target_resp.clone()),
+			peer_ip: target_addr.ip().to_string(),
+			peer_port: target_addr.port().into(),
+			fallback: Fallback::None as i32,
 		};
-		let target_resp = match target_resp {
-			Ok(r) => r,
+
+		let span = Span::current();
+		if resp.success && target_resp.success {
+			span.record("outcome", "punched");
+			info!("Punch success.");
+			return Ok(Response::new(response));
+		}
+		span.record("outcome", "failed");
+
+		if let Some(msg) = resp.message {
+			info!(side = "joiner", message = %msg, "Punch failed");
+		}
+		if let Some(msg) = target_resp.message {
+			info!(side = "host", message = %msg, "Punch failed");
+		}
+
+		// relay fallback //
+		let Some(relay) = self.relay.as_ref() else {
+			return Ok(Response::new(response));
+		};
+
+		// the punch statuses are still worth returning //
+		let allocation = match relay.allocate([session_id, target.session_id()], addr.ip(), target_addr.ip()).await {
+			Ok(allocation) => allocation,
 			Err(e) => {
-				eprintln!("Error while  trying to punch: {e}");
-				PunchStatus{ message: None, success: false}
+				warn!(error = %e, "Unable to allocate relay");
+				return Ok(Response::new(response));
 			},
 		};
 
-		if resp.success && target_resp.success {
-			println!("Punch success.");
-			return Ok(Response::new(JoinResponse { }));
-		} else {
-			if let Some(msg) = resp.message {
-				eprintln!("Punch failure message(joiner): {msg}")
-			}
-			if let Some(msg) = target_resp.message {
-				eprintln!("Punch failure message(target): {msg}")
-			}
+		info!(relay_a = %allocation.addr_a, relay_b = %allocation.addr_b, "Relaying");
+
+		let public_ip = relay.public_ip();
+		if target.is_remote() && public_ip.is_none() {
+			warn!("Relay has no public ip, the host's node can't point it here");
+			return Ok(Response::new(response));
 		}
 
-		// TODO handle Proxy fallback
+		let (resp, target_resp) = join!(
+			order_relay(session, public_ip, allocation.addr_a.port(), self.timeout()),
+			target.order_relay(public_ip, allocation.addr_b.port(), self.timeout()),
+		);
 
-		Ok(Response::new(JoinResponse { }))
+		if let Err(e) = resp.and(target_resp) {
+			warn!(error = %e, "Unable to send relay order");
+			return Ok(Response::new(response));
+		}
+		span.record("outcome", "relayed");
+
+		response.peer_ip = public_ip.map(|ip| ip.to_string()).unwrap_or_default();
+		response.peer_port = allocation.addr_a.port().into();
+		response.fallback = Fallback::Relay as i32;
+
+		Ok(Response::new(response))
 	}
 }
 
-async fn order_punch(session: SessionRef, addr: SocketAddr) -> Result<PunchStatus> {
-	let mut session = session.lock().await;
-	let (tx, rx) = session.streams();
+/// Who is asking to join, as shown to the host.
+struct Joiner {
+	session_id: Uuid,
+	ip: IpAddr,
+	identity: Option<Identity>,
+	payload: Vec<u8>,
+}
+
+/// Checks the password and asks the host of a listing on this node.
+/// Returns the host's session id, session and punch address along with its decision.
+async fn decide_join(
+	store: &dyn RendezvousStore,
+	listing_id: &Uuid,
+	joiner: Joiner,
+	password: Option<&str>,
+	wait: Duration,
+) -> Result<(Uuid, SessionRef, SocketAddr, JoinDecision), Status> {
+	let (host_id, listing) = store
+		.listing(listing_id)
+		.await
+		.ok_or(Status::invalid_argument("Listing ID has no associated session."))?;
+
+	if host_id == joiner.session_id {
+		return Err(Status::invalid_argument("Cannot join your own listing."));
+	}
+
+	// persisted listings can outlive their host's connection //
+	let host = store
+		.session(&host_id)
+		.await
+		.ok_or(Status::unavailable("Listing host is not connected."))?;
+
+	// check password before bothering the host //
+	if !listing.verify_password(password).await {
+		return Err(Status::permission_denied("Wrong listing password."));
+	}
+
+	let decision = ask_host(host.clone(), joiner, wait)
+		.await
+		.map_err(|e| Status::unavailable(format!("Host did not answer join request: {e}")))?;
+
+	let punch_addr = *host.lock().await.punch_addr();
+
+	Ok((host_id, host, punch_addr, decision))
+}
+
+/// The session's sender and a reply to wait on. The session is only locked for this,
+/// so one host can be asked and punched by several joiners at once.
+async fn expect_reply(session: &SessionRef) -> (session::StreamSender, session::Reply) {
+	let session = session.lock().await;
+	(session.sender().clone(), session.replies().expect())
+}
+
+async fn order_punch(session: SessionRef, addr: SocketAddr, wait: Duration) -> Result<PunchStatus> {
+	let (tx, mut reply) = expect_reply(&session).await;
 
 	let punch_order = Ok(ServerStreamMessage {
-		session_id_assignment: None,
 		server_stream_enum: Some(ServerStreamEnum::Punch(Punch {
 			ip: addr.ip().to_string(),
 			port: addr.port().into(),
+			request_id: reply.id().as_bytes().to_vec(),
 		})),
 	});
 
-	timeout(TIMEOUT, tx.send(punch_order))
+	timeout(wait, tx.send(punch_order))
 		.await
 		.map_err(|e| anyhow!("Timeout sending punch order: {e}"))?
 		.map_err(|e| anyhow!("Unable to send order: {e}"))?;
 
-	let ClientStreamEnum::PunchStatus(status) = timeout(TIMEOUT, rx.recv())
-		.await?
-		.ok_or(anyhow!("Stream closed when receiving punch status"))?;
+	match timeout(wait, reply.recv()).await?? {
+		ClientStreamEnum::PunchStatus(status) => Ok(status),
+		other => Err(anyhow!("Expected punch status, got: {other:?}")),
+	}
+}
+
+async fn ask_host(host: SessionRef, joiner: Joiner, wait: Duration) -> Result<JoinDecision> {
+	let (tx, mut reply) = expect_reply(&host).await;
+
+	let join_request = Ok(ServerStreamMessage {
+		server_stream_enum: Some(ServerStreamEnum::JoinRequest(IncomingJoin {
+			request_id: reply.id().as_bytes().to_vec(),
+			joiner_ip: joiner.ip.to_string(),
+			payload: joiner.payload,
+			joiner: joiner.identity,
+		})),
+	});
+
+	timeout(wait, tx.send(join_request))
+		.await
+		.map_err(|e| anyhow!("Timeout sending join request: {e}"))?
+		.map_err(|e| anyhow!("Unable to send join request: {e}"))?;
+
+	match timeout(wait, reply.recv()).await?? {
+		ClientStreamEnum::JoinDecision(decision) => Ok(decision),
+		other => Err(anyhow!("Expected join decision, got: {other:?}")),
+	}
+}
+
+async fn order_relay(session: SessionRef, ip: Option<IpAddr>, port: u16, wait: Duration) -> Result<()> {
+	let tx = session.lock().await.sender().clone();
+
+	let relay_order = Ok(ServerStreamMessage {
+		server_stream_enum: Some(ServerStreamEnum::Relay(RelayOrder {
+			ip: ip.map(|ip| ip.to_string()).unwrap_or_default(),
+			port: port.into(),
+		})),
+	});
 
-	Ok(status)
+	timeout(wait, tx.send(relay_order))
+		.await
+		.map_err(|e| anyhow!("Timeout sending relay order: {e}"))?
+		.map_err(|e| anyhow!("Unable to send relay order: {e}"))?;
+
+	Ok(())
 }
 
 
-async fn handle_stream<Fut>(
+/// Empty messages down the session stream until it closes, so idle proxies don't cut it.
+/// With an idle timeout, these are pings from halfway to it, and a session still quiet past it after a ping expires.
+/// On shutdown, tells the client when to come back, and ends the stream once the server closes.
+async fn keepalive(
+	tx: session::StreamSender,
+	liveness: Liveness,
+	interval: Duration,
+	idle_timeout: Option<Duration>,
+	shutdown: Shutdown,
+	retry_after: Duration,
+) {
+	let mut pinged = false;
+	let mut notified = false;
+
+	loop {
+		// a resume moves the session onto a new stream with its own keepalive //
+		tokio::select! {
+			_ = sleep(interval) => {},
+			_ = liveness.expired() => break,
+			_ = shutdown.draining(), if !notified => {
+				notified = true;
+				let notice = ServerStreamEnum::Shutdown(ShutdownNotice {
+					reason: "Server is shutting down.".to_string(),
+					retry_after_secs: retry_after.as_secs(),
+				});
+				if tx.send(Ok(ServerStreamMessage { server_stream_enum: Some(notice) })).await.is_err() {
+					break;
+				}
+				continue;
+			},
+			_ = shutdown.closed() => {
+				let _ = tx.send(Err(Status::unavailable("Server shut down."))).await;
+				liveness.expire();
+				break;
+			},
+		}
+
+		let idle = liveness.idle();
+		let message = match idle_timeout {
+			// never without a ping the check before, so the client always gets a chance to answer //
+			Some(limit) if idle >= limit && pinged => {
+				info!(idle_secs = idle.as_secs(), "Session idle, expiring");
+				let _ = tx.send(Err(Status::deadline_exceeded("Session expired after going idle."))).await;
+				liveness.expire_idle();
+				break;
+			},
+			Some(limit) if idle >= limit / 2 => Some(ServerStreamEnum::Ping(Ping {
+				expires_in_secs: limit.saturating_sub(idle).max(interval).as_secs(),
+			})),
+			_ => None,
+		};
+		pinged = message.is_some();
+
+		if tx.send(Ok(ServerStreamMessage { server_stream_enum: message })).await.is_err() {
+			break;
+		}
+	}
+}
+
+/// For logging ids straight from a message, before they're validated.
+fn raw_id(bytes: &[u8]) -> Uuid {
+	Uuid::from_slice(bytes).unwrap_or(Uuid::max())
+}
+
+async fn handle_stream<F>(
 	mut stream: Streaming<ClientStreamMessage>, 
-	output: Sender<ClientStreamEnum>, 
-	cleanup: Fut,
+	replies: Dispatcher, 
+	liveness: Liveness,
+	metrics: Arc<Metrics>,
+	cleanup: F,
 ) 
 where 
-	Fut: Future<Output = ()> + Send + 'static,
+	F: FnOnce(bool) -> BoxFuture<'static, ()>,
 {
+	// only a client ending its stream cleanly, or going idle, is gone for good //
+	let mut dropped = false;
+
 	loop {
-		match stream.message().await {
+		// a half-open connection never ends the stream, expiry does //
+		let message = tokio::select! {
+			message = stream.message() => message,
+			_ = liveness.expired() => {
+				// idle clients already had the whole idle timeout to answer //
+				dropped = !liveness.went_idle();
+				break;
+			},
+		};
+
+		match message {
 			Ok(opt) => {
 				match opt {
 					Some(msg) => {
-						if let Some(msg_enum) = msg.client_stream_enum {
-							if let Err(e) = output.send(msg_enum).await {
-								eprintln!("Unable to forward streaming message: {e}; closing stream handler");
-								break;
-							};
+						liveness.seen();
+						if let Some(msg_enum) = msg.client_stream_enum
+							&& let Err(msg_enum) = replies.dispatch(msg_enum)
+						{
+							warn!(reply = ?msg_enum, "Nothing is waiting for this reply, discarding");
 						};
 					},
 					None => break,
 				}
 			},
 			Err(e) => {
-				eprintln!("Received stream error: {e}; continuing");
+				warn!(error = %e, "Received stream error; continuing");
+				metrics.stream_error();
+				dropped = true;
 			},
 		};
 	}
-	cleanup.await;
+	replies.close();
+	cleanup(dropped).await;
 }
\ No newline at end of file
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use prost::Message;
+use crate::{proto::{GetListingsRequest, ListingFilter, ListingSort, ListingsCursor}, server::listing::RustListing};
+
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+pub const MAX_PAGE_SIZE: u32 = 200;
+
+// ---- RUST ---- //
+
+/// Filter, sort and page of a listings query. Unset filter fields match everything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RustListingQuery {
+	/// Case insensitive.
+	pub name_contains: Option<String>,
+	/// All must be equal.
+	pub attributes: HashMap<String, Vec<u8>>,
+	/// Current players, inclusive.
+	pub min_players: Option<u32>,
+	pub max_players: Option<u32>,
+	pub has_password: Option<bool>,
+	pub version: Option<String>,
+
+	pub sort: ListingSort,
+	pub descending: bool,
+	/// 0 for the server default.
+	pub page_size: u32,
+	/// `next_cursor` of the previous page.
+	pub cursor: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListingsPage {
+	pub listings: Vec<RustListing>,
+	/// `None` on the last page.
+	pub next_cursor: Option<Vec<u8>>,
+}
+
+impl From<RustListingQuery> for GetListingsRequest {
+	fn from(query: RustListingQuery) -> Self {
+		Self {
+			filter: Some(ListingFilter {
+				name_contains: query.name_contains,
+				attributes: query.attributes,
+				min_players: query.min_players,
+				max_players: query.max_players,
+				has_password: query.has_password,
+				version: query.version,
+			}),
+			sort: query.sort as i32,
+			descending: query.descending,
+			page_size: query.page_size,
+			cursor: query.cursor.unwrap_or_default(),
+		}
+	}
+}
+
+
+// ---- SERVER ---- //
+
+pub fn matches(filter: &ListingFilter, listing: &RustListing) -> bool {
+	let inner = listing.inner();
+
+	if let Some(name) = filter.name_contains.as_ref()
+		&& !inner.name.to_lowercase().contains(&name.to_lowercase())
+	{
+		return false;
+	}
+
+	let attributes_match = filter
+		.attributes
+		.iter()
+		.all(|(k, v)| inner.attributes.get(k) == Some(v));
+
+	attributes_match
+		&& filter.min_players.is_none_or(|min| inner.current_players >= min)
+		&& filter.max_players.is_none_or(|max| inner.current_players <= max)
+		&& filter.has_password.is_none_or(|p| listing.has_password() == p)
+		&& filter.version.as_ref().is_none_or(|v| inner.version == *v)
+}
+
+/// Only the field for `sort` is set, so tuples compare by that field then id.
+type SortKey = (String, u32, Vec<u8>);
+
+fn sort_key(sort: ListingSort, listing: &RustListing) -> SortKey {
+	let id = listing.id().as_bytes().to_vec();
+	match sort {
+		ListingSort::Id => (String::new(), 0, id),
+		ListingSort::Name => (listing.inner().name.clone(), 0, id),
+		ListingSort::Players => (String::new(), listing.inner().current_players, id),
+	}
+}
+
+/// Filters, sorts and cuts one page out of `listings`, returning it with the cursor for the next.
+pub fn page(listings: impl IntoIterator<Item = RustListing>, request: &GetListingsRequest) -> Result<(Vec<RustListing>, Vec<u8>)> {
+	let sort = ListingSort::try_from(request.sort).unwrap_or_default();
+	let page_size = match request.page_size {
+		0 => DEFAULT_PAGE_SIZE,
+		n => n.min(MAX_PAGE_SIZE),
+	} as usize;
+
+	let after: Option<SortKey> = if request.cursor.is_empty() {
+		None
+	} else {
+		let cursor = ListingsCursor::decode(request.cursor.as_slice())
+			.map_err(|e| anyhow!("Malformed cursor: {e}"))?;
+		Some((cursor.name, cursor.players, cursor.id))
+	};
+
+	let default_filter = ListingFilter::default();
+	let filter = request.filter.as_ref().unwrap_or(&default_filter);
+
+	let mut keyed: Vec<(SortKey, RustListing)> = listings
+		.into_iter()
+		.filter(|l| matches(filter, l))
+		.map(|l| (sort_key(sort, &l), l))
+		.filter(|(key, _)| match after.as_ref() {
+			None => true,
+			Some(after) if request.descending => key < after,
+			Some(after) => key > after,
+		})
+		.collect();
+
+	keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+	if request.descending {
+		keyed.reverse();
+	}
+
+	let next_cursor = match keyed.get(page_size) {
+		// there is more after this page, point at its last listing //
+		Some(_) => {
+			let (name, players, id) = keyed[page_size - 1].0.clone();
+			ListingsCursor { name, players, id }.encode_to_vec()
+		},
+		None => Vec::new(),
+	};
+
+	let page = keyed
+		.into_iter()
+		.take(page_size)
+		.map(|(_, l)| l)
+		.collect();
+
+	Ok((page, next_cursor))
+}
@@ -0,0 +1,211 @@
+use std::{collections::HashMap, hash::Hash, net::IpAddr, sync::{Arc, Mutex}, task::{Context, Poll}, time::Instant};
+use futures::future::{self, Either, Ready};
+use tonic::{codegen::http, transport::server::{TcpConnectInfo, TlsConnectInfo}, Status};
+use tower::{Layer, Service};
+use super::auth::SESSION_TOKEN;
+
+/// Buckets are only pruned once a map grows past this, dropping the ones that have refilled.
+/// After that, once it doubles from what was left, so pruning stays amortized O(1) under load.
+const PRUNE_AT: usize = 1024;
+
+/// Header a trusted reverse proxy appends the client ip to.
+const FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Sustained requests per second, and how many may arrive at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+	pub per_sec: f64,
+	pub burst: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+	tokens: f64,
+	last: Instant,
+}
+
+impl TokenBucket {
+	fn full(rate: &Rate, now: Instant) -> Self {
+		Self { tokens: rate.burst.into(), last: now }
+	}
+
+	fn refill(&mut self, rate: &Rate, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * rate.per_sec).min(rate.burst.into());
+		self.last = now;
+	}
+
+	fn try_take(&mut self, rate: &Rate, now: Instant) -> bool {
+		self.refill(rate, now);
+		if self.tokens < 1.0 {
+			return false;
+		}
+		self.tokens -= 1.0;
+		true
+	}
+}
+
+/// One bucket per key, all filling at the same rate.
+struct Buckets<K> {
+	rate: Rate,
+	buckets: Mutex<BucketMap<K>>,
+}
+
+struct BucketMap<K> {
+	map: HashMap<K, TokenBucket>,
+	prune_at: usize,
+}
+
+impl<K: Hash + Eq> Buckets<K> {
+	fn new(rate: Rate) -> Self {
+		Self { rate, buckets: Mutex::new(BucketMap { map: HashMap::new(), prune_at: PRUNE_AT }) }
+	}
+
+	fn try_take(&self, key: K, now: Instant) -> bool {
+		let mut buckets = self.buckets.lock().unwrap();
+
+		// a refilled bucket is the same as a new one //
+		if buckets.map.len() >= buckets.prune_at {
+			let rate = self.rate;
+			buckets.map.retain(|_, bucket| {
+				bucket.refill(&rate, now);
+				bucket.tokens < f64::from(rate.burst)
+			});
+			buckets.prune_at = PRUNE_AT.max(buckets.map.len() * 2);
+		}
+
+		buckets
+			.map
+			.entry(key)
+			.or_insert_with(|| TokenBucket::full(&self.rate, now))
+			.try_take(&self.rate, now)
+	}
+}
+
+/// Token buckets keyed by remote ip and by session token. Either limit may be off.
+pub struct RateLimiter {
+	ip: Option<Buckets<IpAddr>>,
+	session: Option<Buckets<Vec<u8>>>,
+}
+
+impl RateLimiter {
+	pub fn new(ip: Option<Rate>, session: Option<Rate>) -> Self {
+		Self {
+			ip: ip.map(Buckets::new),
+			session: session.map(Buckets::new),
+		}
+	}
+
+	/// Takes a token from each bucket the request falls in, naming the limit it's over if any.
+	pub fn check(&self, ip: Option<IpAddr>, session_token: Option<&[u8]>, now: Instant) -> Result<(), &'static str> {
+		if let (Some(buckets), Some(ip)) = (self.ip.as_ref(), ip)
+			&& !buckets.try_take(ip, now)
+		{
+			return Err("Too many requests from this address.");
+		}
+
+		if let (Some(buckets), Some(token)) = (self.session.as_ref(), session_token)
+			&& !buckets.try_take(token.to_vec(), now)
+		{
+			return Err("Too many requests from this session.");
+		}
+
+		Ok(())
+	}
+}
+
+
+// -- LAYER -- //
+
+/// Answers requests over a [`RateLimiter`]'s limits with `resource_exhausted` before they reach a service.
+///
+/// Sessions are keyed by their unverified token; forged ones are turned away by [`super::auth::AuthInterceptor`]
+/// right after, and still count against the ip.
+///
+/// Requests from `trusted_proxies` are keyed by the client ip the proxies appended to `X-Forwarded-For` instead.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+	limiter: Arc<RateLimiter>,
+	trusted_proxies: Arc<[IpAddr]>,
+}
+
+impl RateLimitLayer {
+	pub fn new(limiter: RateLimiter, trusted_proxies: Vec<IpAddr>) -> Self {
+		Self { limiter: Arc::new(limiter), trusted_proxies: trusted_proxies.into() }
+	}
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+	type Service = RateLimit<S>;
+
+	fn layer(&self, inner: S) -> Self::Service {
+		RateLimit { inner, limiter: self.limiter.clone(), trusted_proxies: self.trusted_proxies.clone() }
+	}
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+	inner: S,
+	limiter: Arc<RateLimiter>,
+	trusted_proxies: Arc<[IpAddr]>,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for RateLimit<S>
+where
+	S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+	ResBody: Default,
+{
+	type Response = S::Response;
+	type Error = S::Error;
+	type Future = Either<Ready<Result<S::Response, S::Error>>, S::Future>;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		self.inner.poll_ready(cx)
+	}
+
+	fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+		let ip = remote_ip(request.extensions())
+			.map(|ip| client_ip(ip, request.headers(), &self.trusted_proxies));
+		let session_token = request.headers().get(SESSION_TOKEN).map(|t| t.as_bytes());
+
+		match self.limiter.check(ip, session_token, Instant::now()) {
+			Ok(()) => Either::Right(self.inner.call(request)),
+			Err(reason) => Either::Left(future::ready(Ok(Status::resource_exhausted(reason).into_http()))),
+		}
+	}
+}
+
+fn remote_ip(extensions: &http::Extensions) -> Option<IpAddr> {
+	let tcp = extensions
+		.get::<TcpConnectInfo>()
+		.or_else(|| extensions.get::<TlsConnectInfo<TcpConnectInfo>>().map(|tls| tls.get_ref()))?;
+
+	tcp.remote_addr().map(|addr| addr.ip())
+}
+
+/// The ip a request came from, past any trusted proxies. Each proxy appends who it heard from, so the list is read
+/// from the end and the first hop that isn't a trusted proxy is the client. Anything before it could be forged.
+pub fn client_ip(peer: IpAddr, headers: &http::HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+	if !trusted_proxies.contains(&peer) {
+		return peer;
+	}
+
+	let hops = headers
+		.get_all(FORWARDED_FOR)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(','))
+		.map(|hop| hop.trim().parse::<IpAddr>().ok())
+		.collect::<Vec<_>>();
+
+	let mut client = peer;
+	for hop in hops.into_iter().rev() {
+		// garbage from a trusted proxy, stop at the last hop we could read //
+		let Some(hop) = hop else { break };
+		client = hop;
+		if !trusted_proxies.contains(&hop) {
+			break;
+		}
+	}
+	client
+}
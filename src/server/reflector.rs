@@ -0,0 +1,55 @@
+use std::{net::SocketAddr, sync::Arc};
+use anyhow::Result;
+use prost::Message;
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+use crate::{proto::{BindingRequest, BindingResponse}, server::{auth::SessionKey, store::RendezvousStore}};
+
+const MAX_DATAGRAM: usize = 128;
+
+/// Answers `BindingRequest`s with the udp address they were observed from, and records that address on the matching session.
+/// Requests carry the session token, so nobody else can point a session's punches at themselves.
+pub async fn run(addr: SocketAddr, store: Arc<dyn RendezvousStore>, sessions: SessionKey) -> Result<()> {
+	let socket = UdpSocket::bind(addr).await?;
+	let mut buf = [0u8; MAX_DATAGRAM];
+
+	loop {
+		let (len, src) = match socket.recv_from(&mut buf).await {
+			Ok(r) => r,
+			Err(e) => {
+				warn!(error = %e, "Reflector receive error; continuing");
+				continue;
+			},
+		};
+
+		let Ok(request) = BindingRequest::decode(&buf[..len]) else {
+			debug!(%src, "Reflector received malformed binding request");
+			continue;
+		};
+
+		let Some(session_id) = sessions.verify(&request.session_token) else {
+			debug!(%src, "Reflector received bad session token");
+			continue;
+		};
+
+		// only reflect for known sessions //
+		let Some(session) = store.session(&session_id).await else {
+			debug!(%src, %session_id, "Reflector received binding request for unknown session");
+			continue;
+		};
+
+		{
+			let mut session = session.lock().await;
+			session.set_udp_addr(src);
+		}
+
+		let response = BindingResponse {
+			ip: src.ip().to_string(),
+			port: src.port().into(),
+		};
+
+		if let Err(e) = socket.send_to(&response.encode_to_vec(), src).await {
+			warn!(%src, error = %e, "Unable to send binding response");
+		}
+	}
+}
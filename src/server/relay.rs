@@ -0,0 +1,240 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::Duration};
+use anyhow::{anyhow, Result};
+use tokio::{net::UdpSocket, time::sleep};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+const MAX_DATAGRAM: usize = 65_507;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RelayLimits {
+	/// Total bytes forwarded in both directions before the allocation is closed.
+	pub max_bytes: u64,
+	pub lifetime: Duration,
+	/// Allocations forwarding at once, over every session.
+	pub max_allocations: usize,
+	/// Allocations forwarding at once for each of the two sessions they relay between.
+	pub max_allocations_per_session: usize,
+}
+
+impl Default for RelayLimits {
+	fn default() -> Self {
+		Self {
+			max_bytes: 64 * 1024 * 1024,
+			lifetime: Duration::from_secs(10 * 60),
+			max_allocations: 256,
+			max_allocations_per_session: 4,
+		}
+	}
+}
+
+/// Allocates udp port pairs and forwards datagrams between two peers who couldn't punch.
+/// Clones share their allocations.
+#[derive(Clone)]
+pub struct Relay {
+	bind_ip: IpAddr,
+	public_ip: Option<IpAddr>,
+	limits: RelayLimits,
+	usage: Arc<RelayUsage>,
+	allocations: Arc<Mutex<Allocations>>,
+}
+
+/// The allocations still forwarding, under both sessions they relay between.
+#[derive(Default)]
+struct Allocations {
+	next_id: u64,
+	total: usize,
+	by_session: HashMap<Uuid, HashMap<u64, CancellationToken>>,
+}
+
+impl Allocations {
+	fn remove(&mut self, sessions: &[Uuid; 2], id: u64) {
+		let mut removed = false;
+		for session_id in sessions {
+			let Some(session) = self.by_session.get_mut(session_id) else { continue };
+			removed |= session.remove(&id).is_some();
+			if session.is_empty() {
+				self.by_session.remove(session_id);
+			}
+		}
+		if removed {
+			self.total -= 1;
+		}
+	}
+}
+
+/// Running totals over every allocation, see [`super::metrics`].
+#[derive(Debug, Default)]
+pub struct RelayUsage {
+	pub allocations: AtomicU64,
+	/// Allocations still forwarding.
+	pub active: AtomicU64,
+	pub bytes: AtomicU64,
+}
+
+/// One port per peer. Peers send to their own port; the relay forwards it out of the other.
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+	pub addr_a: SocketAddr,
+	pub addr_b: SocketAddr,
+}
+
+impl Relay {
+	pub fn new(bind_ip: IpAddr, limits: RelayLimits) -> Self {
+		Self { bind_ip, public_ip: None, limits, usage: Arc::default(), allocations: Arc::default() }
+	}
+
+	/// Advertised instead of the bind ip, e.g. when bound behind a NAT or a load balancer.
+	pub fn with_public_ip(mut self, public_ip: IpAddr) -> Self {
+		self.public_ip = Some(public_ip);
+		self
+	}
+
+	pub fn usage(&self) -> Arc<RelayUsage> { self.usage.clone() }
+
+	/// The ip to advertise to peers. `None` when there is only an unspecified or loopback bind ip,
+	/// which other machines can't reach, so peers use the grpc server's host instead.
+	pub fn public_ip(&self) -> Option<IpAddr> {
+		self.public_ip.or((!self.bind_ip.is_unspecified() && !self.bind_ip.is_loopback()).then_some(self.bind_ip))
+	}
+
+	/// Binds a port pair and spawns the forwarder, counted against both `sessions`, the joiner's and the host's,
+	/// and released when either ends. Each port only accepts the first source from its expected peer ip.
+	pub async fn allocate(&self, sessions: [Uuid; 2], peer_a: IpAddr, peer_b: IpAddr) -> Result<Allocation> {
+		let (id, cancel) = self.reserve(&sessions)?;
+
+		let (socket_a, socket_b, allocation) = match self.bind().await {
+			Ok(bound) => bound,
+			Err(e) => {
+				self.allocations.lock().unwrap().remove(&sessions, id);
+				return Err(e);
+			},
+		};
+
+		self.usage.allocations.fetch_add(1, Ordering::Relaxed);
+		self.usage.active.fetch_add(1, Ordering::Relaxed);
+
+		let span = info_span!("relay", addr_a = %allocation.addr_a, addr_b = %allocation.addr_b);
+		let relay = self.clone();
+		tokio::spawn(async move {
+			tokio::select! {
+				_ = forward(socket_a, peer_a, socket_b, peer_b, relay.limits, relay.usage.clone()) => {},
+				_ = cancel.cancelled() => info!("Relay allocation released"),
+			}
+			relay.usage.active.fetch_sub(1, Ordering::Relaxed);
+			relay.allocations.lock().unwrap().remove(&sessions, id);
+		}.instrument(span));
+
+		Ok(allocation)
+	}
+
+	/// Stops forwarding every allocation `session_id` is part of, e.g. once it has ended.
+	pub fn release(&self, session_id: &Uuid) {
+		if let Some(session) = self.allocations.lock().unwrap().by_session.get(session_id) {
+			session.values().for_each(CancellationToken::cancel);
+		}
+	}
+
+	/// Counts an allocation before its ports are bound, if the global limit and both sessions' allow it.
+	fn reserve(&self, sessions: &[Uuid; 2]) -> Result<(u64, CancellationToken)> {
+		let mut allocations = self.allocations.lock().unwrap();
+
+		if allocations.total >= self.limits.max_allocations {
+			return Err(anyhow!("Relay is at its allocation limit"));
+		}
+		for session_id in sessions {
+			let session = allocations.by_session.get(session_id).map_or(0, HashMap::len);
+			if session >= self.limits.max_allocations_per_session {
+				return Err(anyhow!("Session is at its relay allocation limit"));
+			}
+		}
+
+		let id = allocations.next_id;
+		let cancel = CancellationToken::new();
+		allocations.next_id += 1;
+		allocations.total += 1;
+		for session_id in sessions {
+			allocations.by_session.entry(*session_id).or_default().insert(id, cancel.clone());
+		}
+
+		Ok((id, cancel))
+	}
+
+	async fn bind(&self) -> Result<(Arc<UdpSocket>, Arc<UdpSocket>, Allocation)> {
+		let socket_a = Arc::new(UdpSocket::bind(SocketAddr::new(self.bind_ip, 0)).await?);
+		let socket_b = Arc::new(UdpSocket::bind(SocketAddr::new(self.bind_ip, 0)).await?);
+
+		let allocation = Allocation {
+			addr_a: socket_a.local_addr()?,
+			addr_b: socket_b.local_addr()?,
+		};
+
+		Ok((socket_a, socket_b, allocation))
+	}
+}
+
+async fn forward(
+	socket_a: Arc<UdpSocket>,
+	peer_a: IpAddr,
+	socket_b: Arc<UdpSocket>,
+	peer_b: IpAddr,
+	limits: RelayLimits,
+	usage: Arc<RelayUsage>,
+) {
+	let mut buf_a = vec![0u8; MAX_DATAGRAM];
+	let mut buf_b = vec![0u8; MAX_DATAGRAM];
+	let mut latched_a: Option<SocketAddr> = None;
+	let mut latched_b: Option<SocketAddr> = None;
+	let mut forwarded: u64 = 0;
+
+	let lifetime = sleep(limits.lifetime);
+	tokio::pin!(lifetime);
+
+	loop {
+		let (result, from_a) = tokio::select! {
+			r = socket_a.recv_from(&mut buf_a) => (r, true),
+			r = socket_b.recv_from(&mut buf_b) => (r, false),
+			_ = &mut lifetime => {
+				info!(forwarded, "Relay allocation expired");
+				break;
+			},
+		};
+
+		let (len, src) = match result {
+			Ok(r) => r,
+			Err(e) => {
+				warn!(error = %e, "Relay receive error; continuing");
+				continue;
+			},
+		};
+
+		let (buf, expected, latch, out, dst) = if from_a {
+			(&buf_a, peer_a, &mut latched_a, &socket_b, latched_b)
+		} else {
+			(&buf_b, peer_b, &mut latched_b, &socket_a, latched_a)
+		};
+
+		// latch the first datagram from the expected ip, drop strangers //
+		match latch {
+			Some(l) if *l != src => continue,
+			Some(_) => {},
+			None if src.ip() != expected => continue,
+			None => *latch = Some(src),
+		}
+
+		// the other side hasnt sent anything yet, so there is nowhere to forward //
+		let Some(dst) = dst else { continue };
+
+		forwarded += len as u64;
+		if forwarded > limits.max_bytes {
+			info!(forwarded, "Relay allocation reached its byte limit");
+			break;
+		}
+
+		match out.send_to(&buf[..len], dst).await {
+			Ok(sent) => { usage.bytes.fetch_add(sent as u64, Ordering::Relaxed); },
+			Err(e) => debug!(%dst, error = %e, "Relay unable to forward"),
+		}
+	}
+}
@@ -1,38 +1,201 @@
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::{mpsc::{Receiver, Sender}, Mutex};
+use std::{collections::HashMap, net::SocketAddr, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
+use anyhow::{anyhow, Result};
+use tokio::sync::{mpsc::Sender, oneshot, Mutex};
+use tokio_util::sync::CancellationToken;
 use tonic::Status;
 use uuid::Uuid;
-use crate::{proto::{client_stream_message::ClientStreamEnum, ServerStreamMessage}, server::listing::RustListing};
+use crate::proto::{client_stream_message::ClientStreamEnum, Identity, ServerStreamMessage};
 
 pub type SessionRef = Arc<Mutex<Session>>;
 
 pub type StreamSender = Sender<Result<ServerStreamMessage, Status>>;
-pub type StreamReceiver = Receiver<ClientStreamEnum>;
 
 pub struct Session {
-	pub listing: Option<RustListing>,
-	streams: (StreamSender, StreamReceiver),
+	tx: StreamSender,
+	replies: Dispatcher,
+	liveness: Liveness,
+	/// Bumped each time the session is resumed on a new stream.
+	attachment: u64,
+	closed: bool,
 	id: Uuid,
 	addr: SocketAddr,
+	udp_addr: Option<SocketAddr>,
+	identity: Option<Identity>,
 }
 
 impl Session {
-	pub fn new(addr: SocketAddr, stream_tx: StreamSender, stream_rx: StreamReceiver) -> Self {
+	pub fn new(id: Uuid, addr: SocketAddr, identity: Option<Identity>, stream_tx: StreamSender, replies: Dispatcher, liveness: Liveness) -> Self {
 		Self {
-			id: Uuid::new_v4(),
-			listing: None,
-			streams: (stream_tx, stream_rx),
+			id,
+			tx: stream_tx,
+			replies,
+			liveness,
+			attachment: 0,
+			closed: false,
 			addr,
+			udp_addr: None,
+			identity,
 		}
 	}
 	
-	pub fn new_ref(addr: SocketAddr, stream_tx: StreamSender, stream_rx: StreamReceiver) -> SessionRef {
-		Arc::new(Mutex::new(Self::new(addr, stream_tx, stream_rx)))
+	pub fn new_ref(id: Uuid, addr: SocketAddr, identity: Option<Identity>, stream_tx: StreamSender, replies: Dispatcher, liveness: Liveness) -> SessionRef {
+		Arc::new(Mutex::new(Self::new(id, addr, identity, stream_tx, replies, liveness)))
 	}
 
 	pub fn id(&self) -> &Uuid {&self.id}
 
 	pub fn addr(&self) -> &SocketAddr {&self.addr}
 
-	pub fn streams(&mut self) -> (&StreamSender, &mut StreamReceiver) { (&self.streams.0, &mut self.streams.1) }
+	/// Verified by the server's [`super::auth::AuthProvider`]; `None` for anonymous sessions.
+	pub fn identity(&self) -> Option<&Identity> {self.identity.as_ref()}
+
+	pub fn set_udp_addr(&mut self, addr: SocketAddr) {self.udp_addr = Some(addr)}
+
+	/// The address peers should punch toward: the reflected udp mapping if the client registered one, otherwise the grpc connection's address.
+	pub fn punch_addr(&self) -> &SocketAddr {self.udp_addr.as_ref().unwrap_or(&self.addr)}
+
+	pub fn sender(&self) -> &StreamSender {&self.tx}
+
+	pub fn replies(&self) -> &Dispatcher {&self.replies}
+
+	pub fn attachment(&self) -> u64 {self.attachment}
+
+	/// Moves the session onto the stream its client resumed it with, ending the old one. Returns the new attachment.
+	pub fn attach(&mut self, addr: SocketAddr, stream_tx: StreamSender, replies: Dispatcher, liveness: Liveness) -> u64 {
+		self.replies.close();
+		self.liveness.expire();
+
+		self.addr = addr;
+		self.tx = stream_tx;
+		self.replies = replies;
+		self.liveness = liveness;
+		self.attachment += 1;
+		self.attachment
+	}
+
+	/// Whether the session has been cleaned up, and can't be resumed.
+	pub fn is_closed(&self) -> bool {self.closed}
+
+	pub fn close(&mut self) {
+		self.closed = true;
+		self.replies.close();
+	}
+}
+
+
+// -- LIVENESS -- //
+
+/// When a session's client was last heard from, and the switch that ends its stream once it's been quiet too long.
+#[derive(Clone)]
+pub struct Liveness {
+	last_seen: Arc<std::sync::Mutex<Instant>>,
+	expired: CancellationToken,
+	went_idle: Arc<AtomicBool>,
+}
+
+impl Default for Liveness {
+	fn default() -> Self {
+		Self {
+			last_seen: Arc::new(std::sync::Mutex::new(Instant::now())),
+			expired: CancellationToken::new(),
+			went_idle: Arc::default(),
+		}
+	}
+}
+
+impl Liveness {
+	pub fn seen(&self) { *self.last_seen.lock().unwrap() = Instant::now() }
+
+	pub fn idle(&self) -> Duration { self.last_seen.lock().unwrap().elapsed() }
+
+	pub fn expire(&self) { self.expired.cancel() }
+
+	/// Expires a client that stopped answering pings; it isn't waited on to resume.
+	pub fn expire_idle(&self) {
+		self.went_idle.store(true, Ordering::Relaxed);
+		self.expire();
+	}
+
+	pub fn went_idle(&self) -> bool { self.went_idle.load(Ordering::Relaxed) }
+
+	pub async fn expired(&self) { self.expired.cancelled().await }
+}
+
+
+// -- DISPATCH -- //
+
+#[derive(Default)]
+struct Waiters {
+	closed: bool,
+	waiting: HashMap<Uuid, oneshot::Sender<ClientStreamEnum>>,
+}
+
+/// Routes a session's replies to the calls waiting on them by request id,
+/// so several punches and join requests can be in flight on one session at once.
+#[derive(Clone, Default)]
+pub struct Dispatcher {
+	waiters: Arc<std::sync::Mutex<Waiters>>,
+}
+
+impl Dispatcher {
+	/// Waits for the reply to a new request id; send the request with [`Reply::id`].
+	pub fn expect(&self) -> Reply {
+		let id = Uuid::new_v4();
+		let (tx, rx) = oneshot::channel();
+
+		// a closed session drops `tx`, failing the reply right away //
+		let mut waiters = self.waiters.lock().unwrap();
+		if !waiters.closed {
+			waiters.waiting.insert(id, tx);
+		}
+
+		Reply { id, rx, waiters: self.waiters.clone() }
+	}
+
+	/// Hands `reply` to the call waiting on its request id, or back if none is.
+	pub fn dispatch(&self, reply: ClientStreamEnum) -> Result<(), ClientStreamEnum> {
+		let request_id = match &reply {
+			ClientStreamEnum::PunchStatus(status) => &status.request_id,
+			ClientStreamEnum::JoinDecision(decision) => &decision.request_id,
+		};
+
+		let waiter = Uuid::from_slice(request_id)
+			.ok()
+			.and_then(|id| self.waiters.lock().unwrap().waiting.remove(&id));
+
+		match waiter {
+			Some(waiter) => waiter.send(reply),
+			None => Err(reply),
+		}
+	}
+
+	/// Fails every waiting and future reply, once the stream has ended.
+	pub fn close(&self) {
+		let mut waiters = self.waiters.lock().unwrap();
+		waiters.closed = true;
+		waiters.waiting.clear();
+	}
+}
+
+/// A reply the session owes; stops waiting when dropped.
+pub struct Reply {
+	id: Uuid,
+	rx: oneshot::Receiver<ClientStreamEnum>,
+	waiters: Arc<std::sync::Mutex<Waiters>>,
+}
+
+impl Reply {
+	pub fn id(&self) -> &Uuid {&self.id}
+
+	pub async fn recv(&mut self) -> Result<ClientStreamEnum> {
+		(&mut self.rx)
+			.await
+			.map_err(|_| anyhow!("Stream closed before the reply"))
+	}
+}
+
+impl Drop for Reply {
+	fn drop(&mut self) {
+		self.waiters.lock().unwrap().waiting.remove(&self.id);
+	}
 }
@@ -0,0 +1,39 @@
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_util::{sync::CancellationToken, task::{task_tracker::TaskTrackerToken, TaskTracker}};
+use tracing::warn;
+
+/// Where a graceful shutdown is at, shared by everything that has to wind down, see [`super::run_with_shutdown`].
+/// Draining refuses new sessions and joins while the running joins finish; closed ends every stream.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+	draining: CancellationToken,
+	closed: CancellationToken,
+	joins: TaskTracker,
+}
+
+impl Shutdown {
+	/// Starts draining, and closes once the running joins are done or `wait` is up.
+	pub async fn drain(&self, wait: Duration) {
+		self.draining.cancel();
+		self.joins.close();
+
+		if timeout(wait, self.joins.wait()).await.is_err() {
+			warn!(joins = self.joins.len(), wait_secs = wait.as_secs(), "Joins still running, closing anyway");
+		}
+		self.closed.cancel();
+	}
+
+	pub fn is_draining(&self) -> bool { self.draining.is_cancelled() }
+
+	pub async fn draining(&self) { self.draining.cancelled().await }
+
+	pub async fn closed(&self) { self.closed.cancelled().await }
+
+	/// Held for the length of a join so draining waits on it; `None` once draining.
+	pub fn join(&self) -> Option<TaskTrackerToken> {
+		// taken before the check, so a drain starting in between still counts it //
+		let token = self.joins.token();
+		(!self.is_draining()).then_some(token)
+	}
+}
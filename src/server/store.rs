@@ -0,0 +1,270 @@
+use std::{collections::HashMap, path::PathBuf};
+use anyhow::{anyhow, Result};
+use prost::Message;
+use tokio::{fs, sync::{Mutex, RwLock}};
+use uuid::Uuid;
+use crate::{proto::{StoredListing, StoredListings}, server::{auth::SessionKey, listing::RustListing, session::SessionRef}};
+
+/// The session and listing registry behind [`super::PuncherServer`].
+///
+/// Sessions hold live streams and are never persisted. Listings are owned by a session id and may outlive it
+/// in a persistent store, in which case joins fail until the owner is connected again. An owner reclaims its
+/// listing by resuming with its old session token, which needs the store's [`RendezvousStore::session_key`];
+/// listings nobody reclaims within the resume grace period are removed.
+#[tonic::async_trait]
+pub trait RendezvousStore: Send + Sync + 'static {
+	/// The key session tokens are signed with, for stores whose listings outlive the process. Random per process otherwise.
+	fn session_key(&self) -> Option<SessionKey> { None }
+
+	/// Returns false, inserting nothing, when `max` sessions are in already.
+	async fn insert_session(&self, session_id: Uuid, session: SessionRef, max: Option<usize>) -> bool;
+
+	async fn session(&self, session_id: &Uuid) -> Option<SessionRef>;
+
+	async fn remove_session(&self, session_id: &Uuid) -> Option<SessionRef>;
+
+	async fn session_count(&self) -> usize;
+
+	/// Inserts or replaces the listing owned by `session_id`. Returns false, inserting nothing,
+	/// when the session has no listing yet and `max` listings are in already.
+	async fn put_listing(&self, session_id: Uuid, listing: RustListing, max: Option<usize>) -> Result<bool>;
+
+	async fn listing_of(&self, session_id: &Uuid) -> Option<RustListing>;
+
+	/// The listing and the id of the session that owns it.
+	async fn listing(&self, listing_id: &Uuid) -> Option<(Uuid, RustListing)>;
+
+	async fn remove_listing_of(&self, session_id: &Uuid) -> Result<Option<RustListing>>;
+
+	/// Every listing, in no particular order.
+	async fn listings(&self) -> Vec<RustListing>;
+
+	async fn listing_count(&self) -> usize;
+}
+
+
+// -- MEMORY -- //
+
+#[derive(Default)]
+struct Listings {
+	by_session: HashMap<Uuid, RustListing>,
+	id_map: HashMap<Uuid, Uuid>,
+}
+
+/// Keeps everything in memory; a restart wipes every listing.
+#[derive(Default)]
+pub struct MemoryStore {
+	sessions: RwLock<HashMap<Uuid, SessionRef>>,
+	listings: RwLock<Listings>,
+}
+
+#[tonic::async_trait]
+impl RendezvousStore for MemoryStore {
+	async fn insert_session(&self, session_id: Uuid, session: SessionRef, max: Option<usize>) -> bool {
+		let mut sessions = self.sessions.write().await;
+
+		// checked under the same lock as the insert, so racing sessions can't both take the last slot //
+		if max.is_some_and(|max| sessions.len() >= max) {
+			return false;
+		}
+		sessions.insert(session_id, session);
+		true
+	}
+
+	async fn session(&self, session_id: &Uuid) -> Option<SessionRef> {
+		self.sessions.read().await.get(session_id).cloned()
+	}
+
+	async fn remove_session(&self, session_id: &Uuid) -> Option<SessionRef> {
+		self.sessions.write().await.remove(session_id)
+	}
+
+	async fn session_count(&self) -> usize {
+		self.sessions.read().await.len()
+	}
+
+	async fn put_listing(&self, session_id: Uuid, listing: RustListing, max: Option<usize>) -> Result<bool> {
+		let mut listings = self.listings.write().await;
+
+		if !listings.by_session.contains_key(&session_id) && max.is_some_and(|max| listings.by_session.len() >= max) {
+			return Ok(false);
+		}
+
+		let listing_id = *listing.id();
+		if let Some(old) = listings.by_session.insert(session_id, listing)
+			&& *old.id() != listing_id
+		{
+			listings.id_map.remove(old.id());
+		}
+		listings.id_map.insert(listing_id, session_id);
+
+		Ok(true)
+	}
+
+	async fn listing_of(&self, session_id: &Uuid) -> Option<RustListing> {
+		self.listings.read().await.by_session.get(session_id).cloned()
+	}
+
+	async fn listing(&self, listing_id: &Uuid) -> Option<(Uuid, RustListing)> {
+		let listings = self.listings.read().await;
+		let session_id = listings.id_map.get(listing_id)?;
+		let listing = listings.by_session.get(session_id)?;
+		Some((*session_id, listing.clone()))
+	}
+
+	async fn remove_listing_of(&self, session_id: &Uuid) -> Result<Option<RustListing>> {
+		let mut listings = self.listings.write().await;
+
+		let listing = listings.by_session.remove(session_id);
+		if let Some(listing) = listing.as_ref() {
+			listings.id_map.remove(listing.id());
+		}
+
+		Ok(listing)
+	}
+
+	async fn listings(&self) -> Vec<RustListing> {
+		self.listings.read().await.by_session.values().cloned().collect()
+	}
+
+	async fn listing_count(&self) -> usize {
+		self.listings.read().await.by_session.len()
+	}
+}
+
+
+// -- FILE -- //
+
+/// A [`MemoryStore`] that writes its listings to a file on every change and reloads them on open,
+/// so a restart keeps every lobby. Sessions are not persisted; owners reclaim their listings with tokens signed by
+/// a session key the caller derives from a secret, see [`SessionKey::derive`], so the file never holds it.
+/// The file does hold the password hashes, so keep it private.
+pub struct FileStore {
+	memory: MemoryStore,
+	path: PathBuf,
+	session_key: SessionKey,
+	// serializes snapshots so an older one never overwrites a newer one //
+	save_lock: Mutex<()>,
+}
+
+impl FileStore {
+	/// Loads the listings in `path`, if it exists. `session_key` has to be the same on every open for owners to reclaim them.
+	pub async fn open(path: impl Into<PathBuf>, session_key: SessionKey) -> Result<Self> {
+		let path = path.into();
+		let memory = MemoryStore::default();
+
+		match fs::read(&path).await {
+			Ok(bytes) => {
+				let stored = StoredListings::decode(bytes.as_slice())
+					.map_err(|e| anyhow!("Malformed listing store {}: {e}", path.display()))?;
+
+				for stored in stored.listings {
+					let (session_id, listing) = decode(stored)?;
+					memory.put_listing(session_id, listing, None).await?;
+				}
+			},
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+			Err(e) => return Err(anyhow!("Unable to read listing store {}: {e}", path.display())),
+		}
+
+		Ok(Self { memory, path, session_key, save_lock: Mutex::new(()) })
+	}
+
+	async fn save(&self) -> Result<()> {
+		let _guard = self.save_lock.lock().await;
+
+		let listings = {
+			let listings = self.memory.listings.read().await;
+			listings
+				.by_session
+				.iter()
+				.map(|(session_id, listing)| encode(session_id, listing))
+				.collect()
+		};
+		let bytes = StoredListings { listings }.encode_to_vec();
+
+		// write then rename, so a crash never leaves a half written store //
+		let tmp = self.path.with_extension("tmp");
+		fs::write(&tmp, bytes)
+			.await
+			.map_err(|e| anyhow!("Unable to write listing store {}: {e}", tmp.display()))?;
+		fs::rename(&tmp, &self.path)
+			.await
+			.map_err(|e| anyhow!("Unable to replace listing store {}: {e}", self.path.display()))?;
+
+		Ok(())
+	}
+}
+
+#[tonic::async_trait]
+impl RendezvousStore for FileStore {
+	fn session_key(&self) -> Option<SessionKey> { Some(self.session_key.clone()) }
+
+	async fn insert_session(&self, session_id: Uuid, session: SessionRef, max: Option<usize>) -> bool {
+		self.memory.insert_session(session_id, session, max).await
+	}
+
+	async fn session(&self, session_id: &Uuid) -> Option<SessionRef> {
+		self.memory.session(session_id).await
+	}
+
+	async fn remove_session(&self, session_id: &Uuid) -> Option<SessionRef> {
+		self.memory.remove_session(session_id).await
+	}
+
+	async fn session_count(&self) -> usize {
+		self.memory.session_count().await
+	}
+
+	async fn put_listing(&self, session_id: Uuid, listing: RustListing, max: Option<usize>) -> Result<bool> {
+		if !self.memory.put_listing(session_id, listing, max).await? {
+			return Ok(false);
+		}
+		self.save().await?;
+		Ok(true)
+	}
+
+	async fn listing_of(&self, session_id: &Uuid) -> Option<RustListing> {
+		self.memory.listing_of(session_id).await
+	}
+
+	async fn listing(&self, listing_id: &Uuid) -> Option<(Uuid, RustListing)> {
+		self.memory.listing(listing_id).await
+	}
+
+	async fn remove_listing_of(&self, session_id: &Uuid) -> Result<Option<RustListing>> {
+		let listing = self.memory.remove_listing_of(session_id).await?;
+		if listing.is_some() {
+			self.save().await?;
+		}
+		Ok(listing)
+	}
+
+	async fn listings(&self) -> Vec<RustListing> {
+		self.memory.listings().await
+	}
+
+	async fn listing_count(&self) -> usize {
+		self.memory.listing_count().await
+	}
+}
+
+fn encode(session_id: &Uuid, listing: &RustListing) -> StoredListing {
+	StoredListing {
+		password_hash: listing.password_hash().map(str::to_string),
+		listing: Some(listing.clone().into()),
+		session_id: session_id.as_bytes().to_vec(),
+	}
+}
+
+fn decode(stored: StoredListing) -> Result<(Uuid, RustListing)> {
+	let session_id = stored
+		.session_id
+		.try_into()
+		.map_err(|e| anyhow!("Stored listing has a bad session id: {e}"))?;
+
+	let listing = RustListing::try_from(stored.listing.ok_or(anyhow!("Stored listing is empty"))?)?
+		.with_password_hash(stored.password_hash);
+
+	Ok((session_id, listing))
+}
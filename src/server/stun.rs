@@ -0,0 +1,32 @@
+use std::net::SocketAddr;
+use anyhow::Result;
+use tokio::net::UdpSocket;
+use tracing::warn;
+use crate::stun::{binding_response, parse_binding_request};
+
+const MAX_DATAGRAM: usize = 548;
+
+/// Standard STUN responder: answers Binding Requests with the observed address as XOR-MAPPED-ADDRESS.
+pub async fn run(addr: SocketAddr) -> Result<()> {
+	let socket = UdpSocket::bind(addr).await?;
+	let mut buf = [0u8; MAX_DATAGRAM];
+
+	loop {
+		let (len, src) = match socket.recv_from(&mut buf).await {
+			Ok(r) => r,
+			Err(e) => {
+				warn!(error = %e, "Stun receive error; continuing");
+				continue;
+			},
+		};
+
+		// silently drop anything that isnt a valid binding request //
+		let Ok(transaction_id) = parse_binding_request(&buf[..len]) else {
+			continue;
+		};
+
+		if let Err(e) = socket.send_to(&binding_response(&transaction_id, src), src).await {
+			warn!(%src, error = %e, "Unable to send stun response");
+		}
+	}
+}
@@ -0,0 +1,139 @@
+use std::{fmt, io, net::SocketAddr, path::{Path, PathBuf}, sync::{Arc, RwLock, Weak}, time::{Duration, SystemTime}};
+use anyhow::{anyhow, Result};
+use rustls::{crypto::aws_lc_rs, pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}, server::{ClientHello, ResolvesServerCert}, sign::CertifiedKey, ServerConfig};
+use tokio::{net::{TcpListener, TcpStream}, sync::mpsc, time::{sleep, timeout}};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, info, warn};
+
+/// Accepted connections, handshake done, for `Server::serve_with_incoming`.
+pub type TlsIncoming = ReceiverStream<io::Result<TlsStream<TcpStream>>>;
+
+/// Serves the certificate at `cert` with the key at `key`, both PEM, and reloads them when either file changes.
+///
+/// tonic's `ServerTlsConfig` can't swap certificates while running, so connections are terminated here instead.
+pub struct ReloadingCert {
+	cert: PathBuf,
+	key: PathBuf,
+	current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for ReloadingCert {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ReloadingCert").field("cert", &self.cert).field("key", &self.key).finish()
+	}
+}
+
+impl ReloadingCert {
+	/// Fails if the files can't be loaded now. Later reload failures keep the previous certificate.
+	pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>, poll: Duration) -> Result<Arc<Self>> {
+		let cert = cert.into();
+		let key = key.into();
+		let current = RwLock::new(Arc::new(load(&cert, &key)?));
+
+		let reloading = Arc::new(Self { cert, key, current });
+		tokio::spawn(watch(Arc::downgrade(&reloading), poll));
+
+		Ok(reloading)
+	}
+
+	fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+		let cert = std::fs::metadata(&self.cert).and_then(|m| m.modified()).ok()?;
+		let key = std::fs::metadata(&self.key).and_then(|m| m.modified()).ok()?;
+		Some((cert, key))
+	}
+}
+
+impl ResolvesServerCert for ReloadingCert {
+	fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+		Some(self.current.read().unwrap().clone())
+	}
+}
+
+/// Polls the files' modification times until the server drops the cert.
+async fn watch(reloading: Weak<ReloadingCert>, poll: Duration) {
+	let mut last = reloading.upgrade().and_then(|r| r.modified());
+
+	loop {
+		sleep(poll).await;
+		let Some(reloading) = reloading.upgrade() else { return };
+
+		let modified = reloading.modified();
+		if modified == last {
+			continue;
+		}
+
+		match load(&reloading.cert, &reloading.key) {
+			Ok(cert) => {
+				*reloading.current.write().unwrap() = Arc::new(cert);
+				last = modified;
+				info!(cert = %reloading.cert.display(), "Reloaded tls certificate");
+			},
+			// maybe caught between writing the cert and the key, try again next poll //
+			Err(e) => warn!(error = %e, "Unable to reload tls certificate, keeping the old one"),
+		}
+	}
+}
+
+fn load(cert: &Path, key: &Path) -> Result<CertifiedKey> {
+	let certs = CertificateDer::pem_file_iter(cert)
+		.map_err(|e| anyhow!("Unable to read certificate {}: {e}", cert.display()))?
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|e| anyhow!("Malformed certificate {}: {e}", cert.display()))?;
+
+	if certs.is_empty() {
+		return Err(anyhow!("No certificate in {}", cert.display()));
+	}
+
+	let private_key = PrivateKeyDer::from_pem_file(key)
+		.map_err(|e| anyhow!("Unable to read private key {}: {e}", key.display()))?;
+
+	let signing_key = aws_lc_rs::sign::any_supported_type(&private_key)
+		.map_err(|e| anyhow!("Unsupported private key: {e}"))?;
+
+	let certified = CertifiedKey::new(certs, signing_key);
+	certified
+		.keys_match()
+		.map_err(|e| anyhow!("Private key {} does not match the certificate: {e}", key.display()))?;
+
+	Ok(certified)
+}
+
+/// Accepts on `addr` and handshakes each connection in its own task, so a slow client can't stall the others.
+/// Connections still handshaking after `handshake_timeout` are dropped.
+pub async fn incoming(addr: SocketAddr, cert: Arc<ReloadingCert>, handshake_timeout: Duration) -> Result<TlsIncoming> {
+	let mut config = ServerConfig::builder()
+		.with_no_client_auth()
+		.with_cert_resolver(cert);
+	// grpc over h2, grpc-web over http/1.1 //
+	config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+	let acceptor = TlsAcceptor::from(Arc::new(config));
+	let listener = TcpListener::bind(addr).await?;
+	let (tx, rx) = mpsc::channel(32);
+
+	tokio::spawn(async move {
+		// stops once the server drops the incoming stream //
+		while !tx.is_closed() {
+			let stream = match listener.accept().await {
+				Ok((stream, _)) => stream,
+				Err(e) => {
+					warn!(error = %e, "Tls accept error; continuing");
+					continue;
+				},
+			};
+
+			let acceptor = acceptor.clone();
+			let tx = tx.clone();
+			tokio::spawn(async move {
+				match timeout(handshake_timeout, acceptor.accept(stream)).await {
+					Ok(Ok(stream)) => { let _ = tx.send(Ok(stream)).await; },
+					Ok(Err(e)) => debug!(error = %e, "Tls handshake failed"),
+					Err(_) => debug!("Tls handshake timed out"),
+				}
+			});
+		}
+	});
+
+	Ok(ReceiverStream::new(rx))
+}
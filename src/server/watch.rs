@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use tokio::sync::{broadcast::{self, error::RecvError}, mpsc};
+use tonic::Status;
+use uuid::Uuid;
+use tracing::debug;
+use crate::{proto::{listing_event::ListingEventEnum, ListingEvent, ListingSnapshot}, server::{listing::RustListing, store::RendezvousStore}};
+
+/// How many changes a watcher may fall behind before it is sent a fresh snapshot.
+pub const EVENT_BUFFER: usize = 64;
+
+// ---- RUST ---- //
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RustListingEvent {
+	/// Replaces everything seen so far.
+	Snapshot(Vec<RustListing>),
+	Added(RustListing),
+	Updated(RustListing),
+	Removed(Uuid),
+}
+
+impl RustListingEvent {
+	/// Applies the event to a local copy of the listings. Deltas may repeat what the snapshot already holds.
+	pub fn apply(self, listings: &mut Vec<RustListing>) {
+		match self {
+			Self::Snapshot(snapshot) => *listings = snapshot,
+			Self::Added(listing) | Self::Updated(listing) => {
+				match listings.iter_mut().find(|l| l.id() == listing.id()) {
+					Some(l) => *l = listing,
+					None => listings.push(listing),
+				}
+			},
+			Self::Removed(id) => listings.retain(|l| *l.id() != id),
+		}
+	}
+}
+
+impl TryFrom<ListingEvent> for RustListingEvent {
+	type Error = anyhow::Error;
+
+	fn try_from(event: ListingEvent) -> Result<Self> {
+		let event = event
+			.listing_event_enum
+			.ok_or(anyhow!("Empty listing event"))?;
+
+		Ok(match event {
+			ListingEventEnum::Snapshot(snapshot) => Self::Snapshot(
+				snapshot
+					.listings
+					.into_iter()
+					.filter_map(|l| l.try_into().ok())
+					.collect()
+			),
+			ListingEventEnum::Added(listing) => Self::Added(listing.try_into()?),
+			ListingEventEnum::Updated(listing) => Self::Updated(listing.try_into()?),
+			ListingEventEnum::Removed(id) => Self::Removed(id.try_into().map_err(|e| anyhow!("Invalid listing Uuid: {e}"))?),
+		})
+	}
+}
+
+
+// ---- SERVER ---- //
+
+pub fn added(listing: &RustListing) -> ListingEvent {
+	ListingEvent { listing_event_enum: Some(ListingEventEnum::Added(listing.clone().into())) }
+}
+
+pub fn updated(listing: &RustListing) -> ListingEvent {
+	ListingEvent { listing_event_enum: Some(ListingEventEnum::Updated(listing.clone().into())) }
+}
+
+pub fn removed(id: &Uuid) -> ListingEvent {
+	ListingEvent { listing_event_enum: Some(ListingEventEnum::Removed(id.as_bytes().to_vec())) }
+}
+
+/// Sends a snapshot, then every change from `events` until the watcher goes away.
+/// `events` must be subscribed before the snapshot is taken so nothing falls in between.
+pub async fn forward(
+	store: Arc<dyn RendezvousStore>,
+	mut events: broadcast::Receiver<ListingEvent>,
+	tx: mpsc::Sender<Result<ListingEvent, Status>>,
+) {
+	loop {
+		let listings = store.listings().await;
+		let snapshot = ListingEvent {
+			listing_event_enum: Some(ListingEventEnum::Snapshot(ListingSnapshot {
+				listings: listings.into_iter().map(Into::into).collect(),
+			})),
+		};
+
+		if tx.send(Ok(snapshot)).await.is_err() {
+			return;
+		}
+
+		loop {
+			let event = tokio::select! {
+				event = events.recv() => event,
+				_ = tx.closed() => return,
+			};
+
+			match event {
+				Ok(event) => {
+					if tx.send(Ok(event)).await.is_err() {
+						return;
+					}
+				},
+				// resync with a new snapshot //
+				Err(RecvError::Lagged(n)) => {
+					debug!(behind = n, "Listing watcher fell behind; resending snapshot");
+					break;
+				},
+				Err(RecvError::Closed) => return,
+			}
+		}
+	}
+}
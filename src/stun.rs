@@ -0,0 +1,170 @@
+//! Minimal RFC 5389 codec: Binding requests and XOR-MAPPED-ADDRESS responses.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use anyhow::{anyhow, bail, Result};
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+pub const HEADER_LEN: usize = 20;
+
+pub const BINDING_REQUEST: u16 = 0x0001;
+pub const BINDING_SUCCESS: u16 = 0x0101;
+
+pub const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+pub const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+const FAMILY_V4: u8 = 0x01;
+const FAMILY_V6: u8 = 0x02;
+
+pub type TransactionId = [u8; 12];
+
+pub fn new_transaction_id() -> TransactionId {
+	rand::random()
+}
+
+// -- ENCODING -- //
+
+pub fn binding_request(transaction_id: &TransactionId) -> Vec<u8> {
+	header(BINDING_REQUEST, 0, transaction_id)
+}
+
+pub fn binding_response(transaction_id: &TransactionId, addr: SocketAddr) -> Vec<u8> {
+	let value = xor_address(transaction_id, addr);
+
+	let mut msg = header(BINDING_SUCCESS, 4 + value.len() as u16, transaction_id);
+	msg.extend_from_slice(&ATTR_XOR_MAPPED_ADDRESS.to_be_bytes());
+	msg.extend_from_slice(&(value.len() as u16).to_be_bytes());
+	msg.extend_from_slice(&value);
+	msg
+}
+
+fn header(msg_type: u16, len: u16, transaction_id: &TransactionId) -> Vec<u8> {
+	let mut msg = Vec::with_capacity(HEADER_LEN + len as usize);
+	msg.extend_from_slice(&msg_type.to_be_bytes());
+	msg.extend_from_slice(&len.to_be_bytes());
+	msg.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+	msg.extend_from_slice(transaction_id);
+	msg
+}
+
+/// XOR-MAPPED-ADDRESS value; the port is xored with the cookie's high half, v6 addresses with cookie || transaction id.
+fn xor_address(transaction_id: &TransactionId, addr: SocketAddr) -> Vec<u8> {
+	let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+	let mask = xor_mask(transaction_id);
+
+	let mut value = Vec::with_capacity(20);
+	value.push(0);
+	match addr.ip() {
+		IpAddr::V4(ip) => {
+			value.push(FAMILY_V4);
+			value.extend_from_slice(&port.to_be_bytes());
+			value.extend(ip.octets().iter().zip(mask).map(|(b, m)| b ^ m));
+		},
+		IpAddr::V6(ip) => {
+			value.push(FAMILY_V6);
+			value.extend_from_slice(&port.to_be_bytes());
+			value.extend(ip.octets().iter().zip(mask).map(|(b, m)| b ^ m));
+		},
+	}
+	value
+}
+
+fn xor_mask(transaction_id: &TransactionId) -> [u8; 16] {
+	let mut mask = [0u8; 16];
+	mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+	mask[4..].copy_from_slice(transaction_id);
+	mask
+}
+
+// -- DECODING -- //
+
+/// Validates a Binding Request and returns its transaction id.
+pub fn parse_binding_request(msg: &[u8]) -> Result<TransactionId> {
+	let (msg_type, transaction_id, _) = parse_header(msg)?;
+	if msg_type != BINDING_REQUEST {
+		bail!("Not a binding request: {msg_type:#06x}");
+	}
+	Ok(transaction_id)
+}
+
+/// Validates a Binding Success Response against `expected` and returns the mapped address.
+pub fn parse_binding_response(msg: &[u8], expected: &TransactionId) -> Result<SocketAddr> {
+	let (msg_type, transaction_id, mut attrs) = parse_header(msg)?;
+	if msg_type != BINDING_SUCCESS {
+		bail!("Not a binding success response: {msg_type:#06x}");
+	}
+	if transaction_id != *expected {
+		bail!("Transaction id mismatch");
+	}
+
+	let mut mapped = None;
+	while attrs.len() >= 4 {
+		let attr_type = u16::from_be_bytes([attrs[0], attrs[1]]);
+		let attr_len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+		let padded = attr_len.next_multiple_of(4);
+		let value = attrs
+			.get(4..4 + attr_len)
+			.ok_or(anyhow!("Truncated attribute {attr_type:#06x}"))?;
+
+		match attr_type {
+			ATTR_XOR_MAPPED_ADDRESS => return parse_address(value, Some(&transaction_id)),
+			ATTR_MAPPED_ADDRESS => mapped = Some(parse_address(value, None)?),
+			_ => {},
+		}
+
+		attrs = attrs.get(4 + padded..).unwrap_or_default();
+	}
+
+	mapped.ok_or(anyhow!("Response has no mapped address"))
+}
+
+fn parse_header(msg: &[u8]) -> Result<(u16, TransactionId, &[u8])> {
+	if msg.len() < HEADER_LEN {
+		bail!("Message shorter than stun header");
+	}
+
+	let msg_type = u16::from_be_bytes([msg[0], msg[1]]);
+	if msg_type & 0xC000 != 0 {
+		bail!("Leading bits set, not a stun message");
+	}
+
+	let len = u16::from_be_bytes([msg[2], msg[3]]) as usize;
+	if !len.is_multiple_of(4) || HEADER_LEN + len != msg.len() {
+		bail!("Bad stun message length: {len}");
+	}
+
+	let cookie = u32::from_be_bytes([msg[4], msg[5], msg[6], msg[7]]);
+	if cookie != MAGIC_COOKIE {
+		bail!("Bad magic cookie: {cookie:#010x}");
+	}
+
+	let mut transaction_id = TransactionId::default();
+	transaction_id.copy_from_slice(&msg[8..HEADER_LEN]);
+
+	Ok((msg_type, transaction_id, &msg[HEADER_LEN..]))
+}
+
+/// Parses a (XOR-)MAPPED-ADDRESS value; `xor` carries the transaction id when the value is xored.
+fn parse_address(value: &[u8], xor: Option<&TransactionId>) -> Result<SocketAddr> {
+	if value.len() < 4 {
+		bail!("Address attribute too short");
+	}
+
+	let mask = xor.map_or([0u8; 16], xor_mask);
+	let port_mask = if xor.is_some() { (MAGIC_COOKIE >> 16) as u16 } else { 0 };
+	let port = u16::from_be_bytes([value[2], value[3]]) ^ port_mask;
+
+	let ip = match (value[1], &value[4..]) {
+		(FAMILY_V4, ip) if ip.len() == 4 => {
+			let mut octets = [0u8; 4];
+			octets.iter_mut().zip(ip).zip(mask).for_each(|((o, b), m)| *o = b ^ m);
+			IpAddr::V4(Ipv4Addr::from(octets))
+		},
+		(FAMILY_V6, ip) if ip.len() == 16 => {
+			let mut octets = [0u8; 16];
+			octets.iter_mut().zip(ip).zip(mask).for_each(|((o, b), m)| *o = b ^ m);
+			IpAddr::V6(Ipv6Addr::from(octets))
+		},
+		(family, _) => bail!("Bad address family or length: {family:#04x}"),
+	};
+
+	Ok(SocketAddr::new(ip, port))
+}
@@ -1,7 +1,11 @@
-use crate::{client::{punch, Client}, server::{listing::RustListingNoId, run}};
-use tokio::net::UdpSocket;
-use tonic::transport::Uri;
-use std::net::SocketAddr;
+use crate::{client::{punch_with, stun_query, Client, ClientOptions, JoinOptions, Transport, Trust, UpdateOptions}, proto::{node_service_client::NodeServiceClient, BindingRequest, Capability, Fallback, ListingSort, NodePunchRequest}, server::{self, auth::SessionKey, cluster::NodeSecret, config::{Cli, ServerConfig}, listing::{RustListingNoId, MAX_PASSWORD_LEN, MAX_TEXT_LEN}, query::RustListingQuery, relay::{Relay, RelayLimits}, run, store::{FileStore, RendezvousStore}, watch::RustListingEvent}};
+use tokio::{net::{TcpStream, UdpSocket}, time::{sleep, timeout}};
+use tokio_stream::StreamExt;
+use clap::{CommandFactory, FromArgMatches};
+use prost::Message;
+use tonic::{transport::{Channel, Uri}, Code};
+use uuid::Uuid;
+use std::{net::{Ipv4Addr, SocketAddr}, sync::Arc, time::Duration};
 
 // -- UTIL -- //
 async fn local_addr() -> SocketAddr {
@@ -11,18 +15,34 @@ async fn local_addr() -> SocketAddr {
 
 async fn test_server() -> SocketAddr {
 	let addr = local_addr().await;
-	tokio::spawn(run(addr));
+	tokio::spawn(run(test_config(addr)));
+	wait_for(addr).await;
 	addr
 }
 
-async fn test_client(addr: SocketAddr) -> Client {
-	let uri = Uri::builder()
-		.scheme("https")
+fn test_config(addr: SocketAddr) -> ServerConfig {
+	ServerConfig { host: addr.ip(), port: addr.port(), ..Default::default() }
+}
+
+/// Waits until the server on `addr` is accepting.
+async fn wait_for(addr: SocketAddr) {
+	while TcpStream::connect(addr).await.is_err() {
+		sleep(Duration::from_millis(10)).await;
+	}
+}
+
+fn test_uri(addr: SocketAddr) -> Uri {
+	Uri::builder()
+		.scheme("http")
 		.authority(addr.to_string())
 		.path_and_query("/")
 		.build()
-		.unwrap();
-	Client::new(uri).await.unwrap()
+		.unwrap()
+}
+
+/// Accepts joins, as most tests don't care to decide them.
+async fn test_client(addr: SocketAddr) -> Client {
+	Client::with_options(test_uri(addr), ClientOptions { transport: Transport::GrpcWeb, ..Default::default() }).await.unwrap()
 }
 
 // -- TESTS -- //
@@ -36,7 +56,7 @@ async fn test_client(addr: SocketAddr) -> Client {
 	let _ = c_1.start_session().await.unwrap();
 	let _ = c_2.start_session().await.unwrap();
 
-	let listing = RustListingNoId { name: "test listing".to_string() };
+	let listing = RustListingNoId { name: "test listing".to_string(), ..Default::default() };
 	let l = listing.clone();
 	c_1.create_listing(listing).await.unwrap(); 
 
@@ -46,7 +66,7 @@ async fn test_client(addr: SocketAddr) -> Client {
 	let target_listing = &listings[0];
 	assert_eq!(*target_listing.inner(), l);
 
-	let listing = RustListingNoId { name: "test listing".to_string() };
+	let listing = RustListingNoId { name: "test listing".to_string(), ..Default::default() };
 	c_2.create_listing(listing).await.unwrap();
 
 	let listings = c_2.get_listings().await.unwrap();
@@ -60,18 +80,78 @@ async fn test_client(addr: SocketAddr) -> Client {
 }
 
 #[tokio::test]
+#[allow(deprecated)]
 /* AI */ async fn punching() {
-	let bind_a = local_addr().await;
-	let bind_b = local_addr().await;
+	let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	let bind_b = peer.local_addr().unwrap();
+
+	// the peer answers whoever punches it //
+	let answer = async {
+		let mut buf = [0u8; 5];
+		let (_, src) = peer.recv_from(&mut buf).await?;
+		peer.send_to(b"punch", src).await
+	};
+	
+	let (a, b) = tokio::join!(
+		crate::client::punch(bind_b),
+		answer,
+	);
+
+	assert!(a.is_ok() && b.is_ok(), "a: {a:?}, \nb: {b:?}");
+}
+
+#[tokio::test]
+async fn punching_with() {
+	let socket_a = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+	let socket_b = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+	let addr_a = socket_a.local_addr().unwrap();
+	let addr_b = socket_b.local_addr().unwrap();
 	
 	let (a, b) = tokio::join!(
-		punch(bind_a),
-		punch(bind_b),
+		punch_with(socket_a, addr_b),
+		punch_with(socket_b, addr_a),
 	);
 
 	assert!(a.is_ok() && b.is_ok(), "a: {a:?}, \nb: {b:?}");
 }
 
+#[tokio::test]
+async fn shared_socket() {
+	let socket_a = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+	let socket_b = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+	let addr_a = socket_a.local_addr().unwrap();
+	let addr_b = socket_b.local_addr().unwrap();
+
+	// the game's traffic is queued on its socket ahead of the punches //
+	let game = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	game.send_to(b"game", addr_a).await.unwrap();
+
+	// and read by the game a while later, leaving the peer's datagrams to the punch //
+	let reader = socket_a.clone();
+	let game_read = tokio::spawn(async move {
+		sleep(Duration::from_millis(100)).await;
+		let mut buf = [0u8; 8];
+		loop {
+			if reader.peek_sender().await.unwrap() == addr_b {
+				sleep(Duration::from_millis(5)).await;
+				continue;
+			}
+			let (len, src) = reader.recv_from(&mut buf).await.unwrap();
+			return (buf[..len].to_vec(), src);
+		}
+	});
+
+	let (a, b) = tokio::join!(
+		punch_with(socket_a, addr_b),
+		punch_with(socket_b, addr_a),
+	);
+	assert!(a.is_ok() && b.is_ok(), "a: {a:?}, \nb: {b:?}");
+
+	let (data, src) = timeout(Duration::from_secs(1), game_read).await.unwrap().unwrap();
+	assert_eq!(data, b"game");
+	assert_eq!(src, game.local_addr().unwrap());
+}
+
 #[tokio::test]
 async fn client_punching() {
 	let s_addr = test_server().await;
@@ -81,7 +161,7 @@ async fn client_punching() {
 	let dst_1 = c_1.start_session().await.unwrap();
 	let dst_2 = c_2.start_session().await.unwrap();
 	
-	let listing = RustListingNoId { name: "test listing".to_string() };
+	let listing = RustListingNoId { name: "test listing".to_string(), ..Default::default() };
 	c_1.create_listing(listing).await.unwrap();
 
 	let listings = c_2.get_listings().await.unwrap();
@@ -92,8 +172,1263 @@ async fn client_punching() {
 	assert!(dst_1.is_empty());
 	assert!(dst_2.is_empty());
 
-	c_2.join(*target_listing.id()).await.unwrap();
+	let outcome = c_2.join(*target_listing.id()).await.unwrap();
+	assert!(outcome.joiner.success && outcome.host.success, "{outcome:?}");
+	assert_eq!(outcome.fallback, Fallback::None);
 
 	assert!(!dst_1.is_empty());
 	assert!(!dst_2.is_empty());
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn reflector() {
+	let s_addr = test_server().await;
+	let mut c = test_client(s_addr).await;
+
+	let _ = c.start_session().await.unwrap();
+
+	let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	let reflected = c.register_udp(&socket).await.unwrap();
+
+	assert_eq!(reflected, socket.local_addr().unwrap());
+
+	// a bare or forged token doesn't move the session's punch address, and isn't answered //
+	let session_id = c.session().as_ref().unwrap().uuid();
+	let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	for session_token in [session_id.to_string(), SessionKey::default().issue(&session_id)] {
+		spoofer.send_to(&BindingRequest { session_token }.encode_to_vec(), s_addr).await.unwrap();
+	}
+	let mut buf = [0u8; 64];
+	assert!(timeout(Duration::from_millis(300), spoofer.recv_from(&mut buf)).await.is_err());
+}
+
+#[tokio::test]
+async fn stun() {
+	let s_addr = local_addr().await;
+	tokio::spawn(server::stun::run(s_addr));
+
+	let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	let mapped = stun_query(&socket, s_addr).await.unwrap();
+
+	assert_eq!(mapped, socket.local_addr().unwrap());
+}
+
+#[test]
+fn stun_codec() {
+	use crate::stun::{binding_request, binding_response, new_transaction_id, parse_binding_request, parse_binding_response};
+
+	let id = new_transaction_id();
+	let v6: SocketAddr = "[2001:db8::1]:40000".parse().unwrap();
+	let response = binding_response(&id, v6);
+
+	// v6 addresses are xored with the transaction id too //
+	assert_eq!(parse_binding_response(&response, &id).unwrap(), v6);
+	assert!(parse_binding_response(&response, &new_transaction_id()).is_err());
+	assert_eq!(parse_binding_request(&binding_request(&id)).unwrap(), id);
+	assert!(parse_binding_request(&response).is_err());
+
+	// attributes it doesn't know are skipped, padding included //
+	let mut unknown = response[..20].to_vec();
+	unknown.extend_from_slice(&[0x80, 0x22, 0x00, 0x05, b'p', b'u', b'n', b'c', b'h', 0, 0, 0]);
+	unknown.extend_from_slice(&response[20..]);
+	let len = (unknown.len() - 20) as u16;
+	unknown[2..4].copy_from_slice(&len.to_be_bytes());
+	assert_eq!(parse_binding_response(&unknown, &id).unwrap(), v6);
+
+	// malformed messages are refused, not panicked on //
+	assert!(parse_binding_response(&response[..12], &id).is_err());
+	assert!(parse_binding_response(&response[..response.len() - 4], &id).is_err());
+
+	let mut bad_cookie = response.clone();
+	bad_cookie[4] ^= 0xFF;
+	assert!(parse_binding_response(&bad_cookie, &id).is_err());
+
+	let mut leading_bits = response.clone();
+	leading_bits[0] |= 0xC0;
+	assert!(parse_binding_response(&leading_bits, &id).is_err());
+
+	// an attribute running past the end of the message //
+	let mut truncated = response.clone();
+	truncated[22..24].copy_from_slice(&64u16.to_be_bytes());
+	assert!(parse_binding_response(&truncated, &id).is_err());
+
+	// a v6 family with a v4 sized address //
+	let mut short_v6 = binding_response(&id, "203.0.113.5:3478".parse().unwrap());
+	short_v6[25] = 0x02;
+	assert!(parse_binding_response(&short_v6, &id).is_err());
+}
+
+#[tokio::test]
+async fn handshake() {
+	let s_addr = test_server().await;
+	let mut c = test_client(s_addr).await;
+
+	let _ = c.start_session().await.unwrap();
+
+	let session = c.session().as_ref().unwrap();
+	assert!(!session.uuid().is_nil());
+	assert!(session.has(Capability::UdpReflector));
+
+	// joins are waited on for as long as the server says they take //
+	assert_eq!(session.join_timeout(), ServerConfig::default().timeout() * 6);
+}
+
+#[tokio::test]
+async fn relay() {
+	let relay = Relay::new(Ipv4Addr::LOCALHOST.into(), RelayLimits::default());
+	let localhost = Ipv4Addr::LOCALHOST.into();
+	let allocation = relay.allocate([Uuid::new_v4(), Uuid::new_v4()], localhost, localhost).await.unwrap();
+
+	let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+	// both sides latch //
+	a.send_to(b"latch", allocation.addr_a).await.unwrap();
+	sleep(Duration::from_millis(50)).await;
+	b.send_to(b"latch", allocation.addr_b).await.unwrap();
+	sleep(Duration::from_millis(50)).await;
+
+	a.send_to(b"data", allocation.addr_a).await.unwrap();
+
+	let mut buf = [0u8; 16];
+	let (len, src) = timeout(Duration::from_secs(1), b.recv_from(&mut buf)).await.unwrap().unwrap();
+	assert_eq!(&buf[..len], b"data");
+	assert_eq!(src, allocation.addr_b);
+
+	// loopback is never advertised, peers fall back to the grpc server's host //
+	assert_eq!(relay.public_ip(), None);
+	let public_ip = Ipv4Addr::new(203, 0, 113, 7).into();
+	assert_eq!(relay.with_public_ip(public_ip).public_ip(), Some(public_ip));
+	let bound = Ipv4Addr::new(10, 0, 0, 5).into();
+	assert_eq!(Relay::new(bound, RelayLimits::default()).public_ip(), Some(bound));
+}
+
+#[tokio::test]
+async fn relay_limits() {
+	let limits = RelayLimits { max_allocations: 3, max_allocations_per_session: 2, ..Default::default() };
+	let relay = Relay::new(Ipv4Addr::LOCALHOST.into(), limits);
+	let localhost = Ipv4Addr::LOCALHOST.into();
+	let (joiner_a, joiner_b, host, other_host) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+	relay.allocate([joiner_a, host], localhost, localhost).await.unwrap();
+	relay.allocate([joiner_a, host], localhost, localhost).await.unwrap();
+	assert!(relay.allocate([joiner_a, other_host], localhost, localhost).await.is_err());
+
+	// the host's share counts too //
+	assert!(relay.allocate([joiner_b, host], localhost, localhost).await.is_err());
+
+	// other sessions only get what's left globally //
+	relay.allocate([joiner_b, other_host], localhost, localhost).await.unwrap();
+	assert!(relay.allocate([Uuid::new_v4(), Uuid::new_v4()], localhost, localhost).await.is_err());
+
+	// the host ending frees its allocations for the joiner too //
+	relay.release(&host);
+	sleep(Duration::from_millis(50)).await;
+	relay.allocate([joiner_a, other_host], localhost, localhost).await.unwrap();
+	relay.allocate([joiner_a, Uuid::new_v4()], localhost, localhost).await.unwrap();
+}
+
+#[tokio::test]
+async fn join_approval() {
+	let s_addr = test_server().await;
+	let mut host = test_client(s_addr).await;
+	let mut joiner = test_client(s_addr).await;
+
+	let _ = host.start_session().await.unwrap();
+	let _ = joiner.start_session().await.unwrap();
+
+	let listing = RustListingNoId { name: "test listing".to_string(), ..Default::default() };
+	let listing_id = host.create_listing(listing).await.unwrap();
+
+	// nobody deciding, and no auto accept //
+	let options = ClientOptions { transport: Transport::GrpcWeb, auto_accept: false, ..Default::default() };
+	let mut strict = Client::with_options(test_uri(s_addr), options).await.unwrap();
+	let _ = strict.start_session().await.unwrap();
+	let strict_id = strict.create_listing(RustListingNoId { name: "strict".to_string(), ..Default::default() }).await.unwrap();
+	assert!(joiner.join(strict_id).await.is_err());
+
+	let mut requests = host.join_requests().unwrap();
+	tokio::spawn(async move {
+		while let Some(request) = requests.recv().await {
+			if request.payload == b"let me in" {
+				request.accept();
+			} else {
+				request.reject("wrong payload");
+			}
+		}
+	});
+
+	let rejected = joiner.join_with(listing_id, JoinOptions { payload: b"hello".to_vec(), ..Default::default() }).await;
+	assert!(rejected.is_err());
+
+	let accepted = joiner.join_with(listing_id, JoinOptions { payload: b"let me in".to_vec(), ..Default::default() }).await.unwrap();
+	assert!(accepted.connected(), "{accepted:?}");
+}
+
+#[tokio::test]
+async fn password() {
+	let s_addr = test_server().await;
+	let mut host = test_client(s_addr).await;
+	let mut joiner = test_client(s_addr).await;
+
+	let _ = host.start_session().await.unwrap();
+	let _ = joiner.start_session().await.unwrap();
+
+	let listing = RustListingNoId { name: "locked".to_string(), password: Some("hunter2".to_string()), ..Default::default() };
+	let listing_id = host.create_listing(listing).await.unwrap();
+
+	let listings = joiner.get_listings().await.unwrap();
+	assert!(listings[0].has_password());
+	assert_eq!(listings[0].inner().password, None);
+
+	assert!(joiner.join(listing_id).await.is_err());
+
+	let wrong = JoinOptions { password: Some("hunter3".to_string()), ..Default::default() };
+	assert!(joiner.join_with(listing_id, wrong).await.is_err());
+
+	let right = JoinOptions { password: Some("hunter2".to_string()), ..Default::default() };
+	assert!(joiner.join_with(listing_id, right).await.unwrap().connected());
+}
+
+#[tokio::test]
+async fn update_listing() {
+	let s_addr = test_server().await;
+	let mut c = test_client(s_addr).await;
+
+	let _ = c.start_session().await.unwrap();
+
+	let mut listing = RustListingNoId {
+		name: "test listing".to_string(),
+		game_mode: "ctf".to_string(),
+		current_players: 1,
+		max_players: 4,
+		attributes: [("ranked".to_string(), vec![1])].into(),
+		..Default::default()
+	};
+	let listing_id = c.create_listing(listing.clone()).await.unwrap();
+
+	listing.current_players = 3;
+	c.update_listing(listing.clone()).await.unwrap();
+
+	let listings = c.get_listings().await.unwrap();
+	assert_eq!(*listings[0].id(), listing_id);
+	assert_eq!(*listings[0].inner(), listing);
+
+	// a password sticks through updates without one, until cleared //
+	c.update_listing(RustListingNoId { password: Some("hunter2".to_string()), ..listing.clone() }).await.unwrap();
+	c.update_listing(listing.clone()).await.unwrap();
+	assert!(c.get_listings().await.unwrap()[0].has_password());
+
+	let clear = UpdateOptions { clear_password: true };
+	let both = RustListingNoId { password: Some("hunter3".to_string()), ..listing.clone() };
+	assert!(c.update_listing_with(both, clear.clone()).await.is_err());
+	c.update_listing_with(listing.clone(), clear).await.unwrap();
+	assert!(!c.get_listings().await.unwrap()[0].has_password());
+
+	// limits //
+	listing.current_players = 5;
+	assert!(c.update_listing(listing.clone()).await.is_err());
+
+	listing.current_players = 1;
+	listing.name = "x".repeat(MAX_TEXT_LEN + 1);
+	assert!(c.update_listing(listing.clone()).await.is_err());
+
+	listing.name = "limits".to_string();
+	listing.password = Some("x".repeat(MAX_PASSWORD_LEN + 1));
+	assert!(c.update_listing(listing).await.is_err());
+}
+
+#[tokio::test]
+async fn query_listings() {
+	let s_addr = test_server().await;
+
+	// one listing per session //
+	let mut clients = Vec::new();
+	for (name, players) in [("alpha", 1), ("beta", 2), ("gamma", 3), ("delta", 4)] {
+		let mut c = test_client(s_addr).await;
+		let _ = c.start_session().await.unwrap();
+		let listing = RustListingNoId { name: name.to_string(), current_players: players, version: "1.0".to_string(), ..Default::default() };
+		c.create_listing(listing).await.unwrap();
+		clients.push(c);
+	}
+	let c = &mut clients[0];
+
+	let query = RustListingQuery { name_contains: Some("ETA".to_string()), ..Default::default() };
+	let page = c.query_listings(query).await.unwrap();
+	assert_eq!(page.listings.len(), 1);
+	assert_eq!(page.listings[0].inner().name, "beta");
+
+	let query = RustListingQuery { min_players: Some(2), max_players: Some(3), ..Default::default() };
+	assert_eq!(c.query_listings(query).await.unwrap().listings.len(), 2);
+
+	// walk pages of 3 by players descending //
+	let mut query = RustListingQuery { sort: ListingSort::Players, descending: true, page_size: 3, ..Default::default() };
+	let first = c.query_listings(query.clone()).await.unwrap();
+	let players: Vec<u32> = first.listings.iter().map(|l| l.inner().current_players).collect();
+	assert_eq!(players, [4, 3, 2]);
+
+	query.cursor = first.next_cursor;
+	let second = c.query_listings(query).await.unwrap();
+	assert_eq!(second.listings.len(), 1);
+	assert_eq!(second.listings[0].inner().current_players, 1);
+	assert_eq!(second.next_cursor, None);
+}
+
+#[tokio::test]
+async fn watch_listings() {
+	let s_addr = test_server().await;
+	let mut host = test_client(s_addr).await;
+	let mut other = test_client(s_addr).await;
+	let watcher = test_client(s_addr).await;
+
+	let _ = host.start_session().await.unwrap();
+	let _ = other.start_session().await.unwrap();
+
+	let mut listing = RustListingNoId { name: "test listing".to_string(), max_players: 4, ..Default::default() };
+	let listing_id = host.create_listing(listing.clone()).await.unwrap();
+
+	let mut events = watcher.watch_listings().await.unwrap();
+	let mut next = async || timeout(Duration::from_secs(5), events.next()).await.unwrap().unwrap().unwrap();
+
+	let RustListingEvent::Snapshot(snapshot) = next().await else { panic!("expected a snapshot first") };
+	assert_eq!(snapshot.len(), 1);
+	assert_eq!(*snapshot[0].id(), listing_id);
+
+	listing.current_players = 2;
+	host.update_listing(listing.clone()).await.unwrap();
+	let RustListingEvent::Updated(updated) = next().await else { panic!("expected an update") };
+	assert_eq!(*updated.inner(), listing);
+
+	let other_id = other.create_listing(RustListingNoId { name: "other".to_string(), ..Default::default() }).await.unwrap();
+	assert!(matches!(next().await, RustListingEvent::Added(l) if *l.id() == other_id));
+
+	host.remove_listing().await.unwrap();
+	assert_eq!(next().await, RustListingEvent::Removed(listing_id));
+
+	// ending the session removes its listing too //
+	other.end_session();
+	assert_eq!(next().await, RustListingEvent::Removed(other_id));
+}
+
+#[tokio::test]
+async fn file_store() {
+	use tokio::sync::oneshot;
+	use crate::client::{Backoff, ConnectionState};
+
+	let path = std::env::temp_dir().join(format!("nat_puncher_{}.store", Uuid::new_v4()));
+	let copy = path.with_extension("copy");
+	let reclaim = path.with_extension("reclaim");
+	let key = || SessionKey::derive(b"store secret");
+
+	let s_addr = local_addr().await;
+	let config = ServerConfig { shutdown_retry_secs: 1, ..test_config(s_addr) };
+	let (stop, signal) = oneshot::channel::<()>();
+	let server = server::PuncherServer::default().with_store(FileStore::open(&path, key()).await.unwrap());
+	let first = tokio::spawn(server::serve_with_shutdown(config.clone(), server, async { let _ = signal.await; }));
+	wait_for(s_addr).await;
+
+	let options = ClientOptions {
+		reconnect: Backoff { initial: Duration::from_millis(100), max: Duration::from_millis(100), attempts: 8 },
+		..Default::default()
+	};
+	let mut host = Client::with_options(test_uri(s_addr), options).await.unwrap();
+	let _ = host.start_session().await.unwrap();
+	let host_id = host.session().as_ref().unwrap().uuid();
+	let listing = RustListingNoId { name: "persisted".to_string(), password: Some("hunter2".to_string()), ..Default::default() };
+	let listing_id = host.create_listing(listing).await.unwrap();
+	std::fs::copy(&path, &copy).unwrap();
+	std::fs::copy(&path, &reclaim).unwrap();
+
+	// the key tokens are signed with is derived again, never written //
+	let stored = std::fs::read(&path).unwrap();
+	assert!(!stored.windows(32).any(|w| w == key().to_bytes()));
+
+	// restarted on the same file, the host reclaims its listing with its old token //
+	let mut connection = host.connection().unwrap();
+	stop.send(()).unwrap();
+	timeout(Duration::from_secs(10), first).await.unwrap().unwrap().unwrap();
+	timeout(Duration::from_secs(5), connection.wait_for(|s| matches!(s, ConnectionState::Reconnecting { .. }))).await.unwrap().unwrap();
+
+	// back before the host runs out of reconnect attempts //
+	let store = FileStore::open(&path, key()).await.unwrap();
+	let (_, stored) = store.listing(&listing_id).await.unwrap();
+	tokio::spawn(server::serve(config, server::PuncherServer::default().with_store(store)));
+	assert!(stored.verify_password(Some("hunter2")).await);
+	assert!(!stored.verify_password(None).await);
+	timeout(Duration::from_secs(5), connection.wait_for(|s| *s == ConnectionState::Connected)).await.unwrap().unwrap();
+	assert_eq!(host.session().as_ref().unwrap().uuid(), host_id);
+
+	let mut c = test_client(s_addr).await;
+	let _ = c.start_session().await.unwrap();
+	let listings = c.get_listings().await.unwrap();
+	assert_eq!(listings.len(), 1);
+	assert_eq!(*listings[0].id(), listing_id);
+	assert!(listings[0].has_password());
+
+	let options = JoinOptions { password: Some("hunter2".to_string()), ..Default::default() };
+	let outcome = c.join_with(listing_id, options).await.unwrap();
+	assert!(outcome.connected(), "{outcome:?}");
+
+	// a listing its owner never comes back for is removed after the grace period //
+	let s_addr = local_addr().await;
+	let config = ServerConfig { resume_grace_secs: 1, ..test_config(s_addr) };
+	tokio::spawn(server::serve(config, server::PuncherServer::default().with_store(FileStore::open(&copy, key()).await.unwrap())));
+	wait_for(s_addr).await;
+
+	let mut c = test_client(s_addr).await;
+	assert_eq!(c.get_listings().await.unwrap().len(), 1);
+	sleep(Duration::from_millis(1500)).await;
+	assert!(c.get_listings().await.unwrap().is_empty());
+
+	// reclaiming starts a fresh session under the old id, it doesn't resume one //
+	let s_addr = local_addr().await;
+	tokio::spawn(server::serve(test_config(s_addr), server::PuncherServer::default().with_store(FileStore::open(&reclaim, key()).await.unwrap())));
+	wait_for(s_addr).await;
+
+	let raw = test_client(s_addr).await;
+	let (_silent, rx) = tokio::sync::mpsc::channel(1);
+	let mut request = tonic::Request::new(tokio_stream::wrappers::ReceiverStream::new(rx));
+	request.metadata_mut().insert(server::auth::SESSION_TOKEN, host.session().as_ref().unwrap().token().parse().unwrap());
+	let mut stream = raw.inner().write().await.stream_session(request).await.unwrap().into_inner();
+	let Some(crate::proto::server_stream_message::ServerStreamEnum::Welcome(welcome)) = stream.message().await.unwrap().unwrap().server_stream_enum else {
+		panic!("Expected a welcome");
+	};
+	assert_eq!(welcome.session_id, host_id.as_bytes());
+	assert!(!welcome.resumed);
+
+	let _ = std::fs::remove_file(path);
+	let _ = std::fs::remove_file(copy);
+	let _ = std::fs::remove_file(reclaim);
+}
+
+/// Records which listings the server looked up, to check joins and listing queries go through the store.
+#[derive(Default)]
+struct MockStore {
+	memory: server::store::MemoryStore,
+	looked_up: std::sync::Mutex<Vec<Uuid>>,
+	listed: std::sync::atomic::AtomicUsize,
+}
+
+#[tonic::async_trait]
+impl RendezvousStore for Arc<MockStore> {
+	async fn insert_session(&self, session_id: Uuid, session: server::session::SessionRef, max: Option<usize>) -> bool { self.memory.insert_session(session_id, session, max).await }
+
+	async fn session(&self, session_id: &Uuid) -> Option<server::session::SessionRef> { self.memory.session(session_id).await }
+
+	async fn remove_session(&self, session_id: &Uuid) -> Option<server::session::SessionRef> { self.memory.remove_session(session_id).await }
+
+	async fn session_count(&self) -> usize { self.memory.session_count().await }
+
+	async fn put_listing(&self, session_id: Uuid, listing: server::listing::RustListing, max: Option<usize>) -> anyhow::Result<bool> { self.memory.put_listing(session_id, listing, max).await }
+
+	async fn listing_of(&self, session_id: &Uuid) -> Option<server::listing::RustListing> { self.memory.listing_of(session_id).await }
+
+	async fn listing(&self, listing_id: &Uuid) -> Option<(Uuid, server::listing::RustListing)> {
+		self.looked_up.lock().unwrap().push(*listing_id);
+		self.memory.listing(listing_id).await
+	}
+
+	async fn remove_listing_of(&self, session_id: &Uuid) -> anyhow::Result<Option<server::listing::RustListing>> { self.memory.remove_listing_of(session_id).await }
+
+	async fn listings(&self) -> Vec<server::listing::RustListing> {
+		self.listed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+		self.memory.listings().await
+	}
+
+	async fn listing_count(&self) -> usize { self.memory.listing_count().await }
+}
+
+#[tokio::test]
+async fn mock_store() {
+	let store = Arc::new(MockStore::default());
+	let s_addr = local_addr().await;
+	tokio::spawn(server::serve(test_config(s_addr), server::PuncherServer::default().with_store(store.clone())));
+	wait_for(s_addr).await;
+
+	let mut host = test_client(s_addr).await;
+	let mut joiner = test_client(s_addr).await;
+	let _ = host.start_session().await.unwrap();
+	let _ = joiner.start_session().await.unwrap();
+
+	let listing_id = host.create_listing(RustListingNoId { name: "mocked".to_string(), ..Default::default() }).await.unwrap();
+
+	let listed = store.listed.load(std::sync::atomic::Ordering::Relaxed);
+	let listings = joiner.get_listings().await.unwrap();
+	assert_eq!(listings.len(), 1);
+	assert_eq!(*listings[0].id(), listing_id);
+	assert!(store.listed.load(std::sync::atomic::Ordering::Relaxed) > listed);
+
+	// unknown listings are looked up and refused //
+	let missing = Uuid::new_v4();
+	assert!(joiner.join(missing).await.is_err());
+	assert!(store.looked_up.lock().unwrap().contains(&missing));
+
+	let outcome = joiner.join(listing_id).await.unwrap();
+	assert!(outcome.connected(), "{outcome:?}");
+	assert!(store.looked_up.lock().unwrap().contains(&listing_id));
+}
+
+#[tokio::test]
+async fn cluster() {
+	let a_addr = local_addr().await;
+	let b_addr = local_addr().await;
+	let a_node = local_addr().await;
+	let b_node = local_addr().await;
+
+	let secret = NodeSecret::new("cluster secret").unwrap();
+	let a_config = ServerConfig { node_port: a_node.port(), ..test_config(a_addr) };
+	let b_config = ServerConfig { node_port: b_node.port(), ..test_config(b_addr) };
+	tokio::spawn(server::serve(a_config, server::PuncherServer::default().with_cluster(secret.clone(), [test_uri(b_node)])));
+	tokio::spawn(server::serve(b_config, server::PuncherServer::default().with_cluster(secret, [test_uri(a_node)])));
+	wait_for(a_node).await;
+	wait_for(b_node).await;
+
+	// host on node a, joiner on node b //
+	let mut host = test_client(a_addr).await;
+	let mut joiner = test_client(b_addr).await;
+	let _ = host.start_session().await.unwrap();
+	let _ = joiner.start_session().await.unwrap();
+
+	let listing = RustListingNoId { name: "remote".to_string(), password: Some("hunter2".to_string()), ..Default::default() };
+	let listing_id = host.create_listing(listing).await.unwrap();
+
+	let listings = joiner.get_listings().await.unwrap();
+	assert_eq!(listings.len(), 1);
+	assert_eq!(*listings[0].id(), listing_id);
+
+	// the password is checked on node a //
+	assert!(joiner.join(listing_id).await.is_err());
+
+	let options = JoinOptions { password: Some("hunter2".to_string()), ..Default::default() };
+	let outcome = joiner.join_with(listing_id, options).await.unwrap();
+	assert!(outcome.joiner.success && outcome.host.success);
+	assert_eq!(outcome.fallback, Fallback::None);
+}
+
+#[tokio::test]
+async fn cluster_fan_out() {
+	use tokio::net::TcpListener;
+
+	// peers that accept but never answer //
+	let mut hung = Vec::new();
+	for _ in 0..2 {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		hung.push(test_uri(listener.local_addr().unwrap()));
+		tokio::spawn(async move {
+			let mut held = Vec::new();
+			while let Ok((stream, _)) = listener.accept().await {
+				held.push(stream);
+			}
+		});
+	}
+
+	let a_addr = local_addr().await;
+	let b_addr = local_addr().await;
+	let b_node = local_addr().await;
+
+	let secret = NodeSecret::new("cluster secret").unwrap();
+	let a_config = ServerConfig { timeout_secs: 1, ..test_config(a_addr) };
+	let b_config = ServerConfig { node_port: b_node.port(), ..test_config(b_addr) };
+	tokio::spawn(server::serve(a_config, server::PuncherServer::default().with_cluster(secret.clone(), hung.into_iter().chain([test_uri(b_node)]))));
+	tokio::spawn(server::serve(b_config, server::PuncherServer::default().with_cluster(secret, [])));
+	wait_for(a_addr).await;
+	wait_for(b_node).await;
+
+	let mut host = test_client(b_addr).await;
+	let mut joiner = test_client(a_addr).await;
+	let _ = host.start_session().await.unwrap();
+	let _ = joiner.start_session().await.unwrap();
+	let listing_id = host.create_listing(RustListingNoId { name: "remote".to_string(), ..Default::default() }).await.unwrap();
+
+	// the hung peers are waited on together, not one after the other //
+	let start = std::time::Instant::now();
+	let listings = joiner.get_listings().await.unwrap();
+	assert!(start.elapsed() < Duration::from_millis(1800), "peers were asked in turn: {:?}", start.elapsed());
+	assert_eq!(listings.len(), 1);
+
+	// and not answering doesn't fail the join the owner answers //
+	let outcome = joiner.join(listing_id).await.unwrap();
+	assert!(outcome.connected(), "{outcome:?}");
+}
+
+#[tokio::test]
+async fn node_auth() {
+	let addr = local_addr().await;
+	let node_addr = local_addr().await;
+
+	let config = ServerConfig { node_port: node_addr.port(), ..test_config(addr) };
+	let secret = NodeSecret::new("cluster secret").unwrap();
+	tokio::spawn(server::serve(config, server::PuncherServer::default().with_cluster(secret, [])));
+	wait_for(addr).await;
+	wait_for(node_addr).await;
+
+	let mut host = test_client(addr).await;
+	let _ = host.start_session().await.unwrap();
+	let host_id = host.session().as_ref().unwrap().uuid();
+	let order = || NodePunchRequest { session_id: host_id.as_bytes().to_vec(), ip: "127.0.0.1".to_string(), port: 9 };
+
+	// no secret, or the wrong one //
+	let channel = Channel::builder(test_uri(node_addr)).connect().await.unwrap();
+	let status = NodeServiceClient::new(channel.clone()).order_punch(order()).await.unwrap_err();
+	assert_eq!(status.code(), Code::Unauthenticated);
+
+	let wrong = NodeSecret::new("guess").unwrap();
+	let status = NodeServiceClient::with_interceptor(channel, wrong).order_punch(order()).await.unwrap_err();
+	assert_eq!(status.code(), Code::Unauthenticated);
+
+	// the public port doesn't serve peers at all //
+	let channel = Channel::builder(test_uri(addr)).connect().await.unwrap();
+	let status = NodeServiceClient::new(channel).order_punch(order()).await.unwrap_err();
+	assert_eq!(status.code(), Code::Unimplemented);
+}
+
+/// Parses flags alone, so the machine's own `PORT` and `NAT_PUNCHER_*` vars don't leak in.
+fn parse_cli(args: &[&str]) -> Cli {
+	let command = Cli::command().mut_args(|arg| arg.env(None::<&str>));
+	Cli::from_arg_matches(&command.try_get_matches_from(args).unwrap()).unwrap()
+}
+
+#[test]
+fn config() {
+	let config = ServerConfig::from_toml(r#"
+		host = "0.0.0.0"
+		port = 8080
+		timeout_secs = 5
+		cors_origins = ["https://example.com"]
+	"#).unwrap();
+	assert_eq!(config.addr(), "0.0.0.0:8080".parse().unwrap());
+	assert_eq!(config.timeout(), Duration::from_secs(5));
+	assert_eq!(config.keepalive_secs, ServerConfig::default().keepalive_secs);
+
+	assert!(ServerConfig::from_toml("unknown = 1").is_err());
+
+	// pings start halfway, leaving at least one keepalive to answer //
+	assert!(ServerConfig::from_toml("keepalive_secs = 30\nidle_timeout_secs = 40").unwrap().validate().is_err());
+	assert!(ServerConfig::from_toml("keepalive_secs = 30\nidle_timeout_secs = 0").unwrap().validate().is_ok());
+
+	// peers can't be asked anything without the node secret //
+	assert!(ServerConfig::from_toml("peers = [\"http://10.0.0.2:3001\"]").unwrap().validate().is_err());
+	assert!(ServerConfig::from_toml("peers = [\"http://10.0.0.2:3001\"]\nnode_secret = \"s\"").unwrap().validate().is_ok());
+
+	// a store needs a secret to derive its session key from //
+	assert!(ServerConfig::from_toml("store_path = \"lobbies.store\"").unwrap().validate().is_err());
+	assert!(ServerConfig::from_toml("store_path = \"lobbies.store\"\nstore_secret = \"s\"").unwrap().validate().is_ok());
+
+	// flags override the file //
+	let path = std::env::temp_dir().join(format!("nat_puncher_{}.toml", Uuid::new_v4()));
+	std::fs::write(&path, "port = 8080\nmax_sessions = 10").unwrap();
+
+	let cli = parse_cli(&["nat_puncher_server", "--config", path.to_str().unwrap(), "--port", "9090", "--no-relay", "--cors-origin", "*"]);
+	let config = ServerConfig::load(cli).unwrap();
+	assert_eq!(config.port, 9090);
+	assert_eq!(config.max_sessions, Some(10));
+	assert!(!config.relay);
+	assert_eq!(config.cors_origins, ["*"]);
+	assert_eq!(config.relay_host(), config.host);
+
+	// a platform's `PORT` is reached from outside, unless the host says otherwise //
+	let config = ServerConfig::load(Cli { platform_port: Some(10000), ..Default::default() }).unwrap();
+	assert_eq!(config.addr(), "0.0.0.0:10000".parse().unwrap());
+	let config = ServerConfig::load(Cli { platform_port: Some(10000), host: Some(Ipv4Addr::LOCALHOST.into()), ..Default::default() }).unwrap();
+	assert_eq!(config.addr(), "127.0.0.1:10000".parse().unwrap());
+	let config = ServerConfig::load(Cli { platform_port: Some(10000), port: Some(9090), ..Default::default() }).unwrap();
+	assert_eq!(config.addr(), "127.0.0.1:9090".parse().unwrap());
+	std::fs::write(&path, "host = \"127.0.0.1\"").unwrap();
+	let config = ServerConfig::load(Cli { config: Some(path.clone()), platform_port: Some(10000), ..Default::default() }).unwrap();
+	assert_eq!(config.addr(), "127.0.0.1:10000".parse().unwrap());
+
+	let cli = parse_cli(&["nat_puncher_server", "--log-level", "loud"]);
+	assert!(ServerConfig::load(cli).is_err());
+	let cli = parse_cli(&["nat_puncher_server", "--log-format", "json"]);
+	assert_eq!(ServerConfig::load(cli).unwrap().log_format, "json");
+	assert!(ServerConfig::from_toml("log_format = \"xml\"").unwrap().validate().is_err());
+
+	let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn max_sessions() {
+	let s_addr = local_addr().await;
+	let config = ServerConfig { max_sessions: Some(1), max_listings: Some(0), ..test_config(s_addr) };
+	tokio::spawn(run(config));
+	wait_for(s_addr).await;
+
+	let mut c_1 = test_client(s_addr).await;
+	let mut c_2 = test_client(s_addr).await;
+	let _ = c_1.start_session().await.unwrap();
+	assert!(c_2.start_session().await.is_err());
+
+	let listing = RustListingNoId { name: "test listing".to_string(), ..Default::default() };
+	assert!(c_1.create_listing(listing).await.is_err());
+
+	// the limit is checked in the same step as the insert, so racing creates can't overshoot it //
+	let store = Arc::new(server::store::MemoryStore::default());
+	let puts = (0..8).map(|_| {
+		let store = store.clone();
+		tokio::spawn(async move {
+			let listing = server::listing::RustListing::new(server::listing::PreparedListing::new(RustListingNoId::default()).await.unwrap());
+			store.put_listing(Uuid::new_v4(), listing, Some(3)).await.unwrap()
+		})
+	});
+	let added = futures::future::join_all(puts).await.into_iter().filter(|r| *r.as_ref().unwrap()).count();
+	assert_eq!(added, 3);
+	assert_eq!(store.listing_count().await, 3);
+}
+
+#[tokio::test]
+async fn tls() {
+	use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+	// a fresh CA and a leaf for 127.0.0.1 it signed, as pems //
+	let issue = || {
+		let ca_key = KeyPair::generate().unwrap();
+		let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+		ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+		let ca = ca_params.self_signed(&ca_key).unwrap();
+
+		let key = KeyPair::generate().unwrap();
+		let cert = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap().signed_by(&key, &ca, &ca_key).unwrap();
+		(ca.pem(), cert.pem(), key.serialize_pem())
+	};
+
+	let dir = std::env::temp_dir();
+	let id = Uuid::new_v4();
+	let cert_path = dir.join(format!("nat_puncher_{id}.crt"));
+	let key_path = dir.join(format!("nat_puncher_{id}.key"));
+
+	let (ca, cert, key) = issue();
+	std::fs::write(&cert_path, &cert).unwrap();
+	std::fs::write(&key_path, &key).unwrap();
+
+	let s_addr = local_addr().await;
+	let config = ServerConfig {
+		tls_cert: Some(cert_path.clone()),
+		tls_key: Some(key_path.clone()),
+		tls_reload_secs: 1,
+		tls_handshake_secs: 1,
+		..test_config(s_addr)
+	};
+	tokio::spawn(run(config));
+	wait_for(s_addr).await;
+
+	let uri: Uri = format!("https://{s_addr}/").parse().unwrap();
+	let session = async |trust, transport| {
+		let mut client = Client::with_options(uri.clone(), ClientOptions { transport, trust, ..Default::default() }).await?;
+		client.start_session().await
+	};
+
+	for transport in [Transport::GrpcWeb, Transport::Grpc] {
+		assert!(session(Trust::Ca(ca.clone().into_bytes()), transport).await.is_ok());
+		assert!(session(Trust::Pinned(cert.clone().into_bytes()), transport).await.is_ok());
+		assert!(session(Trust::Ca(issue().0.into_bytes()), transport).await.is_err());
+	}
+
+	// rotate to a new CA, the old one stops working once it's picked up //
+	let (new_ca, new_cert, new_key) = issue();
+	std::fs::write(&key_path, &new_key).unwrap();
+	std::fs::write(&cert_path, &new_cert).unwrap();
+	sleep(Duration::from_millis(2500)).await;
+
+	assert!(session(Trust::Ca(new_ca.into_bytes()), Transport::Grpc).await.is_ok());
+	assert!(session(Trust::Ca(ca.into_bytes()), Transport::Grpc).await.is_err());
+
+	// a connection that never handshakes is dropped //
+	let mut idle = TcpStream::connect(s_addr).await.unwrap();
+	let mut buf = [0u8; 1];
+	let read = timeout(Duration::from_secs(3), tokio::io::AsyncReadExt::read(&mut idle, &mut buf)).await.unwrap();
+	assert!(matches!(read, Ok(0) | Err(_)), "{read:?}");
+
+	let _ = std::fs::remove_file(cert_path);
+	let _ = std::fs::remove_file(key_path);
+}
+
+#[tokio::test]
+async fn transports() {
+	let s_addr = test_server().await;
+
+	// a native host and a grpc-web joiner share one server //
+	let mut host = Client::with_options(test_uri(s_addr), ClientOptions { transport: Transport::Grpc, ..Default::default() }).await.unwrap();
+	let mut joiner = Client::new(test_uri(s_addr), Transport::GrpcWeb).await.unwrap();
+	let _ = host.start_session().await.unwrap();
+	let _ = joiner.start_session().await.unwrap();
+
+	let listing_id = host.create_listing(RustListingNoId { name: "native".to_string(), ..Default::default() }).await.unwrap();
+
+	let mut events = host.watch_listings().await.unwrap();
+	let event = timeout(Duration::from_secs(5), events.next()).await.unwrap().unwrap().unwrap();
+	assert!(matches!(event, RustListingEvent::Snapshot(l) if l.len() == 1));
+
+	for client in [&mut joiner, &mut host] {
+		let listings = client.get_listings().await.unwrap();
+		assert_eq!(listings.len(), 1);
+		assert_eq!(*listings[0].id(), listing_id);
+	}
+
+	let outcome = joiner.join(listing_id).await.unwrap();
+	assert!(outcome.joiner.success && outcome.host.success);
+
+	host.remove_listing().await.unwrap();
+	assert_eq!(timeout(Duration::from_secs(5), events.next()).await.unwrap().unwrap().unwrap(), RustListingEvent::Removed(listing_id));
+}
+
+#[tokio::test]
+async fn session_tokens() {
+	use crate::{proto::{AddListingRequest, JoinRequest}, server::auth::SESSION_TOKEN};
+	use tonic::{Code, Request};
+
+	let s_addr = test_server().await;
+	let mut victim = test_client(s_addr).await;
+	let mut attacker = test_client(s_addr).await;
+	let _ = victim.start_session().await.unwrap();
+	let _ = attacker.start_session().await.unwrap();
+
+	let victim_id = victim.session().as_ref().unwrap().id();
+	let listing = || Some(RustListingNoId { name: "stolen".to_string(), ..Default::default() }.into());
+
+	// knowing the id isn't enough //
+	let bare = Request::new(AddListingRequest { session_id: victim_id.clone(), listing: listing() });
+	let status = attacker.inner().write().await.add_listing(bare).await.unwrap_err();
+	assert_eq!(status.code(), Code::Unauthenticated);
+
+	let mut forged = Request::new(AddListingRequest { session_id: victim_id.clone(), listing: listing() });
+	forged.metadata_mut().insert(SESSION_TOKEN, "AAAAAAAAAAAAAAAAAAAAAA".parse().unwrap());
+	let status = attacker.inner().write().await.add_listing(forged).await.unwrap_err();
+	assert_eq!(status.code(), Code::Unauthenticated);
+
+	// nor is a valid token for another session //
+	let borrowed = attacker.session().as_ref().unwrap().request(JoinRequest { session_id: victim_id, ..Default::default() });
+	let status = attacker.inner().write().await.join(borrowed).await.unwrap_err();
+	assert_eq!(status.code(), Code::PermissionDenied);
+
+	// the client attaches its own token //
+	let listing_id = victim.create_listing(RustListingNoId { name: "mine".to_string(), ..Default::default() }).await.unwrap();
+	assert!(attacker.join(listing_id).await.is_ok());
+	victim.remove_listing().await.unwrap();
+}
+
+#[tokio::test]
+async fn identity() {
+	use crate::{proto::Identity, server::auth::JwtAuth};
+
+	let s_addr = local_addr().await;
+	tokio::spawn(run(ServerConfig { auth_secret: Some("backend secret".to_string()), ..test_config(s_addr) }));
+	wait_for(s_addr).await;
+
+	let backend = JwtAuth::new("backend secret");
+	let player = |name: &str| Identity { account_id: format!("{name}-id"), display_name: name.to_string(), platform: "steam".to_string() };
+	let client = async |token: Option<String>| {
+		let options = ClientOptions { auth_token: token, ..Default::default() };
+		Client::with_options(test_uri(s_addr), options).await.unwrap()
+	};
+
+	// anonymous, forged and expired tokens are turned away //
+	assert!(client(None).await.start_session().await.is_err());
+	let forged = JwtAuth::new("guess").sign(&player("eve"), Duration::from_secs(60));
+	assert!(client(Some(forged)).await.start_session().await.is_err());
+	let expired = jsonwebtoken::encode(
+		&Default::default(),
+		&serde_json::json!({ "sub": "eve-id", "exp": jsonwebtoken::get_current_timestamp() - 1 }),
+		&jsonwebtoken::EncodingKey::from_secret(b"backend secret"),
+	)
+	.unwrap();
+	assert!(client(Some(expired)).await.start_session().await.is_err());
+
+	let mut host = client(Some(backend.sign(&player("alice"), Duration::from_secs(60)))).await;
+	let mut joiner = client(Some(backend.sign(&player("bob"), Duration::from_secs(60)))).await;
+	let _ = host.start_session().await.unwrap();
+	let _ = joiner.start_session().await.unwrap();
+
+	let listing_id = host.create_listing(RustListingNoId { name: "alice's".to_string(), ..Default::default() }).await.unwrap();
+	let listings = joiner.get_listings().await.unwrap();
+	assert_eq!(listings[0].host(), Some(&player("alice")));
+
+	let mut requests = host.join_requests().unwrap();
+	let answer = async {
+		let pending = requests.recv().await.unwrap();
+		let joiner = pending.joiner.clone();
+		pending.reject("full");
+		joiner
+	};
+	let (joiner_identity, outcome) = tokio::join!(answer, joiner.join(listing_id));
+	assert_eq!(joiner_identity, Some(player("bob")));
+	assert!(outcome.is_err());
+}
+
+#[test]
+fn rate_limiter() {
+	use crate::server::ratelimit::{Rate, RateLimiter};
+
+	let limiter = RateLimiter::new(Some(Rate { per_sec: 2.0, burst: 3 }), Some(Rate { per_sec: 1.0, burst: 1 }));
+	let start = std::time::Instant::now();
+	let a = Some(Ipv4Addr::new(10, 0, 0, 1).into());
+	let b = Some(Ipv4Addr::new(10, 0, 0, 2).into());
+
+	// the burst, then nothing until it refills //
+	for _ in 0..3 {
+		assert!(limiter.check(a, None, start).is_ok());
+	}
+	assert!(limiter.check(a, None, start).is_err());
+	assert!(limiter.check(a, None, start + Duration::from_millis(400)).is_err());
+	assert!(limiter.check(a, None, start + Duration::from_millis(500)).is_ok());
+
+	// refills cap at the burst //
+	let later = start + Duration::from_secs(60);
+	for _ in 0..3 {
+		assert!(limiter.check(a, None, later).is_ok());
+	}
+	assert!(limiter.check(a, None, later).is_err());
+
+	// ips and sessions have their own buckets //
+	assert!(limiter.check(b, Some(b"one"), start).is_ok());
+	assert!(limiter.check(b, Some(b"one"), start).is_err());
+	assert!(limiter.check(b, Some(b"two"), start).is_ok());
+	assert!(limiter.check(None, Some(b"one"), start + Duration::from_secs(1)).is_ok());
+
+	// pruning many one-off ips keeps the buckets still refilling //
+	for i in 0..5000u32 {
+		assert!(limiter.check(Some(Ipv4Addr::from(0x0b00_0000 + i).into()), None, later).is_ok());
+	}
+	assert!(limiter.check(a, None, later).is_err());
+
+	// off means unlimited //
+	let unlimited = RateLimiter::new(None, None);
+	assert!((0..1000).all(|_| unlimited.check(a, Some(b"one"), start).is_ok()));
+}
+
+#[test]
+fn forwarded_for() {
+	use tonic::codegen::http::{HeaderMap, HeaderValue};
+	use crate::server::ratelimit::client_ip;
+
+	let proxy = Ipv4Addr::new(10, 0, 0, 1).into();
+	let inner = Ipv4Addr::new(10, 0, 0, 2).into();
+	let client = Ipv4Addr::new(203, 0, 113, 7).into();
+	let headers = |value: &'static str| {
+		let mut headers = HeaderMap::new();
+		headers.insert("x-forwarded-for", HeaderValue::from_static(value));
+		headers
+	};
+
+	// only believed from a trusted proxy //
+	let forged = headers("198.51.100.1");
+	assert_eq!(client_ip(client, &forged, &[proxy]), client);
+	assert_eq!(client_ip(proxy, &forged, &[]), proxy);
+
+	// the last hop that isn't a proxy, whatever the client put before it //
+	assert_eq!(client_ip(proxy, &headers("198.51.100.1, 203.0.113.7"), &[proxy]), client);
+	assert_eq!(client_ip(proxy, &headers("198.51.100.1, 203.0.113.7, 10.0.0.2"), &[proxy, inner]), client);
+	assert_eq!(client_ip(proxy, &HeaderMap::new(), &[proxy]), proxy);
+	assert_eq!(client_ip(proxy, &headers("garbage"), &[proxy]), proxy);
+}
+
+#[tokio::test]
+async fn rate_limit() {
+	use tonic::{Code, Request};
+	use crate::proto::GetListingsRequest;
+
+	let s_addr = local_addr().await;
+	let config = ServerConfig { ip_rate_limit: 0.5, ip_burst: 4, session_rate_limit: 0.5, session_burst: 1, ..test_config(s_addr) };
+	tokio::spawn(run(config));
+	wait_for(s_addr).await;
+
+	// opening the session takes one from the ip //
+	let mut c = test_client(s_addr).await;
+	let _ = c.start_session().await.unwrap();
+
+	// the session's single token //
+	let _ = c.create_listing(RustListingNoId { name: "spam".to_string(), ..Default::default() }).await.unwrap();
+	let err = c.update_listing(RustListingNoId { name: "spam".to_string(), ..Default::default() }).await.unwrap_err();
+	assert!(err.to_string().contains("session"), "{err}");
+
+	// the ip's last token, then exhausted //
+	assert!(c.get_listings().await.is_ok());
+	let status = c.inner().write().await.get_listings(Request::new(GetListingsRequest::default())).await.unwrap_err();
+	assert_eq!(status.code(), Code::ResourceExhausted);
+}
+
+#[tokio::test]
+async fn dispatcher() {
+	use crate::{proto::{client_stream_message::ClientStreamEnum, PunchStatus}, server::session::Dispatcher};
+
+	let status = |id: &Uuid, message: &str| ClientStreamEnum::PunchStatus(PunchStatus {
+		message: Some(message.to_string()),
+		success: true,
+		request_id: id.as_bytes().to_vec(),
+	});
+
+	let replies = Dispatcher::default();
+	let mut first = replies.expect();
+	let mut second = replies.expect();
+
+	// answered out of order, each reaches its own request //
+	replies.dispatch(status(second.id(), "second")).unwrap();
+	replies.dispatch(status(first.id(), "first")).unwrap();
+	assert_eq!(first.recv().await.unwrap(), status(first.id(), "first"));
+	assert_eq!(second.recv().await.unwrap(), status(second.id(), "second"));
+
+	// answered twice, or for a request nobody waits on anymore //
+	assert!(replies.dispatch(status(first.id(), "again")).is_err());
+	let dropped = *replies.expect().id();
+	assert!(replies.dispatch(status(&dropped, "late")).is_err());
+
+	// the stream ending fails waiting and later replies //
+	let mut waiting = replies.expect();
+	replies.close();
+	assert!(waiting.recv().await.is_err());
+	assert!(replies.expect().recv().await.is_err());
+}
+
+#[tokio::test]
+async fn concurrent_joins() {
+	let s_addr = test_server().await;
+	let mut host = test_client(s_addr).await;
+	let mut joined = host.start_session().await.unwrap();
+	let listing_id = host.create_listing(RustListingNoId { name: "busy".to_string(), ..Default::default() }).await.unwrap();
+
+	let mut joiners = Vec::new();
+	for _ in 0..3 {
+		let mut joiner = test_client(s_addr).await;
+		let _ = joiner.start_session().await.unwrap();
+		joiners.push(joiner);
+	}
+
+	// the host punches all of them at once, each status finding its own join //
+	let start = std::time::Instant::now();
+	let outcomes = futures::future::join_all(joiners.iter_mut().map(|j| j.join(listing_id))).await;
+	assert!(start.elapsed() < Duration::from_secs(5), "joins were serialized: {:?}", start.elapsed());
+
+	for outcome in outcomes {
+		let outcome = outcome.unwrap();
+		assert!(outcome.joiner.success && outcome.host.success, "{outcome:?}");
+	}
+
+	let mut peers = Vec::new();
+	while let Ok(addr) = joined.try_recv() {
+		peers.push(addr);
+	}
+	peers.sort();
+	peers.dedup();
+	assert_eq!(peers.len(), 3);
+}
+
+#[tokio::test]
+async fn idle_expiry() {
+	use tokio::sync::mpsc;
+	use tokio_stream::wrappers::ReceiverStream;
+	use tonic::{Code, Request};
+	use crate::proto::{server_stream_message::ServerStreamEnum, AddListingRequest};
+
+	let s_addr = local_addr().await;
+	// the resume grace isn't added on top of the idle timeout //
+	let config = ServerConfig { keepalive_secs: 1, idle_timeout_secs: 2, resume_grace_secs: 30, ..test_config(s_addr) };
+	tokio::spawn(run(config));
+	wait_for(s_addr).await;
+
+	// answers pings //
+	let mut alive = test_client(s_addr).await;
+	let _ = alive.start_session().await.unwrap();
+	alive.create_listing(RustListingNoId { name: "alive".to_string(), ..Default::default() }).await.unwrap();
+
+	// a half-open connection: the stream stays open but never sends //
+	let ghost = test_client(s_addr).await;
+	let (_silent, rx) = mpsc::channel(1);
+	let mut stream = ghost.inner().write().await.stream_session(Request::new(ReceiverStream::new(rx))).await.unwrap().into_inner();
+	let Some(ServerStreamEnum::Welcome(welcome)) = stream.message().await.unwrap().unwrap().server_stream_enum else {
+		panic!("Expected a welcome");
+	};
+
+	let mut request = Request::new(AddListingRequest {
+		listing: Some(RustListingNoId { name: "ghost".to_string(), ..Default::default() }.into()),
+		session_id: welcome.session_id,
+	});
+	request.metadata_mut().insert(server::auth::SESSION_TOKEN, welcome.session_token.parse().unwrap());
+	ghost.inner().write().await.add_listing(request).await.unwrap();
+	assert_eq!(alive.get_listings().await.unwrap().len(), 2);
+
+	// warned, then dropped //
+	let mut pinged = false;
+	let status = loop {
+		match timeout(Duration::from_secs(5), stream.message()).await.unwrap() {
+			Ok(Some(msg)) => pinged |= matches!(msg.server_stream_enum, Some(ServerStreamEnum::Ping(_))),
+			Ok(None) => panic!("Stream ended without a status"),
+			Err(status) => break status,
+		}
+	};
+	assert!(pinged);
+	assert_eq!(status.code(), Code::DeadlineExceeded);
+
+	sleep(Duration::from_millis(100)).await;
+	let listings = alive.get_listings().await.unwrap();
+	assert_eq!(listings.len(), 1);
+	assert_eq!(listings[0].inner().name, "alive");
+	alive.update_listing(RustListingNoId { name: "still alive".to_string(), ..Default::default() }).await.unwrap();
+}
+
+#[tokio::test]
+async fn resume() {
+	use tokio::{io::copy_bidirectional, net::TcpListener, task::JoinHandle};
+	use crate::client::{Backoff, ConnectionState};
+
+	let s_addr = local_addr().await;
+	tokio::spawn(run(ServerConfig { resume_grace_secs: 5, ..test_config(s_addr) }));
+	wait_for(s_addr).await;
+
+	// a proxy whose connections can be cut, as a flaky network would //
+	let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let p_addr = proxy.local_addr().unwrap();
+	let connections = Arc::new(std::sync::Mutex::new(Vec::<JoinHandle<()>>::new()));
+	let accepted = connections.clone();
+	tokio::spawn(async move {
+		while let Ok((mut inbound, _)) = proxy.accept().await {
+			let mut outbound = TcpStream::connect(s_addr).await.unwrap();
+			accepted.lock().unwrap().push(tokio::spawn(async move {
+				let _ = copy_bidirectional(&mut inbound, &mut outbound).await;
+			}));
+		}
+	});
+
+	// and the reflector, which isn't cut //
+	let reflector = UdpSocket::bind(p_addr).await.unwrap();
+	tokio::spawn(async move {
+		let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let mut buf = [0u8; 1500];
+		// again on every resume //
+		loop {
+			let (len, client) = reflector.recv_from(&mut buf).await.unwrap();
+			upstream.send_to(&buf[..len], s_addr).await.unwrap();
+			let len = upstream.recv(&mut buf).await.unwrap();
+			reflector.send_to(&buf[..len], client).await.unwrap();
+		}
+	});
+
+	let options = ClientOptions {
+		reconnect: Backoff { initial: Duration::from_millis(100), max: Duration::from_millis(500), attempts: 20 },
+		..Default::default()
+	};
+	let mut host = Client::with_options(test_uri(p_addr), options).await.unwrap();
+	let _ = host.start_session().await.unwrap();
+	let session_id = host.session().as_ref().unwrap().uuid();
+	let listing_id = host.create_listing(RustListingNoId { name: "kept".to_string(), ..Default::default() }).await.unwrap();
+
+	let mut connection = host.connection().unwrap();
+	assert_eq!(*connection.borrow(), ConnectionState::Connected);
+
+	for task in connections.lock().unwrap().drain(..) {
+		task.abort();
+	}
+
+	// drops, then picks the same session back up //
+	timeout(Duration::from_secs(5), connection.wait_for(|s| matches!(s, ConnectionState::Reconnecting { .. }))).await.unwrap().unwrap();
+	timeout(Duration::from_secs(5), connection.wait_for(|s| *s == ConnectionState::Connected)).await.unwrap().unwrap();
+	assert_eq!(host.session().as_ref().unwrap().uuid(), session_id);
+
+	let mut other = test_client(s_addr).await;
+	let listings = other.get_listings().await.unwrap();
+	assert_eq!(listings.len(), 1);
+	assert_eq!(*listings[0].id(), listing_id);
+	host.update_listing(RustListingNoId { name: "still kept".to_string(), ..Default::default() }).await.unwrap();
+
+	// ending it on purpose skips the grace period //
+	host.end_session();
+	timeout(Duration::from_secs(5), connection.wait_for(|s| *s == ConnectionState::Disconnected)).await.unwrap().unwrap();
+	sleep(Duration::from_millis(200)).await;
+	assert!(other.get_listings().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn shutdown() {
+	use tokio::sync::oneshot;
+	use crate::client::{Backoff, ConnectionState};
+
+	let s_addr = local_addr().await;
+	let (stop, signal) = oneshot::channel::<()>();
+	let config = ServerConfig { drain_secs: 5, shutdown_retry_secs: 1, ..test_config(s_addr) };
+	let server = tokio::spawn(server::run_with_shutdown(config, async { let _ = signal.await; }));
+	wait_for(s_addr).await;
+
+	let options = ClientOptions {
+		reconnect: Backoff { initial: Duration::from_millis(100), max: Duration::from_millis(100), attempts: 2 },
+		..Default::default()
+	};
+	let mut host = Client::with_options(test_uri(s_addr), options).await.unwrap();
+	let _ = host.start_session().await.unwrap();
+	let listing_id = host.create_listing(RustListingNoId { name: "closing".to_string(), ..Default::default() }).await.unwrap();
+	let mut requests = host.join_requests().unwrap();
+	let mut connection = host.connection().unwrap();
+
+	let mut joiner = test_client(s_addr).await;
+	let _ = joiner.start_session().await.unwrap();
+	let join = tokio::spawn(async move { joiner.join(listing_id).await });
+
+	// shut down while the host is deciding //
+	let pending = timeout(Duration::from_secs(5), requests.recv()).await.unwrap().unwrap();
+	stop.send(()).unwrap();
+	sleep(Duration::from_millis(100)).await;
+
+	let mut late = test_client(s_addr).await;
+	assert!(late.start_session().await.is_err());
+	assert!(!server.is_finished());
+
+	// the running join still punches through //
+	pending.accept();
+	let outcome = timeout(Duration::from_secs(5), join).await.unwrap().unwrap().unwrap();
+	assert!(outcome.connected(), "{outcome:?}");
+
+	timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+	let old_id = host.session().as_ref().unwrap().uuid();
+	timeout(Duration::from_secs(5), connection.wait_for(|s| matches!(s, ConnectionState::Reconnecting { .. }))).await.unwrap().unwrap();
+
+	// told when to come back, by which time the server restarted with a new key //
+	let start = std::time::Instant::now();
+	tokio::spawn(run(test_config(s_addr)));
+	timeout(Duration::from_secs(5), connection.wait_for(|s| *s == ConnectionState::Connected)).await.unwrap().unwrap();
+	assert!(start.elapsed() >= Duration::from_millis(900), "reconnected before retry_after: {:?}", start.elapsed());
+
+	// the old session is gone, the new one works //
+	assert_ne!(host.session().as_ref().unwrap().uuid(), old_id);
+	host.create_listing(RustListingNoId { name: "reopened".to_string(), ..Default::default() }).await.unwrap();
+	assert_eq!(test_client(s_addr).await.get_listings().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn metrics() {
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	async fn scrape(addr: SocketAddr, path: &str) -> String {
+		let mut stream = TcpStream::connect(addr).await.unwrap();
+		stream.write_all(format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n").as_bytes()).await.unwrap();
+		let mut response = String::new();
+		stream.read_to_string(&mut response).await.unwrap();
+		response
+	}
+
+	let s_addr = local_addr().await;
+	let m_addr = SocketAddr::new(s_addr.ip(), local_addr().await.port());
+	tokio::spawn(run(ServerConfig { metrics_port: m_addr.port(), ..test_config(s_addr) }));
+	wait_for(s_addr).await;
+	wait_for(m_addr).await;
+
+	let mut host = test_client(s_addr).await;
+	let mut joiner = test_client(s_addr).await;
+	let _ = host.start_session().await.unwrap();
+	let _ = joiner.start_session().await.unwrap();
+	let listing_id = host.create_listing(RustListingNoId { name: "measured".to_string(), ..Default::default() }).await.unwrap();
+	assert!(joiner.join(listing_id).await.unwrap().connected());
+
+	let response = scrape(m_addr, "/metrics").await;
+	assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+	for line in [
+		"nat_puncher_sessions 2",
+		"nat_puncher_listings 1",
+		"nat_puncher_join_attempts_total 1",
+		"nat_puncher_punches_total{side=\"joiner\",result=\"success\"} 1",
+		"nat_puncher_punches_total{side=\"host\",result=\"failure\"} 0",
+		"nat_puncher_punch_duration_seconds_bucket{side=\"host\",le=\"+Inf\"} 1",
+		"nat_puncher_punch_duration_seconds_count{side=\"joiner\"} 1",
+		"nat_puncher_stream_errors_total 0",
+		"nat_puncher_relay_allocations_total 0",
+	] {
+		assert!(response.lines().any(|l| l == line), "missing {line} in:\n{response}");
+	}
+
+	assert!(scrape(m_addr, "/").await.starts_with("HTTP/1.1 404"));
+}